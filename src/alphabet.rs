@@ -0,0 +1,150 @@
+use crate::*;
+
+// Alphabets used by the ciphers. Letters are kept in order, in lower and upper case, so
+// that a cipher can work with a letter's position and keep its case.
+
+pub const ALPHABET_NAMES: [(&str, &str); 5] = [
+    ("latin", "the 26 letters a-z (the default)"),
+    ("german", "a-z followed by ä, ö, ü and ß"),
+    ("cyrillic", "the 33 letters of the Russian alphabet, а-я with ё"),
+    ("greek", "the 24 letters α-ω; final ς is treated as σ"),
+    ("custom:LETTERS", "the given letters, in order"),
+];
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct Alphabet {
+    lower: Vec<char>,
+    upper: Vec<char>,
+    variants: Vec<(char, usize)>, //other forms of a letter, such as final sigma
+}
+
+impl Alphabet {
+    pub fn latin() -> Alphabet {
+        Alphabet::from_letters(&('a'..='z').collect::<Vec<char>>(), &[])
+    }
+
+    // finds an alphabet by name, or builds one from "custom:" followed by its letters
+    pub fn from_name(name: &str) -> Result<Alphabet, CError> {
+        if let Some(s) = name.strip_prefix("custom:") {
+            let letters: Vec<char> = s.chars().collect();
+            for (i, c) in letters.iter().enumerate() {
+                if letters[..i].contains(c) {
+                    return Err(CError::from(format!("Error: letter '{}' appears twice in custom alphabet", c)));
+                }
+            }
+            if letters.len() < 2 {
+                return Err(CError::from_slice("Error: a custom alphabet needs at least two letters"));
+            }
+            return Ok(Alphabet::from_letters(&letters, &[]));
+        }
+        match name {
+            "latin" | "latin26" | "en" => Ok(Alphabet::latin()),
+            "german" | "de" => {
+                let mut letters: Vec<char> = ('a'..='z').collect();
+                letters.extend(['ä', 'ö', 'ü', 'ß']);
+                Ok(Alphabet::from_letters(&letters, &[]))
+            },
+            "cyrillic" | "ru" => {
+                let mut letters: Vec<char> = ('а'..='я').collect();
+                letters.insert(6, 'ё');
+                Ok(Alphabet::from_letters(&letters, &[]))
+            },
+            "greek" | "el" => {
+                //U+03C2 (final sigma) sits between ρ and σ
+                let letters: Vec<char> = ('α'..='ω').filter(|x| *x != 'ς').collect();
+                Ok(Alphabet::from_letters(&letters, &[('ς', 17)]))
+            },
+            _ => Err(CError::from(format!("Error: unknown alphabet '{}'", name))),
+        }
+    }
+
+    fn from_letters(letters: &[char], variants: &[(char, usize)]) -> Alphabet {
+        let upper = letters.iter().map(|c| match c {
+            'ß' => 'ẞ',
+            _ => {
+                let mut u = c.to_uppercase();
+                match (u.next(), u.next()) {
+                    (Some(x), None) => x,
+                    _ => *c, //letters without a single uppercase form keep their case
+                }
+            },
+        }).collect();
+        Alphabet {
+            lower: letters.to_vec(),
+            upper,
+            variants: variants.to_vec(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.lower.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lower.is_empty()
+    }
+
+    // position of a letter in the alphabet, and whether it is uppercase
+    pub fn index(&self, c: char) -> Option<(usize, bool)> {
+        if let Some(i) = self.lower.iter().position(|x| *x == c) {
+            return Some((i, false));
+        }
+        if let Some(i) = self.upper.iter().position(|x| *x == c) {
+            return Some((i, true));
+        }
+        self.variants.iter().find(|x| x.0 == c).map(|x| (x.1, false))
+    }
+
+    pub fn letter(&self, index: usize, upper: bool) -> char {
+        if upper {
+            self.upper[index % self.len()]
+        } else {
+            self.lower[index % self.len()]
+        }
+    }
+
+    pub fn contains(&self, c: char) -> bool {
+        self.index(c).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn named_alphabets() {
+        assert_eq!(Alphabet::from_name("en").unwrap(), Alphabet::latin());
+        assert_eq!(Alphabet::from_name("german").unwrap().len(), 30);
+        let ru = Alphabet::from_name("ru").unwrap();
+        assert_eq!(ru.len(), 33);
+        assert_eq!(ru.index('ё'), Some((6, false)));
+        assert_eq!(ru.index('Ж'), Some((7, true)));
+        assert!(Alphabet::from_name("klingon").is_err());
+    }
+
+    #[test]
+    fn letters_keep_their_case() {
+        let de = Alphabet::from_name("de").unwrap();
+        assert_eq!(de.index('ß'), Some((29, false)));
+        assert_eq!(de.letter(29, true), 'ẞ');
+        assert_eq!(de.letter(30, false), 'a');
+        assert!(!de.contains('é'));
+    }
+
+    #[test]
+    fn final_sigma_counts_as_sigma() {
+        let el = Alphabet::from_name("greek").unwrap();
+        assert_eq!(el.len(), 24);
+        assert_eq!(el.index('ς'), el.index('σ'));
+        assert_eq!(el.letter(17, true), 'Σ');
+    }
+
+    #[test]
+    fn custom_alphabets() {
+        let abc = Alphabet::from_name("custom:xyz").unwrap();
+        assert_eq!(abc.index('Y'), Some((1, true)));
+        assert!(Alphabet::from_name("custom:aba").unwrap_err().to_string().contains("appears twice"));
+        assert!(Alphabet::from_name("custom:a").is_err());
+    }
+}
@@ -0,0 +1,182 @@
+use snailquote::unescape;
+use crate::*;
+
+use crate::lexer::*;
+
+// Syntax tree of a script. Comments are kept, so that tools such as the
+// formatter can reproduce the script they were given.
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+    pub end_line: usize,
+}
+impl Span {
+    fn of(first: &Token, last: &Token) -> Span {
+        Span {
+            line: first.line,
+            col: first.col,
+            end_line: last.line,
+        }
+    }
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum Expr {
+    Ident(String, Span),
+    Str(String, Span),
+    //operations are executed right-to-left, so the right side holds the rest of the chain
+    Operation(Box<Expr>, String, Box<Expr>),
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct TransformCall {
+    pub name: String,
+    pub args: Vec<String>,
+    pub span: Span,
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum StatementKind {
+    Var(String),
+    Const(String, String),
+    Load(String, Expr), //target, source
+    Write(Expr, String), //source, target
+    Apply(TransformCall, String), //transform, target
+    Directive(Vec<(String, String)>), //key, value
+    Arg(String, Option<String>), //name, default
+    Comment(String),
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct Statement {
+    pub kind: StatementKind,
+    pub span: Span,
+    pub comment: Option<String>, //trailing comment
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct Script {
+    pub statements: Vec<Statement>,
+}
+
+pub fn parse_script(script: &str) -> Result<Script, CError> {
+    let tokens = Lexer::new(script).with_comments().lex()?;
+    let mut statements: Vec<Statement> = Vec::new();
+    for group in tokens.split(|x| x.ttype == TType::EndStatement) {
+        if group.is_empty() {
+            continue;
+        }
+        statements.push(parse_statement(group)?);
+    }
+    Ok(Script { statements })
+}
+
+fn parse_statement(st: &[Token]) -> Result<Statement, CError> {
+    //separate the trailing comment from the statement itself
+    let (st, comment) = match &st.last().unwrap().ttype {
+        TType::Comment(s) if st.len() > 1 => (&st[..st.len()-1], Some(s.clone())),
+        _ => (st, None),
+    };
+    let first = &st[0];
+    let span = Span::of(first, st.last().unwrap());
+    let malformed = |name: &str| CError::at(first.line, first.col, &format!("Malformed {} statement", name));
+    let kind = match &first.ttype {
+        TType::Comment(s) => StatementKind::Comment(s.clone()),
+        TType::Var => {
+            match st {
+                [_, b] => StatementKind::Var(ident(b).ok_or_else(|| malformed("variable declaration"))?),
+                _ => {return Err(malformed("variable declaration"));},
+            }
+        },
+        TType::Const => {
+            match st {
+                [_, b, Token { ttype: TType::Str(v), .. }] => {
+                    StatementKind::Const(ident(b).ok_or_else(|| malformed("constant declaration"))?, v.clone())
+                },
+                _ => {return Err(malformed("constant declaration"));},
+            }
+        },
+        TType::Load => {
+            if st.len() < 4 || !st[2].is_from() {
+                return Err(malformed("load"));
+            }
+            let target = ident(&st[1]).ok_or_else(|| malformed("load"))?;
+            StatementKind::Load(target, parse_expr(&st[3..])?)
+        },
+        TType::Write => {
+            if st.len() < 4 || !st[st.len()-2].is_to() {
+                return Err(malformed("write"));
+            }
+            let target = ident(st.last().unwrap()).ok_or_else(|| malformed("write"))?;
+            StatementKind::Write(parse_expr(&st[1..st.len()-2])?, target)
+        },
+        TType::Apply => {
+            match st {
+                [_, t @ Token { ttype: TType::Transform(n, a), .. }, to, x] if to.is_to() => {
+                    let call = TransformCall { name: n.clone(), args: a.clone(), span: Span::of(t, t) };
+                    StatementKind::Apply(call, ident(x).ok_or_else(|| malformed("apply"))?)
+                },
+                _ => {return Err(malformed("apply"));},
+            }
+        },
+        TType::Directive(s) if s == "arg" => {
+            match st {
+                [_, n] => StatementKind::Arg(ident(n).ok_or_else(|| malformed("argument"))?, None),
+                [_, n, eq, Token { ttype: TType::Str(v), .. }] if eq.ttype == TType::Identifier("=".to_string()) => {
+                    StatementKind::Arg(ident(n).ok_or_else(|| malformed("argument"))?, Some(v.clone()))
+                },
+                _ => {return Err(malformed("argument"));},
+            }
+        },
+        TType::Directive(s) => {
+            if st.len() != 1 {
+                return Err(CError::at(first.line, first.col, "Unexpected tokens after directive"));
+            }
+            let mut entries: Vec<(String, String)> = Vec::new();
+            for x in parse(s, ',') {
+                let (k, v) = match x.find('=') {
+                    Some(n) => (&x[..n], &x[n+1..]),
+                    None => {return Err(CError::at(first.line, first.col, "Directive is missing an equal sign"));},
+                };
+                let v = if v.starts_with('"') {
+                    match unescape(v) {
+                        Ok(s) => s,
+                        Err(_) => {return Err(CError::at(first.line, first.col, "Invalid directive value"));},
+                    }
+                } else {
+                    v.to_string()
+                };
+                entries.push((k.to_string(), v));
+            }
+            StatementKind::Directive(entries)
+        },
+        _ => {return Err(CError::at(first.line, first.col, "Statement must begin with a keyword or directive"));},
+    };
+    Ok(Statement { kind, span, comment })
+}
+
+fn ident(t: &Token) -> Option<String> {
+    match &t.ttype {
+        TType::Identifier(s) if s != "=" => Some(s.clone()),
+        _ => None,
+    }
+}
+
+// operand ($operation operand)*
+fn parse_expr(st: &[Token]) -> Result<Expr, CError> {
+    let t = &st[0];
+    let operand = match &t.ttype {
+        TType::Identifier(s) => Expr::Ident(s.clone(), Span::of(t, t)),
+        TType::Str(s) => Expr::Str(s.clone(), Span::of(t, t)),
+        _ => {return Err(CError::at(t.line, t.col, "Expected an identifier"));},
+    };
+    match st.get(1) {
+        None => Ok(operand),
+        Some(Token { ttype: TType::Operation(op), .. }) if st.len() > 2 => {
+            Ok(Expr::Operation(Box::new(operand), op.clone(), Box::new(parse_expr(&st[2..])?)))
+        },
+        Some(x) => Err(CError::at(x.line, x.col, "Expected an operation")),
+    }
+}
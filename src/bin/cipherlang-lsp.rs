@@ -0,0 +1,450 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use serde_json::{json, Value};
+use cipherlang::*;
+use cipherlang::ast::*;
+use cipherlang::compile::*;
+use cipherlang::transform::*;
+use cipherlang::dictionary::SearchPath;
+
+/* Language server for cipherlang scripts.
+   Speaks the Language Server Protocol over stdin/stdout, and provides
+   diagnostics, completion, go to definition and hover.
+   Documents are synced in full on every change.
+ */
+
+const KEYWORDS: [&str; 7] = ["var", "const", "load", "write", "apply", "to", "from"];
+
+const OPERATIONS: [(&str, &str); 3] = [
+    ("cat", "Concatenates the two arguments"),
+    ("eq", "Returns an empty string if the arguments are equal, and a non-empty string otherwise"),
+    ("repeat", "Returns (Arg2) instances of Arg1, concatenated. Arg2 must be a nonnegative integer"),
+];
+
+fn special_var_doc(name: &str) -> &'static str {
+    match name {
+        "_" => "Last Transform: contains the result of the previous transform (read-only)",
+        "_o" => "Last Operation: contains the result of the previous operation (read-only)",
+        "_stdin" => "Standard Input: each read returns a line of input",
+        "_stdout" => "Standard Output: any data written here is sent to the output (write-only)",
+        "_null" => "Null String: can be written to a var to clear it (read-only)",
+        "_c" => "Character: a 1-character-long string with arbitrary contents (read-only)",
+        "_argc" => "Argument Count: the number of arguments passed in",
+        _ => "Reserved special variable",
+    }
+}
+
+fn main() {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let mut server = Server {
+        documents: HashMap::new(),
+        search: search_path(),
+        shutdown: false,
+    };
+    while let Some(msg) = read_message(&mut input) {
+        for x in server.handle(&msg) {
+            write_message(&x);
+        }
+        if msg["method"] == "exit" {
+            std::process::exit(if server.shutdown { 0 } else { 1 });
+        }
+    }
+}
+
+// reads one message, framed by a Content-Length header
+fn read_message(input: &mut impl BufRead) -> Option<Value> {
+    let mut len: usize = 0;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line).ok()? == 0 {
+            return None;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(s) = line.strip_prefix("Content-Length:") {
+            len = s.trim().parse().ok()?;
+        }
+    }
+    let mut body = vec![0; len];
+    input.read_exact(&mut body).ok()?;
+    serde_json::from_slice(&body).ok()
+}
+
+fn write_message(msg: &Value) {
+    let body = msg.to_string();
+    let mut out = io::stdout().lock();
+    let _ = write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body);
+    let _ = out.flush();
+}
+
+struct Server {
+    documents: HashMap<String, String>,
+    search: SearchPath, //read once, and again when the configuration changes
+    shutdown: bool,
+}
+
+impl Server {
+    // returns the responses and notifications to send
+    fn handle(&mut self, msg: &Value) -> Vec<Value> {
+        let params = &msg["params"];
+        let method = msg["method"].as_str().unwrap_or("");
+        let result = match method {
+            "initialize" => json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "completionProvider": { "triggerCharacters": ["_", "$"] },
+                    "hoverProvider": true,
+                    "definitionProvider": true,
+                },
+                "serverInfo": { "name": "cipherlang-lsp" },
+            }),
+            "shutdown" => {
+                self.shutdown = true;
+                Value::Null
+            },
+            "workspace/didChangeConfiguration" => {
+                self.search = search_path();
+                //methods may have appeared or gone, so check the open documents again
+                let mut uris: Vec<&String> = self.documents.keys().collect();
+                uris.sort();
+                return uris.into_iter().map(|x| self.diagnostics(x)).collect();
+            },
+            "textDocument/didOpen" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or("").to_string();
+                let text = params["textDocument"]["text"].as_str().unwrap_or("").to_string();
+                self.documents.insert(uri.clone(), text);
+                return vec![self.diagnostics(&uri)];
+            },
+            "textDocument/didChange" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or("").to_string();
+                if let Some(x) = params["contentChanges"].as_array().and_then(|x| x.last()) {
+                    let text = x["text"].as_str().unwrap_or("").to_string();
+                    self.documents.insert(uri.clone(), text);
+                }
+                return vec![self.diagnostics(&uri)];
+            },
+            "textDocument/didClose" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or("").to_string();
+                self.documents.remove(&uri);
+                return vec![json!({
+                    "jsonrpc": "2.0",
+                    "method": "textDocument/publishDiagnostics",
+                    "params": { "uri": uri, "diagnostics": [] },
+                })];
+            },
+            "textDocument/completion" => self.completion(params),
+            "textDocument/hover" => self.hover(params),
+            "textDocument/definition" => self.definition(params),
+            _ => {
+                //notifications get no response
+                if msg.get("id").is_none() {
+                    return Vec::new();
+                }
+                return vec![json!({
+                    "jsonrpc": "2.0",
+                    "id": msg["id"],
+                    "error": { "code": -32601, "message": format!("Unsupported method {}", method) },
+                })];
+            },
+        };
+        if msg.get("id").is_none() {
+            return Vec::new();
+        }
+        vec![json!({ "jsonrpc": "2.0", "id": msg["id"], "result": result })]
+    }
+
+    fn document(&self, params: &Value) -> &str {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+        self.documents.get(uri).map(|x| &x[..]).unwrap_or("")
+    }
+
+    fn diagnostics(&self, uri: &str) -> Value {
+        let text = self.documents.get(uri).map(|x| &x[..]).unwrap_or("");
+        let mut diags: Vec<Value> = Vec::new();
+        //compile errors stop at the first one
+        if let Err(e) = convert_to_method(text) {
+            let (line, col) = e.position().unwrap_or((1, 1));
+            diags.push(diagnostic(text, line, col, 1, &e.to_string()));
+        }
+        //transforms that are neither built in nor in the dictionary fail at runtime
+        if let Ok(script) = parse_script(text) {
+            for x in script.statements.iter() {
+                if let StatementKind::Apply(t, _) = &x.kind {
+                    if !is_builtin_transform(&t.name) && self.search.find(&t.name).is_none() {
+                        let msg = format!("'{}' is not a built-in transform or a dictionary method", t.name);
+                        diags.push(diagnostic(text, t.span.line, t.span.col, 2, &msg));
+                    }
+                }
+            }
+        }
+        json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": { "uri": uri, "diagnostics": diags },
+        })
+    }
+
+    fn completion(&self, params: &Value) -> Value {
+        let text = self.document(params);
+        let mut items: Vec<Value> = Vec::new();
+        for x in SPECIAL_VARS {
+            items.push(json!({ "label": x, "kind": 6, "documentation": special_var_doc(x) }));
+        }
+        for x in KEYWORDS {
+            items.push(json!({ "label": x, "kind": 14 }));
+        }
+        for (x, doc) in OPERATIONS {
+            items.push(json!({ "label": format!("${}", x), "kind": 24, "documentation": doc }));
+        }
+        for (x, args, _, doc) in BUILTIN_TRANSFORMS {
+            items.push(json!({ "label": x, "kind": 3, "detail": args, "documentation": doc }));
+        }
+        for x in self.search.names() {
+            items.push(json!({ "label": x, "kind": 3, "detail": "dictionary method" }));
+        }
+        //variables, constants and arguments declared in the script
+        if let Ok(script) = parse_script(text) {
+            let mut args = 0;
+            for x in script.statements.iter() {
+                match &x.kind {
+                    StatementKind::Var(s) => {items.push(json!({ "label": s, "kind": 6 }));},
+                    StatementKind::Const(s, v) => {items.push(json!({ "label": s, "kind": 21, "detail": v }));},
+                    StatementKind::Arg(s, _) => {
+                        items.push(json!({ "label": s, "kind": 6, "detail": format!("argument _{}", args) }));
+                        args += 1;
+                    },
+                    _ => {},
+                }
+            }
+        }
+        json!(items)
+    }
+
+    fn hover(&self, params: &Value) -> Value {
+        let text = self.document(params);
+        let tok = match token_at(text, &params["position"]) {
+            Some(s) => s,
+            None => {return Value::Null;},
+        };
+        let doc = match &tok.ttype {
+            TType::Transform(name, _) => transform_doc(&self.search, name),
+            TType::Operation(op) => OPERATIONS.iter().find(|x| x.0 == op).map(|x| format!("**${}**\n\n{}", x.0, x.1)),
+            TType::Identifier(s) if is_valid_special_var(s) => {
+                let doc = match get_special_var_num(s) {
+                    Some(n) if n >= 0x40 => format!("Argument #{}", n - 0x40),
+                    _ => special_var_doc(s).to_string(),
+                };
+                Some(format!("**{}**\n\n{}", s, doc))
+            },
+            TType::Identifier(s) => declaration(text, s).map(|(_, d)| format!("```\n{}\n```", d)),
+            _ => None,
+        };
+        match doc {
+            Some(s) => json!({ "contents": { "kind": "markdown", "value": s } }),
+            None => Value::Null,
+        }
+    }
+
+    fn definition(&self, params: &Value) -> Value {
+        let text = self.document(params);
+        let name = match token_at(text, &params["position"]).map(|x| x.ttype) {
+            Some(TType::Identifier(s)) => s,
+            _ => {return Value::Null;},
+        };
+        match declaration(text, &name) {
+            Some((t, _)) => json!({
+                "uri": params["textDocument"]["uri"],
+                "range": range(t.line, t.col, name.chars().count()),
+            }),
+            None => Value::Null,
+        }
+    }
+}
+
+// lines and columns are 1-based in tokens, 0-based in the protocol.
+// columns are counted in characters, which matches the protocol for BMP text
+fn range(line: usize, col: usize, len: usize) -> Value {
+    json!({
+        "start": { "line": line - 1, "character": col - 1 },
+        "end": { "line": line - 1, "character": col - 1 + len },
+    })
+}
+
+fn diagnostic(text: &str, line: usize, col: usize, severity: u8, msg: &str) -> Value {
+    //underline from the error to the end of its line
+    let len = text.lines().nth(line - 1).map(|x| x.chars().count()).unwrap_or(col);
+    json!({
+        "range": range(line, col, len.max(col) - col + 1),
+        "severity": severity,
+        "source": "cipherlang",
+        "message": msg,
+    })
+}
+
+// the token under the position. Tokens don't record where they end, so a token is taken to
+// run up to the next one (or the end of its line), less any whitespace before it
+fn token_at(text: &str, pos: &Value) -> Option<Token> {
+    let line = pos["line"].as_u64()? as usize + 1;
+    let col = pos["character"].as_u64()? as usize + 1;
+    let mut tokens = tokenize(text).ok()?;
+    let i = tokens.iter().rposition(|x| x.line == line && x.col <= col)?;
+    if tokens[i].ttype == TType::EndStatement {
+        return None;
+    }
+    let chars: Vec<char> = text.lines().nth(line - 1)?.chars().collect();
+    let mut end = match tokens.get(i + 1) {
+        Some(t) if t.line == line => t.col,
+        _ => chars.len() + 1,
+    };
+    while end > tokens[i].col + 1 && chars.get(end - 2).is_some_and(|x| x.is_whitespace()) {
+        end -= 1;
+    }
+    if col >= end {
+        return None;
+    }
+    Some(tokens.swap_remove(i))
+}
+
+// finds the token naming a var, const or argument, and a description of it
+fn declaration(text: &str, name: &str) -> Option<(Token, String)> {
+    let tokens = tokenize(text).ok()?;
+    let mut args = 0;
+    for st in tokens.split(|x| x.ttype == TType::EndStatement) {
+        let desc = match st {
+            [Token { ttype: TType::Var, .. }, ..] => "var".to_string(),
+            [Token { ttype: TType::Const, .. }, _, Token { ttype: TType::Str(v), .. }] => {
+                format!("const = {:?}", v)
+            },
+            [Token { ttype: TType::Directive(d), .. }, ..] if d == "arg" => {
+                args += 1;
+                match st.get(3) {
+                    Some(Token { ttype: TType::Str(v), .. }) => format!("argument _{} = {:?}", args - 1, v),
+                    _ => format!("argument _{} (required)", args - 1),
+                }
+            },
+            _ => {continue;},
+        };
+        if let Some(t) = st.get(1) {
+            if t.ttype == TType::Identifier(name.to_string()) {
+                return Some((t.clone(), format!("{} {}", name, desc)));
+            }
+        }
+    }
+    None
+}
+
+fn transform_doc(search: &SearchPath, name: &str) -> Option<String> {
+    if let Some((n, args, encoding, doc)) = BUILTIN_TRANSFORMS.iter().find(|x| x.0 == name) {
+        return Some(format!("**{}**<{}>\n\nBuilt-in transform. {} Accepts {} input.", n, args, doc, encoding.name()));
+    }
+    let method = search.load(name).ok()?;
+    let meta = read_metadata(&method).ok()?;
+    let mut doc = format!("**{}**\n\nDictionary method", name);
+    if let Some(s) = &meta.version {
+        doc += &format!(" (version {})", s);
+    }
+    for s in [&meta.description, &meta.usage].into_iter().flatten() {
+        doc += &format!("\n\n{}", s);
+    }
+    if !meta.args.is_empty() {
+        doc += &format!("\n\nArguments: {}", (0..meta.args.len()).map(|i| meta.arg_name(i)).collect::<Vec<String>>().join(", "));
+    }
+    Some(doc)
+}
+
+// the dictionary search path; an unreadable config file is reported and leaves it empty
+fn search_path() -> SearchPath {
+    SearchPath::from_env().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        SearchPath::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URI: &str = "file:///tmp/caesar.cip";
+    const SCRIPT: &str = "%argmin=0,argmax=0\nvar text\nconst bang \"!\"\nload text from _stdin\napply upper<> to text\nwrite text $cat bang to _stdout\n";
+
+    fn open(text: &str) -> (Server, Vec<Value>) {
+        let mut server = Server { documents: HashMap::new(), search: SearchPath::default(), shutdown: false };
+        let out = server.handle(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": { "textDocument": { "uri": URI, "text": text } },
+        }));
+        (server, out)
+    }
+
+    fn request(server: &mut Server, method: &str, line: u64, character: u64) -> Value {
+        let out = server.handle(&json!({
+            "jsonrpc": "2.0",
+            "id": 7,
+            "method": method,
+            "params": { "textDocument": { "uri": URI }, "position": { "line": line, "character": character } },
+        }));
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0]["id"], 7);
+        out[0]["result"].clone()
+    }
+
+    #[test]
+    fn clean_scripts_have_no_diagnostics() {
+        let (_, out) = open(SCRIPT);
+        assert_eq!(out[0]["method"], "textDocument/publishDiagnostics");
+        assert_eq!(out[0]["params"]["diagnostics"], json!([]));
+    }
+
+    #[test]
+    fn compile_errors_and_unknown_transforms_are_reported() {
+        let (_, out) = open("%argmin=0,argmax=0\nvar x\nload y from _stdin\n");
+        let diags = out[0]["params"]["diagnostics"].as_array().unwrap();
+        assert_eq!(diags.len(), 1);
+        assert_eq!(diags[0]["severity"], 1);
+        assert_eq!(diags[0]["range"]["start"]["line"], 2);
+
+        let (_, out) = open("%argmin=0,argmax=0\nvar x\napply no_such_transform<> to x\n");
+        let diags = out[0]["params"]["diagnostics"].as_array().unwrap();
+        let warning = diags.iter().find(|x| x["severity"] == 2).unwrap();
+        assert!(warning["message"].as_str().unwrap().contains("no_such_transform"));
+        assert_eq!(warning["range"]["start"], json!({ "line": 2, "character": 6 }));
+    }
+
+    #[test]
+    fn hover_describes_transforms_and_declarations() {
+        let (mut server, _) = open(SCRIPT);
+        let upper = request(&mut server, "textDocument/hover", 4, 8);
+        assert!(upper["contents"]["value"].as_str().unwrap().starts_with("**upper**<>"));
+        let bang = request(&mut server, "textDocument/hover", 5, 17);
+        assert!(bang["contents"]["value"].as_str().unwrap().contains("bang const = \"!\""));
+        let stdin = request(&mut server, "textDocument/hover", 3, 16);
+        assert!(stdin["contents"]["value"].as_str().unwrap().contains("Standard Input"));
+    }
+
+    #[test]
+    fn definition_finds_the_declaration() {
+        let (mut server, _) = open(SCRIPT);
+        let def = request(&mut server, "textDocument/definition", 3, 6);
+        assert_eq!(def["uri"], URI);
+        assert_eq!(def["range"], json!({
+            "start": { "line": 1, "character": 4 },
+            "end": { "line": 1, "character": 8 },
+        }));
+        assert_eq!(request(&mut server, "textDocument/definition", 3, 1), Value::Null);
+    }
+
+    #[test]
+    fn only_positions_inside_a_token_find_it() {
+        let (mut server, _) = open("%argmin=0,argmax=0\nvar text   \nload text   from _stdin\n");
+        let def = request(&mut server, "textDocument/definition", 2, 8);
+        assert_eq!(def["range"]["start"], json!({ "line": 1, "character": 4 }));
+        //the spaces after "text", on its own line and before "from"
+        assert_eq!(request(&mut server, "textDocument/definition", 2, 9), Value::Null);
+        assert_eq!(request(&mut server, "textDocument/definition", 1, 10), Value::Null);
+        assert_eq!(request(&mut server, "textDocument/hover", 2, 11), Value::Null);
+    }
+}
@@ -0,0 +1,222 @@
+use std::fs;
+use std::path::Path;
+use serde_json::{json, Map};
+use crate::*;
+use crate::interpret::external_transforms;
+use crate::dictionary::*;
+
+// A bundle is a set of methods packed into one JSON file so they can be moved between
+// dictionaries. Each method is kept in hex with its SHA-256 hash, the dictionary methods it
+// uses as transforms, and optionally the script it was compiled from.
+
+const BUNDLE_FORMAT: &str = "cipherlang-bundle";
+const BUNDLE_VERSION: u64 = 1;
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct BundleEntry {
+    pub name: String, //including the version, if it has one
+    pub method: Vec<u8>,
+    pub source: Option<(String, String)>, //file name and text of the script
+    pub depends: Vec<String>,
+}
+
+#[derive(Debug,Clone,Default,PartialEq)]
+pub struct Bundle {
+    pub methods: Vec<BundleEntry>,
+}
+
+impl Bundle {
+    pub fn new() -> Bundle {
+        Bundle::default()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&BundleEntry> {
+        self.methods.iter().find(|x| x.name == name)
+    }
+
+    // adds a method, replacing one of the same name
+    pub fn add(&mut self, name: &str, method: &[u8], source: Option<(String, String)>) -> Result<(), CError> {
+        MethodName::parse(name)?;
+        let entry = BundleEntry {
+            name: name.to_string(),
+            method: method.to_vec(),
+            source,
+            depends: external_transforms(method)?,
+        };
+        self.methods.retain(|x| x.name != name);
+        self.methods.push(entry);
+        Ok(())
+    }
+
+    // the method in the bundle a dependency refers to. Like a dictionary, a name without a
+    // version takes the highest version
+    pub fn provides(&self, name: &str) -> Option<&BundleEntry> {
+        let wanted = MethodName::parse(name).ok()?;
+        self.methods.iter().filter(|x| match MethodName::parse(&x.name) {
+            Ok(n) => n.namespace == wanted.namespace && n.name == wanted.name
+                && (wanted.version.is_none() || n.version == wanted.version),
+            Err(_) => false,
+        }).max_by(|a, b| {
+            let version = |x: &BundleEntry| MethodName::parse(&x.name).ok().and_then(|n| n.version).unwrap_or_default();
+            compare_versions(&version(a), &version(b))
+        })
+    }
+
+    // dependencies that are neither in the bundle nor on the search path, as
+    // (method, dependency) pairs
+    pub fn missing(&self, search: &SearchPath) -> Vec<(String, String)> {
+        let mut out = Vec::new();
+        for x in self.methods.iter() {
+            for d in x.depends.iter() {
+                if self.provides(d).is_none() && search.locate(d).is_empty() {
+                    out.push((x.name.clone(), d.clone()));
+                }
+            }
+        }
+        out
+    }
+
+    pub fn to_json(&self) -> String {
+        let methods: Vec<serde_json::Value> = self.methods.iter().map(|x| {
+            let meta = read_metadata(&x.method).unwrap_or_default();
+            let mut m = Map::new();
+            m.insert("name".to_string(), json!(x.name));
+            m.insert("sha256".to_string(), json!(hash(&x.method)));
+            if let Some(s) = &meta.version {
+                m.insert("version".to_string(), json!(s));
+            }
+            if let Some(s) = &meta.description {
+                m.insert("description".to_string(), json!(s));
+            }
+            m.insert("depends".to_string(), json!(x.depends));
+            m.insert("method".to_string(), json!(x.method.iter().map(|b| format!("{:02x}", b)).collect::<String>()));
+            if let Some((file, text)) = &x.source {
+                m.insert("source".to_string(), json!({"file": file, "text": text}));
+            }
+            serde_json::Value::Object(m)
+        }).collect();
+        let bundle = json!({
+            "format": BUNDLE_FORMAT,
+            "version": BUNDLE_VERSION,
+            "methods": methods,
+        });
+        serde_json::to_string_pretty(&bundle).unwrap_or_default() + "\n"
+    }
+
+    // reads a bundle, checking each method against its hash and recorded dependencies
+    pub fn parse(text: &str) -> Result<Bundle, CError> {
+        let invalid = |s: &str| CError::from(format!("Error: invalid bundle: {}", s));
+        let bundle: serde_json::Value = serde_json::from_str(text).map_err(|_| invalid("not JSON"))?;
+        if bundle["format"].as_str() != Some(BUNDLE_FORMAT) {
+            return Err(invalid("not a cipherlang bundle"));
+        }
+        match bundle["version"].as_u64() {
+            Some(BUNDLE_VERSION) => {},
+            _ => {return Err(invalid("unsupported bundle version"));},
+        }
+        let mut out = Bundle::new();
+        for x in bundle["methods"].as_array().ok_or(invalid("no method list"))? {
+            let name = x["name"].as_str().ok_or(invalid("a method has no name"))?;
+            let bad = |s: &str| CError::from(format!("Error: invalid bundle: method '{}' {}", name, s));
+            let digits = x["method"].as_str().ok_or(bad("has no code"))?;
+            if !digits.len().is_multiple_of(2) || !digits.is_ascii() {
+                return Err(bad("has malformed code"));
+            }
+            let method = (0..digits.len()).step_by(2).map(|i| u8::from_str_radix(&digits[i..i+2], 16))
+                .collect::<Result<Vec<u8>, _>>().map_err(|_| bad("has malformed code"))?;
+            if x["sha256"].as_str() != Some(&hash(&method)[..]) {
+                return Err(bad("does not match its hash"));
+            }
+            let source = match &x["source"] {
+                serde_json::Value::Null => None,
+                s => match (s["file"].as_str(), s["text"].as_str()) {
+                    (Some(f), Some(t)) => Some((f.to_string(), t.to_string())),
+                    _ => {return Err(bad("has a malformed source"));},
+                },
+            };
+            out.add(name, &method, source)?;
+            let depends: Option<Vec<&str>> = x["depends"].as_array().map(|d| d.iter().filter_map(|s| s.as_str()).collect());
+            if depends != Some(out.methods.last().unwrap().depends.iter().map(|s| &s[..]).collect()) {
+                return Err(bad("does not match its dependency list"));
+            }
+        }
+        Ok(out)
+    }
+
+    pub fn read(path: &Path) -> Result<Bundle, CError> {
+        Bundle::parse(&fs::read_to_string(path)?)
+    }
+
+    // installs every method into a dictionary, recording the bundle as their source
+    pub fn install(&self, dict: &mut Dictionary, origin: &Path) -> Result<Vec<DictEntry>, CError> {
+        self.methods.iter().map(|x| dict.install(&x.name, &x.method, Some(origin))).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile::convert_to_method;
+
+    // a method applying each of the given transforms
+    fn applying(transforms: &[&str]) -> Vec<u8> {
+        let mut script = String::from("%argmin=0,argmax=0,version=1,description=\"packed\"\nvar x\nload x from _stdin\n");
+        for t in transforms {
+            let call = if t.contains('<') { t.to_string() } else { format!("{}<>", t) };
+            script += &format!("apply {} to x\n", call);
+        }
+        convert_to_method(&script).unwrap()
+    }
+
+    #[test]
+    fn dependencies_are_the_transforms_that_are_not_built_in() {
+        let m = applying(&["upper", "team/vig<3>", "caesar", "team/vig<4>"]);
+        assert_eq!(external_transforms(&m).unwrap(), vec!["caesar", "team/vig"]);
+        assert!(external_transforms(&applying(&["upper", "shift<1>"])).unwrap().is_empty());
+    }
+
+    #[test]
+    fn bundles_survive_a_round_trip() {
+        let mut bundle = Bundle::new();
+        bundle.add("team/vig@1", &applying(&["upper"]), None).unwrap();
+        bundle.add("rot", &applying(&["team/vig"]), Some(("rot.cip".to_string(), "var x\n".to_string()))).unwrap();
+        let json = bundle.to_json();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["methods"][1]["sha256"], hash(&applying(&["team/vig"])));
+        assert_eq!(value["methods"][1]["depends"], json!(["team/vig"]));
+        assert_eq!(value["methods"][0]["description"], "packed");
+        assert_eq!(Bundle::parse(&json).unwrap(), bundle);
+    }
+
+    #[test]
+    fn tampered_bundles_are_rejected() {
+        let mut bundle = Bundle::new();
+        bundle.add("rot", &applying(&["caesar"]), None).unwrap();
+        let json = bundle.to_json();
+        let code = serde_json::from_str::<serde_json::Value>(&json).unwrap()["methods"][0]["method"].as_str().unwrap().to_string();
+        //changing one byte of the code breaks the hash
+        let last = &code[code.len() - 2..];
+        let changed = json.replace(&code, &format!("{}{}", &code[..code.len() - 2], if last == "00" { "01" } else { "00" }));
+        assert!(Bundle::parse(&changed).unwrap_err().to_string().contains("does not match its hash"));
+        let undeclared = json.replace("\"caesar\"", "\"other\"");
+        assert!(Bundle::parse(&undeclared).unwrap_err().to_string().contains("does not match its dependency list"));
+        assert!(Bundle::parse(&json.replace(BUNDLE_FORMAT, "zip")).unwrap_err().to_string().contains("not a cipherlang bundle"));
+        assert!(Bundle::parse("{").is_err());
+    }
+
+    #[test]
+    fn dependencies_are_found_in_the_bundle_or_on_the_search_path() {
+        let dir = std::env::temp_dir().join(format!("cipherlang-bundle-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        Dictionary::at(dir.clone()).unwrap().install("caesar", &applying(&[]), None).unwrap();
+        let mut search = SearchPath::default();
+        search.dirs.push((dir.clone(), PathSource::Flag));
+        let mut bundle = Bundle::new();
+        bundle.add("rot", &applying(&["caesar", "team/vig", "missing"]), None).unwrap();
+        bundle.add("team/vig@1.9", &applying(&[]), None).unwrap();
+        bundle.add("team/vig@1.10", &applying(&[]), None).unwrap();
+        assert_eq!(bundle.provides("team/vig").unwrap().name, "team/vig@1.10");
+        assert_eq!(bundle.missing(&search), vec![("rot".to_string(), "missing".to_string())]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,130 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::*;
+use crate::compile::convert_to_method;
+use crate::dictionary::hash;
+
+// Compiled local scripts are cached by a hash of the script's text, the method format
+// and the compiler version, so a script is recompiled exactly when one of them changes,
+// whatever the files' modification times say. Scripts can't include other files, so the
+// script's text is all the source there is. Entries are kept in the user's cache
+// directory rather than next to the scripts.
+
+const COMPILER_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+pub struct ScriptCache {
+    dir: PathBuf,
+}
+
+impl ScriptCache {
+    // the cache in the user's cache directory: ~/.cache/ciplang/scripts on Linux
+    pub fn open() -> Result<ScriptCache, CError> {
+        match dirs::cache_dir() {
+            Some(s) => Ok(ScriptCache::at(s.join("ciplang").join("scripts"))),
+            None => Err(CError::from_slice("Error: could not find the cache directory")),
+        }
+    }
+
+    pub fn at(dir: PathBuf) -> ScriptCache {
+        ScriptCache { dir }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    // the name of a script's entry
+    pub fn key(script: &str) -> String {
+        hash(format!("{}\0{}\0{}", METHOD_FORMAT, COMPILER_VERSION, script).as_bytes())
+    }
+
+    fn path(&self, script: &str) -> PathBuf {
+        self.dir.join(ScriptCache::key(script)).with_extension("cpth")
+    }
+
+    // the cached method for a script, if there is one
+    pub fn get(&self, script: &str) -> Option<Vec<u8>> {
+        let method = fs::read(self.path(script)).ok()?;
+        //a damaged entry is treated as missing, and replaced when the script is compiled
+        if method.len() < HEADER_LEN || !method.starts_with(b"CPTH") {
+            return None;
+        }
+        Some(method)
+    }
+
+    pub fn put(&self, script: &str, method: &[u8]) -> Result<(), CError> {
+        fs::create_dir_all(&self.dir)?;
+        //written under another name first, so a concurrent run never reads half an entry
+        let path = self.path(script);
+        let temp = path.with_extension(format!("tmp{}", std::process::id()));
+        fs::write(&temp, method)?;
+        fs::rename(&temp, &path)?;
+        Ok(())
+    }
+
+    // compiles a script, or returns the cached method if it has been compiled before.
+    // A cache that can't be written to is only a warning
+    pub fn compile(&self, script: &str) -> Result<Vec<u8>, CError> {
+        if let Some(s) = self.get(script) {
+            return Ok(s);
+        }
+        let method = convert_to_method(script)?;
+        if let Err(s) = self.put(script, &method) {
+            eprintln!("warning: could not write to the script cache: {}", s);
+        }
+        Ok(method)
+    }
+
+    // removes every entry. Returns how many there were
+    pub fn clean(&self) -> Result<usize, CError> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(s) => s,
+            Err(_) => {return Ok(0);},
+        };
+        let mut count = 0;
+        for x in entries.filter_map(|x| x.ok()) {
+            let path = x.path();
+            if path.is_file() {
+                fs::remove_file(&path)?;
+                if path.extension().is_some_and(|e| e == "cpth") {
+                    count += 1;
+                }
+            }
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scripts_are_compiled_once() {
+        let dir = std::env::temp_dir().join(format!("cipherlang-scripts-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let cache = ScriptCache::at(dir.clone());
+        let script = "%argmin=0,argmax=0\nvar x\nload x from _stdin\nwrite x to _stdout\n";
+        assert!(cache.get(script).is_none());
+        let method = cache.compile(script).unwrap();
+        assert_eq!(cache.get(script), Some(method.clone()));
+
+        //what's in the cache is used, without compiling again
+        let other = convert_to_method("%argmin=0,argmax=0\nvar y\n").unwrap();
+        cache.put(script, &other).unwrap();
+        assert_eq!(cache.compile(script).unwrap(), other);
+        assert_ne!(ScriptCache::key(script), ScriptCache::key(&script.replace('x', "z")));
+        assert!(cache.get(&script.replace('x', "z")).is_none());
+
+        //a damaged entry is compiled again
+        fs::write(dir.join(ScriptCache::key(script)).with_extension("cpth"), b"CPT").unwrap();
+        assert!(cache.get(script).is_none());
+        assert_eq!(cache.compile(script).unwrap(), method);
+
+        assert!(cache.compile("load x from nowhere\n").is_err());
+        assert_eq!(cache.clean().unwrap(), 1);
+        assert!(cache.get(script).is_none());
+        assert_eq!(ScriptCache::at(dir.join("none")).clean().unwrap(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
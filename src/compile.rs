@@ -1,128 +1,45 @@
 use std::collections::HashMap;
 use snailquote::unescape;
-use cipherlang::*;
+use crate::*;
+
+use crate::lexer::*;
 type Statement = Vec<Token>;
 
-pub fn convert_to_method(inp: &String) -> Result<Vec<u8>, CError> {
+pub fn convert_to_method(inp: &str) -> Result<Vec<u8>, CError> {
     //tokenize
-    let lines: Vec<String> = inp.lines().map(|x| x.to_string()).collect();
-    let mut tokens: Vec<Token> = tokenize(&lines)?;
+    let mut tokens: Vec<Token> = tokenize(inp)?;
     //compile to bytecode
     compile(&mut tokens)
 }
 
-
-
-pub fn tokenize(script: &Vec<String>) -> Result<Vec<Token>, CError> {
-    //tokenize line by line
-    let mut line_counter: usize = 1; //starts with 1 because code starts with line 1;
-    let mut tokens: Vec<Token> = Vec::new();
-
-    for line in script {
-        if line.len() == 0 || line.chars().nth(0) == Some('#') {
-            line_counter += 1;
-            continue;
-        }
-        //tokenize line
-        let mut k = tokenize_line(line, line_counter)?;
-        tokens.append(&mut k);
-        line_counter += 1;
-    }
-
-    //dbg!(&tokens);
-    Ok(tokens)
+// compiles a script, also returning the information a debugger needs
+pub fn convert_to_method_debug(inp: &str) -> Result<(Vec<u8>, DebugInfo), CError> {
+    let mut tokens: Vec<Token> = tokenize(inp)?;
+    compile_debug(&mut tokens)
 }
 
-pub fn tokenize_line(line: &String, count: usize) -> Result<Vec<Token>, CError> {
-
-    let mut tokens: Vec<Token> = Vec::new();
-    //run a state machine to split this into varieties
-    let segments = parse(line, ' ');
-    for s in segments.iter() {
-        let mut t = Token::new();
-        if s.len() < 1 {
-            continue;
-        }
-        let c1 = s.chars().nth(0).unwrap();
-        let cback = s.chars().nth_back(0).unwrap();
-        if c1 == '_' {
-            //special variable: Identifier
-            t.ttype = TType::Identifier(s.to_string());
-        } else if c1 == '$' {
-            //operation
-            if s.len() < 2 {
-                return Err(CError::from(format!("Line {}: Null operations are not permitted", count)));
-            }
-            t.ttype = TType::Operation(s[1..].to_string());
-        } else if c1 == '"' {
-            //string constant
-            //strparse value to handle escape characters
-            if s.len() < 2 {
-                return Err(CError::from(format!("Line {}: Invalid string constant", count)));
-            }
-            if let Ok(r) = unescape(s) {
-                t.ttype = TType::Str(r);
-            } else {
-                return Err(CError::from(format!("Line {}: Invalid string escape code", count)));
-            }
-        } else if c1 == '!' {
-            //file operation -- not implemented?
-            return Err(CError::from(format!("Line {}: File operation -- File operations are not implemented", count)));
-        } else if c1 == '%' {
-            //directive
-            if s.len() < 2 {
-                return Err(CError::from(format!("Line {}: Empty directive line", count)));
-            }
-            t.ttype = TType::Directive(s[1..].to_string());
-        } else if cback == '>' {
-            //transform: extract transform name and arguments
-            if let Some(n) = s.find('<') {
-                // I don't know if this actually works... (rust strings are weird)
-                let args = &s[n+1..s.len()-1];
-                let argvec: Vec<String> = parse(args, ',');
-
-                if !valid_transform_args(&argvec) {
-                    return Err(CError::from(format!("Line {}: Invalid transform arguments", count)));
-                }
-
-                t.ttype = TType::Transform(s[0..n].to_string(), argvec);
-
-            } else {
-                return Err(CError::from(format!("Line {}: Invalid transform token",count)));
-            }
-        } else if s == "var" {
-            t.ttype = TType::Var;
-        } else if s == "const" {
-            t.ttype = TType::Const;
-        } else if s == "load" {
-            t.ttype = TType::Load;
-        } else if s == "write" {
-            t.ttype = TType::Write;
-        } else if s == "apply" {
-            t.ttype = TType::Apply;
-        } else if s == "to" {
-            t.ttype = TType::To;
-        } else if s == "from" {
-            t.ttype = TType::From;
-        } else {
-            //attempt to determine type of token based on text contents
-            //most likely identifier
-            t.ttype = TType::Identifier(s.to_string());
-        }
-        t.line = count;
-        tokens.push(t);
+#[derive(Debug,Clone,Default)]
+pub struct DebugInfo {
+    pub lines: Vec<(usize, usize)>, //instruction offset, source line
+    pub vars: Vec<String>, //variable names, by number
+}
 
+impl DebugInfo {
+    pub fn line_of(&self, offset: usize) -> Option<usize> {
+        self.lines.iter().find(|x| x.0 == offset).map(|x| x.1)
     }
-    if segments.len() != 0 {
-        let mut k = Token::new();
-        k.ttype = TType::EndStatement;
-        tokens.push(k);
+    // offset of the first instruction on the line, or on the next line with code
+    pub fn offset_of(&self, line: usize) -> Option<(usize, usize)> {
+        self.lines.iter().filter(|x| x.1 >= line).min_by_key(|x| (x.1, x.0)).map(|x| (x.0, x.1))
     }
-    Ok(tokens)
+}
+
+pub fn tokenize(script: &str) -> Result<Vec<Token>, CError> {
+    Lexer::new(script).lex()
 }
 
 //this splits the tokens into a series of 
-pub fn organize_tokens(tlist: &mut Vec<Token>) -> Result<Vec<Statement>, CError> {
+pub fn organize_tokens(tlist: &mut [Token]) -> Result<Vec<Statement>, CError> {
     let mut statements: Vec<Statement> = Vec::new();
     
     let mut current: Statement = Vec::new();
@@ -135,7 +52,10 @@ pub fn organize_tokens(tlist: &mut Vec<Token>) -> Result<Vec<Statement>, CError>
     Ok(statements)
 }
 
-pub fn locate_vars(statements: &Vec<Statement>) -> Result<(Vec<String>, Vec<String>, HashMap<String, String> ), CError> {
+// variable names, constant names, and each constant's value by name
+type Tables = (Vec<String>, Vec<String>, HashMap<String, String>);
+
+pub fn locate_vars(statements: &[Statement]) -> Result<Tables, CError> {
     //in each statement, check for var/const declarations/usage
     //when var/const is declared, check if has been used yet. If so, error.
     //Else, add to appropriate table
@@ -146,75 +66,72 @@ pub fn locate_vars(statements: &Vec<Statement>) -> Result<(Vec<String>, Vec<Stri
     let mut consts: Vec<String> = Vec::new();
     let mut cval: HashMap<String, String> = HashMap::new();
     for x in statements.iter() {
-        if x.len() == 0 {
+        if x.is_empty() {
             //disregard empty statements
             continue;
         }
         if let TType::Var = x[0].ttype {
             //get declaration information
             if x.len() != 2 {
-                return Err(CError::from(format!("Line {}: Malformed variable declaration statement", x[0].line)));
+                return Err(CError::at(x[0].line, x[0].col, "Malformed variable declaration statement"));
             }
             if let TType::Identifier(s) = &x[1].ttype {
                 //search for identifier in var and const tables
-                if s.len() > 0 && s.chars().nth(0).unwrap() == '_' {
-                    return Err(CError::from(format!("Line {}: The '_' prefix for variable names is reserved", x[0].line)));
+                if s.starts_with('_') {
+                    return Err(CError::at(x[0].line, x[0].col, "The '_' prefix for variable names is reserved"));
                 }
                 if vars.contains(s) {
-                    return Err(CError::from(format!("Line {}: Error: Redeclaration of variable {}", x[0].line, s)));
+                    return Err(CError::at(x[0].line, x[0].col, &format!("Error: Redeclaration of variable {}", s)));
                 } else if consts.contains(s) {
-                    return Err(CError::from(format!("Line {}: Error: Redeclaration of constant {}", x[0].line, s)));
+                    return Err(CError::at(x[0].line, x[0].col, &format!("Error: Redeclaration of constant {}", s)));
                 } else {
                     vars.push(s.clone());
                 }
             } else {
-                return Err(CError::from(format!("Line {}: Malformed variable declaration statement", x[0].line)));
+                return Err(CError::at(x[0].line, x[0].col, "Malformed variable declaration statement"));
             }
         } else if let TType::Const = x[0].ttype {
             //get declaration information
             if x.len() != 3 {
-                return Err(CError::from(format!("Line {}: Malformed constant declaration statement", x[0].line)));
+                return Err(CError::at(x[0].line, x[0].col, "Malformed constant declaration statement"));
             }
             if let TType::Identifier(s) = &x[1].ttype {
                 //search for identifier in var and const tables
-                if s.len() > 0 && s.chars().nth(0).unwrap() == '_' {
-                    return Err(CError::from(format!("Line {}: The '_' prefix for variable names is reserved", x[0].line)));
+                if s.starts_with('_') {
+                    return Err(CError::at(x[0].line, x[0].col, "The '_' prefix for variable names is reserved"));
                 }
                 if vars.contains(s) {
-                    return Err(CError::from(format!("Line {}: Error: Redeclaration of variable {}", x[0].line, s)));
+                    return Err(CError::at(x[0].line, x[0].col, &format!("Error: Redeclaration of variable {}", s)));
                 } else if consts.contains(s) {
-                    return Err(CError::from(format!("Line {}: Error: Redeclaration of constant {}", x[0].line, s)));
+                    return Err(CError::at(x[0].line, x[0].col, &format!("Error: Redeclaration of constant {}", s)));
                 } else if let TType::Str(t) = &x[2].ttype {
                     consts.push(s.clone());
                     cval.insert(s.clone(), t.clone());
                 }
             } else {
-                return Err(CError::from(format!("Line {}: Malformed constant declaration statement", x[0].line)));
+                return Err(CError::at(x[0].line, x[0].col, "Malformed constant declaration statement"));
             }
         } else {
             //check if variables are used
             for tok in x {
                 if let TType::Identifier(s) = &tok.ttype {
                     //check if s has been declared yet
-                    if !consts.contains(&s) && !vars.contains(&s) {
-                        if !is_valid_special_var(&s) {
-                            return Err(CError::from(format!("Line {}: Identifier '{}' has not been declared", tok.line, s)));
-                        }
+                    if !consts.contains(s) && !vars.contains(s) && !is_valid_special_var(s) {
+                        return Err(CError::at(tok.line, tok.col, &format!("Identifier '{}' has not been declared", s)));
                     }
 
 
                 } else if let TType::Transform(n, a) = &tok.ttype {
-                    if !value_in_str_map(&cval, &n).is_some() {
+                    if value_in_str_map(&cval, n).is_none() {
                         let name = format!("__cpth_cGenConst`{}", consts.len());
                         consts.push(name.clone());
                         cval.insert(name, n.to_string());
                     } // if this constant already exists,
 
                     //handle args
-                    for i in 0..a.len() {
-                        let t = &a[i];
+                    for t in a.iter() {
                         //is t a constant or a number?
-                        if let Ok(_) = t.trim().parse::<i16>() {
+                        if t.trim().parse::<i16>().is_ok() {
 
                         } else {
                             //t is a constant:
@@ -227,7 +144,7 @@ pub fn locate_vars(statements: &Vec<Statement>) -> Result<(Vec<String>, Vec<Stri
                     }
 
                 } else if let TType::Operation(s) = &tok.ttype {
-                    if !value_in_str_map(&cval, &s).is_some() {
+                    if value_in_str_map(&cval, s).is_none() {
                         let name = format!("__cpth_cGenConst`{}", consts.len());
                         consts.push(name.clone());
                         cval.insert(name, s.to_string());
@@ -250,66 +167,68 @@ pub fn consolidate(statements: &Vec<Statement>) -> Result<Vec<ProtoInstruction>,
     //for each statement:
     for st in statements {
         let mut x = st.clone();
-        if x.len() == 0 { //ignore empty statements
+        if x.is_empty() { //ignore empty statements
             continue;
         }
         let mut p = ProtoInstruction::new();
+        p.line = x[0].line;
 
         match &x[0].ttype {
             TType::Load => { //should be [load] [ident:...] [from] [source] where source can be an
                       //operation statement
                 if x.len() < 4 || x.len() & 1 == 1 { // statement must have an even number of tokens
                                                    // to be a valid use of operations
-                    return Err(CError::from(format!("Line {}: Error: Malformed load statement", x[0].line)));
+                    return Err(CError::at(x[0].line, x[0].col, "Error: Malformed load statement"));
                 }
 
                 if !x[1].is_ident() {
-                    return Err(CError::from(format!("Line {}: Error: Malformed load statement - Missing first ident", x[0].line))); 
+                    return Err(CError::at(x[0].line, x[0].col, "Error: Malformed load statement - Missing first ident")); 
                 }
 
                 if !x[2].is_from() {
-                    return Err(CError::from(format!("Line {}: Error: Malformed load statement - Missing from", x[0].line))); 
+                    return Err(CError::at(x[0].line, x[0].col, "Error: Malformed load statement - Missing from")); 
                 }
                 //this uses a weird version of a for loop, so implementing using while
                 let mut i=x.len()-1;
                 while i > 3 {
                     let mut pt = ProtoInstruction::new();
                     if !x[i].is_ident() {
-                        return Err(CError::from(format!("Line {}: Error: Malformed load statement - missing final ident", x[0].line))); 
+                        return Err(CError::at(x[0].line, x[0].col, "Error: Malformed load statement - missing final ident")); 
                     }
                     pt.pitype = PIType::Operation;
+                    pt.line = x[0].line;
                     pt.source = match &x[i-2].ttype{
                         TType::Identifier(s) => s.to_string(),
-                        _ => { return Err(CError::from(format!("Line {}: Error: not ident", x[0].line))); }
+                        _ => { return Err(CError::at(x[0].line, x[0].col, "Error: not ident")); }
                     };
                     pt.second_source = match &x[i].ttype {
                         TType::Identifier(s) => s.to_string(),
-                        _ => { return Err(CError::from(format!("Line {}: Error not ident", x[0].line)));}
+                        _ => { return Err(CError::at(x[0].line, x[0].col, "Error not ident"));}
                     };
                     pt.value = match &x[i-1].ttype {
                         TType::Operation(s) => s.to_string(),
-                        _ => { return Err(CError::from(format!("Line {}: Error not op", x[0].line)));}
+                        _ => { return Err(CError::at(x[0].line, x[0].col, "Error not op"));}
                     };
                     
-                    x[i-2] = Token::new_val(TType::Identifier("_o".to_string()), x[i-2].line); //set s to "_o";
+                    x[i-2] = Token::at(TType::Identifier("_o".to_string()), x[i-2].line, x[i-2].col); //set s to "_o";
                     proto.push(pt);
-                    i = i-2;
+                    i -= 2;
                 }
                 p.pitype = PIType::Load;
                 p.target = match &x[1].ttype {
                     TType::Identifier(s) => s.to_string(),
-                    _ => { return Err(CError::from(format!("Line {}: Error not ident", x[0].line)));},
+                    _ => { return Err(CError::at(x[0].line, x[0].col, "Error not ident"));},
                 };
                 p.source = match &x[3].ttype {
                     TType::Identifier(s) => s.to_string(),
-                    _ => { return Err(CError::from(format!("Line {}: Error", x[0].line)));},
+                    _ => { return Err(CError::at(x[0].line, x[0].col, "Error"));},
                 };
                 proto.push(p);
             },
             TType::Write => {
          // statement must have an even number of tokens to be a valid use of operations
                 if x.len() < 4 || x.len() & 1 == 1 {
-                    return Err(CError::from(format!("Line {}: Error: Malformed load statement", x[0].line)));
+                    return Err(CError::at(x[0].line, x[0].col, "Error: Malformed load statement"));
                 }
 
                 let mut i=x.len()-3;
@@ -317,66 +236,67 @@ pub fn consolidate(statements: &Vec<Statement>) -> Result<Vec<ProtoInstruction>,
                     //create things
                     let mut pt = ProtoInstruction::new();
                     if !x[i].is_ident() || !x[i-1].is_oper() || !x[i-2].is_ident() {
-                        return Err(CError::from(format!("Line {}: Error: Malformed write statement", x[0].line))); 
+                        return Err(CError::at(x[0].line, x[0].col, "Error: Malformed write statement")); 
                     }
                     pt.pitype = PIType::Operation;
+                    pt.line = x[0].line;
                     pt.source = match &x[i-2].ttype{
                         TType::Identifier(s) => s.to_string(),
-                        _ => { return Err(CError::from(format!("Line {}: Error: not ident", x[0].line))); }
+                        _ => { return Err(CError::at(x[0].line, x[0].col, "Error: not ident")); }
                     };
                     pt.second_source = match &x[i].ttype {
                         TType::Identifier(s) => s.to_string(),
-                        _ => { return Err(CError::from(format!("Line {}: Error not ident", x[0].line)));}
+                        _ => { return Err(CError::at(x[0].line, x[0].col, "Error not ident"));}
                     };
                     pt.value = match &x[i-1].ttype {
                         TType::Operation(s) => s.to_string(),
-                        _ => { return Err(CError::from(format!("Line {}: Error not op", x[0].line)));}
+                        _ => { return Err(CError::at(x[0].line, x[0].col, "Error not op"));}
                     };
                     
-                    x[i-2] = Token::new_val(TType::Identifier("_o".to_string()), x[i-2].line); //set s to "_o";
+                    x[i-2] = Token::at(TType::Identifier("_o".to_string()), x[i-2].line, x[i-2].col); //set s to "_o";
                     proto.push(pt);
-                    i = i-2;
+                    i -= 2;
                 }
 
                 if !x[1].is_ident() {
-                    return Err(CError::from(format!("Line {}: Error: Malformed write statement - Missing first ident", x[0].line))); 
+                    return Err(CError::at(x[0].line, x[0].col, "Error: Malformed write statement - Missing first ident")); 
                 }
 
                 if !x[x.len()-2].is_to() {
-                    return Err(CError::from(format!("Line {}: Error: Malformed write statement - Missing to", x[0].line))); 
+                    return Err(CError::at(x[0].line, x[0].col, "Error: Malformed write statement - Missing to")); 
                 }
 
                 p.pitype = PIType::Load;
                 p.target = match &x.last().unwrap().ttype {
                     TType::Identifier(s) => s.to_string(),
-                    _ => { return Err(CError::from(format!("Line {}: Error not ident", x[0].line)));},
+                    _ => { return Err(CError::at(x[0].line, x[0].col, "Error not ident"));},
                 };
                 p.source = match &x[1].ttype {
                     TType::Identifier(s) => s.to_string(),
-                    _ => { return Err(CError::from(format!("Line {}: Error", x[0].line)));},
+                    _ => { return Err(CError::at(x[0].line, x[0].col, "Error"));},
                 };
                 proto.push(p);
             },
             TType::Apply => {
                 if x.len() != 4 {
-                    return Err(CError::from(format!("Line {}: Malformed apply statement", x[0].line)));
+                    return Err(CError::at(x[0].line, x[0].col, "Malformed apply statement"));
                 }
                 if !x[1].is_transform() || !x[2].is_to() || !x[3].is_ident() {
-                    return Err(CError::from(format!("Line {}: Malformed apply statement", x[0].line)));
+                    return Err(CError::at(x[0].line, x[0].col, "Malformed apply statement"));
                 }
                 p.pitype = PIType::Apply;
                 p.value = match &x[1].ttype {
                     TType::Transform(s, _) => s.to_string(),
-                    _ => { return Err(CError::from(format!("Line {}: Error", x[0].line)));},
+                    _ => { return Err(CError::at(x[0].line, x[0].col, "Error"));},
                 };
                 p.args = match &x[1].ttype {
                     TType::Transform(_, t) => t.to_vec(),
-                    _ => { return Err(CError::from(format!("Line {}: Error", x[0].line)));},
+                    _ => { return Err(CError::at(x[0].line, x[0].col, "Error"));},
                 };
-                p.arg_str = p.args.iter().map(|x| !x.parse::<f64>().is_ok()).collect();
+                p.arg_str = p.args.iter().map(|x| x.parse::<f64>().is_err()).collect();
                 p.target = match &x[3].ttype {
                     TType::Identifier(s) => s.to_string(),
-                    _ => { return Err(CError::from(format!("Line {}: Error", x[0].line)));},
+                    _ => { return Err(CError::at(x[0].line, x[0].col, "Error"));},
                 };
                 proto.push(p);
             },
@@ -391,7 +311,7 @@ pub fn consolidate(statements: &Vec<Statement>) -> Result<Vec<ProtoInstruction>,
     Ok(proto)
 }
 
-pub fn resolve_references(proto: &Vec<ProtoInstruction>, variables: &Vec<String>, constants: &Vec<String>, constvals: &HashMap<String, String>) -> Result<Vec<ProtoInstruction>, String> {
+pub fn resolve_references(proto: &[ProtoInstruction], variables: &[String], constants: &[String], constvals: &HashMap<String, String>) -> Result<Vec<ProtoInstruction>, String> {
     //go through each instruction, and create a new one
     let mut out: Vec<ProtoInstruction> = Vec::new();
 
@@ -404,36 +324,22 @@ pub fn resolve_references(proto: &Vec<ProtoInstruction>, variables: &Vec<String>
                 let vn = get_reference_num(variables, &x.source);
                 let cn = get_reference_num(constants, &x.source);
                 let sn = get_special_var_num(&x.source);
-                if vn == None {
-                    if cn == None {
-                        if sn == None {
-                            return Err(format!("Invalid reference {}", &x.source));
-                        } else {
-                            y.source = (SPECIAL_VAR_OFFSET + sn.unwrap()).to_string();
-                        }
-                    } else {
-                        y.source = (CONST_OFFSET + cn.unwrap()).to_string();
-                    }
-                } else {
-                    y.source = (VAR_OFFSET + vn.unwrap()).to_string();
-                }
+                y.source = match (vn, cn, sn) {
+                    (Some(n), _, _) => VAR_OFFSET + n,
+                    (None, Some(n), _) => CONST_OFFSET + n,
+                    (None, None, Some(n)) => SPECIAL_VAR_OFFSET + n,
+                    (None, None, None) => {return Err(format!("Invalid reference {}", &x.source));},
+                }.to_string();
 
                 let vn = get_reference_num(variables, &x.target);
                 let cn = get_reference_num(constants, &x.target);
                 let sn = get_special_var_num(&x.target);
-                if vn == None {
-                    if cn == None {
-                        if sn == None {
-                            return Err(format!("Invalid reference (somehow) uh oh"));
-                        } else {
-                            y.target = (SPECIAL_VAR_OFFSET + sn.unwrap()).to_string();
-                        }
-                    } else {
-                        return Err(format!("Error: Illegal write to constant"));
-                    }
-                } else {
-                    y.target = (VAR_OFFSET + vn.unwrap()).to_string();
-                }
+                y.target = match (vn, cn, sn) {
+                    (Some(n), _, _) => VAR_OFFSET + n,
+                    (None, Some(_), _) => {return Err("Error: Illegal write to constant".to_string());},
+                    (None, None, Some(n)) => SPECIAL_VAR_OFFSET + n,
+                    (None, None, None) => {return Err("Invalid reference (somehow) uh oh".to_string());},
+                }.to_string();
 
             },
             PIType::Apply => {
@@ -441,43 +347,36 @@ pub fn resolve_references(proto: &Vec<ProtoInstruction>, variables: &Vec<String>
                 let vn = get_reference_num(variables, &x.target);
                 let cn = get_reference_num(constants, &x.target);
                 let sn = get_special_var_num(&x.target);
-                if vn == None {
-                    if cn == None {
-                        if sn == None {
-                            return Err(format!("Invalid reference (somehow) uh oh"));
-                        } else {
-                            y.target = (SPECIAL_VAR_OFFSET + sn.unwrap()).to_string();
-                        }
-                    } else {
-                        return Err(format!("Error: Illegal write to constant"));
-                    }
-                } else {
-                    y.target = (VAR_OFFSET + vn.unwrap()).to_string();
-                }
+                y.target = match (vn, cn, sn) {
+                    (Some(n), _, _) => VAR_OFFSET + n,
+                    (None, Some(_), _) => {return Err("Error: Illegal write to constant".to_string());},
+                    (None, None, Some(n)) => SPECIAL_VAR_OFFSET + n,
+                    (None, None, None) => {return Err("Invalid reference (somehow) uh oh".to_string());},
+                }.to_string();
 
                 // val should be in const table, find it
-                let cname = match value_in_str_map(&constvals, &x.value) {
+                let cname = match value_in_str_map(constvals, &x.value) {
                     Some(s) => s,
-                    None => {return Err(format!("Invalid constant uh oh"));},
+                    None => {return Err("Invalid constant uh oh".to_string());},
                 };
-                if let Some(s) = index_of_vec_val(&constants, &cname) {
+                if let Some(s) = index_of_vec_val(constants, &cname) {
                     y.value = (CONST_OFFSET as usize + s).to_string();
                 } else {
-                    return Err(format!("Error: invalid constant ..."));
+                    return Err("Error: invalid constant ...".to_string());
                 }
 
                 // each arg should be in const table, find it
                 for (i, a) in x.args.iter().enumerate() {
                     //check if arg is an int
                     if x.arg_str[i] {
-                        let cname = match value_in_str_map(&constvals, &a) {
+                        let cname = match value_in_str_map(constvals, a) {
                             Some(s) => s,
                             None => {return Err(format!("Invalid constant {}", &a));},
                         };
-                        if let Some(s) = index_of_vec_val(&constants, &cname) {
+                        if let Some(s) = index_of_vec_val(constants, &cname) {
                             y.args[i] = (CONST_OFFSET as usize + s).to_string();
                         } else {
-                            return Err(format!("Error: invalid constant ..."));
+                            return Err("Error: invalid constant ...".to_string());
                         }
                     }
                 }
@@ -487,46 +386,32 @@ pub fn resolve_references(proto: &Vec<ProtoInstruction>, variables: &Vec<String>
                 let vn = get_reference_num(variables, &x.source);
                 let cn = get_reference_num(constants, &x.source);
                 let sn = get_special_var_num(&x.source);
-                if vn == None {
-                    if cn == None {
-                        if sn == None {
-                            return Err(format!("Invalid reference (somehow) uh oh"));
-                        } else {
-                            y.source = (SPECIAL_VAR_OFFSET + sn.unwrap()).to_string();
-                        }
-                    } else {
-                        y.source = (CONST_OFFSET + cn.unwrap()).to_string();
-                    }
-                } else {
-                    y.source = (VAR_OFFSET + vn.unwrap()).to_string();
-                }
+                y.source = match (vn, cn, sn) {
+                    (Some(n), _, _) => VAR_OFFSET + n,
+                    (None, Some(n), _) => CONST_OFFSET + n,
+                    (None, None, Some(n)) => SPECIAL_VAR_OFFSET + n,
+                    (None, None, None) => {return Err("Invalid reference (somehow) uh oh".to_string());},
+                }.to_string();
 
                 let vn = get_reference_num(variables, &x.second_source);
                 let cn = get_reference_num(constants, &x.second_source);
                 let sn = get_special_var_num(&x.second_source);
-                if vn == None {
-                    if cn == None {
-                        if sn == None {
-                            return Err(format!("Invalid reference (somehow) uh oh"));
-                        } else {
-                            y.second_source = (SPECIAL_VAR_OFFSET + sn.unwrap()).to_string();
-                        }
-                    } else {
-                        y.second_source = (CONST_OFFSET + cn.unwrap()).to_string();
-                    }
-                } else {
-                    y.second_source = (VAR_OFFSET + vn.unwrap()).to_string();
-                }
+                y.second_source = match (vn, cn, sn) {
+                    (Some(n), _, _) => VAR_OFFSET + n,
+                    (None, Some(n), _) => CONST_OFFSET + n,
+                    (None, None, Some(n)) => SPECIAL_VAR_OFFSET + n,
+                    (None, None, None) => {return Err("Invalid reference (somehow) uh oh".to_string());},
+                }.to_string();
 
                 // val should be in const table, find it
-                let cname = match value_in_str_map(&constvals, &x.value) {
+                let cname = match value_in_str_map(constvals, &x.value) {
                     Some(s) => s,
-                    None => {return Err(format!("Invalid constant uh oh"));},
+                    None => {return Err("Invalid constant uh oh".to_string());},
                 };
-                if let Some(s) = index_of_vec_val(&constants, &cname) {
+                if let Some(s) = index_of_vec_val(constants, &cname) {
                     y.value = (CONST_OFFSET as usize + s).to_string();
                 } else {
-                    return Err(format!("Error: invalid constant ..."));
+                    return Err("Error: invalid constant ...".to_string());
                 }
 
             },
@@ -553,7 +438,7 @@ pub fn generate_text(bin: &Vec<BinaryInstruction>) -> Result<Vec<u8>, String> {
 }
 
 pub fn generate_const(values: &HashMap<String, String>, constants: &Vec<String>) -> Result<Vec<u8>, String> {
-    let total_char_len: usize = values.iter().map(|(_, v)| v.len()+1).sum();
+    let total_char_len: usize = values.values().map(|v| v.len()+1).sum();
 
     let mut table: Vec<u8> = Vec::with_capacity(total_char_len + CONST_HEADER_WIDTH as usize* values.len());
     let mut pos: u16 = 0;
@@ -582,12 +467,11 @@ pub fn generate_const(values: &HashMap<String, String>, constants: &Vec<String>)
 }
 
 pub fn create_header(argmin: i32, argmax: i32, num_consts: usize, text_len: usize, var_num: usize) -> Result<Vec<u8>, String> {
-    let mut header: Vec<u8> = Vec::with_capacity(16);
-    header.resize(16, 0);
-    header[0] = 'C' as u8;
-    header[1] = 'P' as u8;
-    header[2] = 'T' as u8;
-    header[3] = 'H' as u8;
+    let mut header: Vec<u8> = vec![0; 16];
+    header[0] = b'C';
+    header[1] = b'P';
+    header[2] = b'T';
+    header[3] = b'H';
     header[4] = MAJOR_VERSION;
     header[5] = MINOR_VERSION;
     header[6] = PATCH_NUM;
@@ -602,9 +486,7 @@ pub fn create_header(argmin: i32, argmax: i32, num_consts: usize, text_len: usiz
     header[0xf] = 0x0;
     Ok(header)
 }
-pub fn assemble(header: &Vec<u8>, text: &Vec<u8>, data: &Vec<u8>) -> Result<Vec<u8>, CError>{
-    let total = header.len() + text.len() + data.len();
-    eprintln!("header : 0x{:x};  text : 0x{:x};  data : 0x{:x};  total : 0x{:x}", header.len(), text.len(), data.len(), total);
+pub fn assemble(header: &[u8], text: &[u8], data: &[u8]) -> Result<Vec<u8>, CError>{
     let mut table: Vec<u8> = Vec::new();
     table.extend(header.iter());
     table.extend(text.iter());
@@ -613,7 +495,7 @@ pub fn assemble(header: &Vec<u8>, text: &Vec<u8>, data: &Vec<u8>) -> Result<Vec<
 }
 
 //takes an identifier, and replaces it with a numerical reference
-pub fn get_reference_num(ident_list: &Vec<String>, ident: &String) -> Option<u16> {
+pub fn get_reference_num(ident_list: &[String], ident: &String) -> Option<u16> {
     for (i, x) in ident_list.iter().enumerate() {
         if x == ident {
             return Some((i) as u16);
@@ -622,14 +504,17 @@ pub fn get_reference_num(ident_list: &Vec<String>, ident: &String) -> Option<u16
     None
 }
 
+//special vars with a constant name, indexed by number
+pub const SPECIAL_VARS: [&str; 16] = ["_", "_o", "_randU", "_randL", "_randE", "_randN", "_randA",
+    "_argc", "_stdin", "_stdout", "_null", "_c", "_k", "_cs", "_cc", "_loc"];
+
 //special var space map:
 //0x300 - 0x33f: general use variables
 //0x340 - 0x35f: arguments
 //this should use Option<u16> or something like that
 pub fn get_special_var_num(name: &str) -> Option<u16> {
     //values contains any constant values. Some, like _# have a non-constant name
-    let values = vec!["_", "_o", "_randU", "_randL", "_randE", "_randN", "_randA",
-    "_argc", "_stdin", "_stdout", "_null", "_c", "_k", "_cs", "_cc", "_loc"];
+    let values = SPECIAL_VARS;
     for (i, _) in values.iter().enumerate() {
         if values[i] == name {
             return Some(i.try_into().unwrap());
@@ -648,7 +533,7 @@ pub fn get_special_var_num(name: &str) -> Option<u16> {
 }
 
 pub fn is_valid_special_var(name: &str) -> bool {
-    if name.len() == 0 {
+    if name.is_empty() {
         return false;
     }
     let values = vec!["_", "_o", "_randU", "_randL", "_randE", "_randN", "_randA",
@@ -666,34 +551,38 @@ pub fn is_valid_special_var(name: &str) -> bool {
     false
 }
 
-pub fn valid_transform_args(args: &Vec<String>) -> bool {
-
-    for _x in args.iter() {
-        //should be a number or a string constant. I'm not sure how to validate this, but
-        //it's here
-
-    }
-    true
-}
-
 // Compiles a list of tokens to a method (bytecode)
-pub fn compile(tlist: &mut Vec<Token>) -> Result<Vec<u8>, CError> {
-    eprintln!("Compiling Method");
+pub fn compile(tlist: &mut [Token]) -> Result<Vec<u8>, CError> {
+    Ok(compile_debug(tlist)?.0)
+}
 
+pub fn compile_debug(tlist: &mut [Token]) -> Result<(Vec<u8>, DebugInfo), CError> {
     //check for directives, verify req'd args
     let mut argmax: i32=-1;
     let mut argmin: i32=-1;
     let mut directives: Vec<String> = Vec::new();
-    for x in tlist.iter() {
-        if let TType::Directive(s) = &x.ttype {
-            let mut k = parse(&s, ',');
-            directives.append(&mut k);     
+    let mut metadata = Metadata::default();
+
+    //organize tokens into expressions
+    let mut statements = organize_tokens(tlist)?;
+
+    //pull directive statements out of the script
+    for x in statements.iter() {
+        if x.is_empty() {
+            continue;
+        }
+        if let TType::Directive(s) = &x[0].ttype {
+            if s == "arg" {
+                metadata.args.push(arg_declaration(x)?);
+            } else if x.len() == 1 {
+                let mut k = parse(s, ',');
+                directives.append(&mut k);
+            } else {
+                return Err(CError::at(x[0].line, x[0].col, "Unexpected tokens after directive"));
+            }
         }
     }
-    
-    //now, remove all directive tokens from tlist
-    tlist.retain(|v| if let TType::Directive(_) = v.ttype {false}else{true});
-
+    statements.retain(|v| v.is_empty() || !matches!(v[0].ttype, TType::Directive(_)));
 
     //handle directives
     for x in directives.iter() {
@@ -704,31 +593,60 @@ pub fn compile(tlist: &mut Vec<Token>) -> Result<Vec<u8>, CError> {
             Some(s) => {
                 if s == 0 {
                     return Err(CError::from_slice("Directive is missing a name"));
-                } else if s == directives.len() {
+                } else if s == x.len() - 1 {
                     return Err(CError::from_slice("Directive is missing a key"));
                 }
                 else {
                     let name = &x[0..s];
-                    let value = &x[s+1..];
+                    let value = directive_value(&x[s+1..])?;
                     if name == "argmin" {
                         argmin = value.parse()?
                     } else if name == "argmax" {
                         argmax = value.parse()?
+                    } else if name == "arg" {
+                        return Err(CError::from_slice("Arguments are declared with %arg NAME = \"DEFAULT\""));
+                    } else if name == "depends" {
+                        return Err(CError::from_slice("Dependencies are found by the compiler, not declared"));
                     } else {
-                        return Err(CError::from(format!("Invalid directive key {}", name)));
+                        metadata.set(name, &value)?;
                     }
                 }
             },
             None => {return Err(CError::from_slice("Directive is missing an equal sign"))},
         }
     }
+    //named arguments imply the argument counts when they aren't given
+    let declared = metadata.args.iter().any(|x| x.required || x.default.is_some());
+    if declared && argmax < 0 {
+        argmax = metadata.args.len() as i32;
+    }
+    if declared && argmin < 0 {
+        argmin = metadata.args.iter().filter(|x| x.required).count() as i32;
+    }
     if argmin < 0 || argmax < 0 {
         return Err(CError::from_slice("argmax and argmin must be specified"));
     }
+    if metadata.args.len() > argmax as usize {
+        return Err(CError::from_slice("More arguments are named than argmax allows"));
+    }
+    if argmax > 0x20 {
+        return Err(CError::from_slice("A method may take at most 32 arguments"));
+    }
+    for (i, x) in metadata.args.iter().enumerate() {
+        if metadata.args[..i].iter().any(|y| y.name == x.name) {
+            return Err(CError::from(format!("Redeclaration of argument {}", x.name)));
+        }
+    }
+    for (i, x) in metadata.args.iter_mut().enumerate() {
+        //unnamed %argnames slots below argmin must be supplied
+        if x.default.is_none() && (i as i32) < argmin {
+            x.required = true;
+        }
+    }
+
+    //replace argument names with the matching argument special var
+    bind_arg_names(&mut statements, &metadata.args)?;
 
-    //organize tokens into expressions
-    
-    let statements = organize_tokens(tlist)?;
     //create an index of variables and consts
 
     let (variables, constants, constvals) = locate_vars(&statements)?;
@@ -750,6 +668,14 @@ pub fn compile(tlist: &mut Vec<Token>) -> Result<Vec<u8>, CError> {
 
     //generate text section of method
     let text = generate_text(&binary_ins)?;
+
+    //record which line each instruction came from
+    let mut debug = DebugInfo { lines: Vec::new(), vars: variables.clone() };
+    let mut offset = HEADER_LEN;
+    for (x, b) in instructions.iter().zip(binary_ins.iter()) {
+        debug.lines.push((offset, x.line));
+        offset += b.binary()?.len();
+    }
     
     //generate constants table of method
     let data = generate_const(&constvals, &constants)?;
@@ -757,6 +683,136 @@ pub fn compile(tlist: &mut Vec<Token>) -> Result<Vec<u8>, CError> {
     //generate method header
     let header = create_header(argmin, argmax, constants.len(), text.len(), variables.len())?;
 
-    //assemble into a complete method file
-    assemble(&header, &text, &data)
+    //assemble into a complete method file, then record the transforms it needs from the dictionary
+    let method = assemble(&header, &text, &data)?;
+    metadata.depends = crate::interpret::external_transforms(&method)?;
+    if metadata.is_empty() {
+        return Ok((method, debug));
+    }
+    Ok((write_metadata(&method, &metadata)?, debug))
+}
+
+//parses `%arg NAME` or `%arg NAME = "DEFAULT"`
+fn arg_declaration(st: &Statement) -> Result<ArgSpec, CError> {
+    let (line, col) = (st[0].line, st[0].col);
+    let name = match st.get(1).map(|x| &x.ttype) {
+        Some(TType::Identifier(s)) => s.clone(),
+        _ => {return Err(CError::at(line, col, "Argument declaration is missing a name"));},
+    };
+    if name.starts_with('_') {
+        return Err(CError::at(line, col, "The '_' prefix for argument names is reserved"));
+    }
+    match st.len() {
+        2 => Ok(ArgSpec { name, default: None, required: true }),
+        4 => {
+            if st[2].ttype != TType::Identifier("=".to_string()) {
+                return Err(CError::at(line, col, "Malformed argument declaration"));
+            }
+            if let TType::Str(s) = &st[3].ttype {
+                Ok(ArgSpec { name, default: Some(s.clone()), required: false })
+            } else {
+                Err(CError::at(line, col, "Argument default must be a string constant"))
+            }
+        },
+        _ => Err(CError::at(line, col, "Malformed argument declaration")),
+    }
+}
+
+//rewrites references to named arguments as argument special vars (_0 to _31)
+fn bind_arg_names(statements: &mut [Statement], args: &[ArgSpec]) -> Result<(), CError> {
+    for x in statements.iter_mut() {
+        if x.len() > 1 && (x[0].is_var() || x[0].is_const()) {
+            if let TType::Identifier(s) = &x[1].ttype {
+                if args.iter().any(|a| &a.name == s) {
+                    return Err(CError::at(x[0].line, x[0].col, &format!("Error: Redeclaration of argument {}", s)));
+                }
+            }
+            continue;
+        }
+        for tok in x.iter_mut() {
+            if let TType::Identifier(s) = &tok.ttype {
+                if let Some(n) = args.iter().position(|a| &a.name == s) {
+                    tok.ttype = TType::Identifier(format!("_{}", n));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+//directive values may be quoted to allow spaces and commas
+fn directive_value(value: &str) -> Result<String, CError> {
+    if value.starts_with('"') {
+        match unescape(value) {
+            Ok(s) => Ok(s),
+            Err(_) => Err(CError::from(format!("Invalid directive value {}", value))),
+        }
+    } else {
+        Ok(value.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCRIPT: &str = "%argmin=1,argmax=2\n%name=caesar,description=\"shifts, then stops\"\n%usage=\"caesar SHIFT [ALPHABET]\",argnames=\"shift,alphabet\"\nvar x\nload x from _stdin\nwrite x to _stdout\n";
+
+    #[test]
+    fn directives_are_stored_as_metadata() {
+        let method = convert_to_method(SCRIPT).unwrap();
+        assert_ne!(method[HEADER_FLAGS] & FLAG_METADATA, 0);
+        let meta = read_metadata(&method).unwrap();
+        assert_eq!(meta.name.as_deref(), Some("caesar"));
+        assert_eq!(meta.description.as_deref(), Some("shifts, then stops"));
+        assert_eq!(meta.usage.as_deref(), Some("caesar SHIFT [ALPHABET]"));
+        let names: Vec<&str> = meta.args.iter().map(|x| &x.name[..]).collect();
+        assert_eq!(names, vec!["shift", "alphabet"]);
+    }
+
+    #[test]
+    fn methods_without_directives_have_no_metadata_section() {
+        let method = convert_to_method("%argmin=0,argmax=0\nvar x\nload x from _stdin\nwrite x to _stdout\n").unwrap();
+        assert_eq!(method[HEADER_FLAGS] & FLAG_METADATA, 0);
+        assert_eq!(const_table_end(&method).unwrap(), method.len());
+    }
+
+    #[test]
+    fn bad_directives_are_rejected() {
+        let compile = |d: &str| convert_to_method(&format!("%argmin=0,argmax=1\n{}\nvar x\n", d));
+        assert!(compile("%colour=blue").is_err());
+        assert!(compile("%argnames=\"a,b\"").is_err());
+        assert!(compile("%name=").is_err());
+    }
+
+    #[test]
+    fn wrong_argument_counts_show_the_usage() {
+        let method = convert_to_method(SCRIPT).unwrap();
+        let e = crate::interpret::interpret(&method, "abc", &[], 0).unwrap_err().to_string();
+        assert!(e.contains("Usage: caesar SHIFT [ALPHABET]"), "{}", e);
+        assert!(e.contains("Arguments: _0 (shift), _1 (alphabet)"), "{}", e);
+    }
+
+    #[test]
+    fn named_arguments_reach_the_method() {
+        let script = "%arg prefix = \"<<\"\n%arg suffix = \">>\"\n%arg sep\nvar x\nload x from _stdin\nwrite prefix $cat sep $cat x $cat suffix to _stdout\n".to_string();
+        let method = convert_to_method(&script).unwrap();
+        let output = crate::interpret::interpret(&method, "abc", &["sep=:", "suffix=!"], 0).unwrap();
+        assert_eq!(output, "<<:abc!\n");
+    }
+
+    #[test]
+    fn argument_declarations_are_checked() {
+        for bad in ["%arg _x\n", "%arg x = y\n", "%arg x\n%arg x\n", "%arg x\nvar x\n"] {
+            assert!(convert_to_method(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn the_compiler_records_dependencies() {
+        let method = convert_to_method("%argmin=0,argmax=0\nvar x\napply vig<1> to x\napply upper<> to x\napply team/rot<> to x\napply vig<2> to x\n").unwrap();
+        assert_eq!(read_metadata(&method).unwrap().depends, vec!["team/rot", "vig"]);
+        let err = convert_to_method("%argmin=0,argmax=0,depends=vig\nvar x\n").unwrap_err();
+        assert!(err.to_string().contains("found by the compiler"), "{}", err);
+    }
 }
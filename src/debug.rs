@@ -0,0 +1,389 @@
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
+use crate::*;
+
+use crate::compile::*;
+use crate::dictionary::SearchPath;
+use crate::interpret::*;
+use crate::trace::*;
+
+// Interactive step debugger.
+// Commands are read a line at a time; the script is compiled with a line table so
+// that breakpoints can be set by source line as well as by instruction offset.
+
+const HELP: &str = "\
+break LINE | break @OFFSET   stop before the given line or instruction
+delete N                     remove breakpoint N
+breakpoints                  list breakpoints
+step (s)                     run one instruction, entering external transforms
+next (n)                     run one instruction, running external transforms to completion
+continue (c)                 run until a breakpoint or the end of the method
+print (p) WHAT               show vars, last_tr, last_op, stdin, output, args or a variable
+backtrace (bt)               show the methods being run
+list (l)                     show the source around the current line
+quit (q)                     stop debugging";
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum Breakpoint {
+    Line(usize, usize), //line, offset of its first instruction
+    Offset(usize),
+}
+
+// collects the events of the instructions run by a command
+struct Recorder {
+    events: Vec<TraceEvent>,
+}
+
+impl Tracer for Recorder {
+    fn instruction(&mut self, event: &TraceEvent) {
+        self.events.push(event.clone());
+    }
+    fn finish(&mut self, _depth: usize, _instructions: usize, _output_len: usize) {}
+}
+
+pub struct Debugger {
+    exec: Vm,
+    debug: DebugInfo,
+    source: Vec<String>,
+    breakpoints: Vec<Option<Breakpoint>>, //deleted breakpoints keep their number
+    output: Option<Value>, //set once the method has finished
+    failed: bool,
+}
+
+impl Debugger {
+    pub fn new(script: &str, input: &str, args: &[&str]) -> Result<Debugger, CError> {
+        let (method, debug) = convert_to_method_debug(script)?;
+        Ok(Debugger {
+            exec: Vm::new(method, input.as_bytes(), args, 0)?,
+            debug,
+            source: script.lines().map(|x| x.to_string()).collect(),
+            breakpoints: Vec::new(),
+            output: None,
+            failed: false,
+        })
+    }
+
+    pub fn with_search_path(self, search: Rc<SearchPath>) -> Debugger {
+        Debugger { exec: self.exec.with_search_path(search), ..self }
+    }
+
+    // reads commands until quit or the end of the command stream
+    pub fn run<R: BufRead, W: Write>(&mut self, commands: R, out: &mut W) -> io::Result<()> {
+        self.location(out)?;
+        let mut lines = commands.lines();
+        loop {
+            write!(out, "(cdb) ")?;
+            out.flush()?;
+            let line = match lines.next() {
+                Some(s) => s?,
+                None => {
+                    writeln!(out)?;
+                    return Ok(());
+                },
+            };
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                [] => {},
+                ["quit" | "q"] => {return Ok(());},
+                ["help" | "h"] => {writeln!(out, "{}", HELP)?;},
+                ["break" | "b", at] => {self.set_breakpoint(at, out)?;},
+                ["delete" | "d", n] => {
+                    match n.parse::<usize>().ok().and_then(|x| self.breakpoints.get_mut(x.wrapping_sub(1))) {
+                        Some(b) if b.is_some() => {*b = None;},
+                        _ => {writeln!(out, "No breakpoint number {}", n)?;},
+                    }
+                },
+                ["breakpoints"] => {self.list_breakpoints(out)?;},
+                ["step" | "s"] => {self.resume(out, |_| true)?;},
+                ["next" | "n"] => {
+                    let depth = self.exec.frames.len();
+                    self.resume(out, |d| d.exec.frames.len() <= depth || d.at_breakpoint().is_some())?;
+                },
+                ["continue" | "c"] => {self.resume(out, |d| d.at_breakpoint().is_some())?;},
+                ["print" | "p", what] => {self.print(what, out)?;},
+                ["backtrace" | "bt"] => {self.backtrace(out)?;},
+                ["list" | "l"] => {self.list(out)?;},
+                _ => {writeln!(out, "Unknown command '{}'. Type help for a list of commands.", line.trim())?;},
+            }
+        }
+    }
+
+    // runs instructions until stop returns true (checked after each instruction) or the method ends
+    fn resume<W: Write>(&mut self, out: &mut W, stop: impl Fn(&Debugger) -> bool) -> io::Result<()> {
+        if self.output.is_some() || self.failed {
+            writeln!(out, "The method is not running.")?;
+            return Ok(());
+        }
+        let mut rec = Recorder { events: Vec::new() };
+        loop {
+            let result = self.exec.step(&mut rec);
+            match result {
+                Ok(Some(output)) => {
+                    self.show_writes(&rec.events, out)?;
+                    writeln!(out, "Method finished. Output:")?;
+                    out.write_all(output.as_bytes())?;
+                    self.output = Some(output);
+                    return Ok(());
+                },
+                Ok(None) => {},
+                Err(s) => {
+                    self.show_writes(&rec.events, out)?;
+                    writeln!(out, "{}", s)?;
+                    self.failed = true;
+                    return Ok(());
+                },
+            }
+            if stop(self) {
+                break;
+            }
+        }
+        //only the last few writes are useful when running to a breakpoint
+        let skip = rec.events.len().saturating_sub(8);
+        self.show_writes(&rec.events[skip..], out)?;
+        if let Some(n) = self.at_breakpoint() {
+            writeln!(out, "Breakpoint {}", n)?;
+        }
+        self.location(out)
+    }
+
+    fn at_breakpoint(&self) -> Option<usize> {
+        let frame = self.exec.current()?;
+        self.breakpoints.iter().position(|x| match x {
+            Some(Breakpoint::Line(_, off)) => frame.depth == 0 && frame.count == *off,
+            Some(Breakpoint::Offset(off)) => frame.count == *off,
+            None => false,
+        }).map(|x| x + 1)
+    }
+
+    fn set_breakpoint<W: Write>(&mut self, at: &str, out: &mut W) -> io::Result<()> {
+        let bp = if let Some(s) = at.strip_prefix('@') {
+            let off = match s.strip_prefix("0x") {
+                Some(h) => usize::from_str_radix(h, 16).ok(),
+                None => s.parse().ok(),
+            };
+            match off {
+                Some(o) => Breakpoint::Offset(o),
+                None => {return writeln!(out, "Invalid offset '{}'", s);},
+            }
+        } else {
+            let line = match at.parse::<usize>() {
+                Ok(s) => s,
+                Err(_) => {return writeln!(out, "Invalid line '{}'", at);},
+            };
+            match self.debug.offset_of(line) {
+                Some((off, l)) => Breakpoint::Line(l, off),
+                None => {return writeln!(out, "No code at or after line {}", line);},
+            }
+        };
+        self.breakpoints.push(Some(bp));
+        write!(out, "Breakpoint {} ", self.breakpoints.len())?;
+        describe_breakpoint(&bp, out)
+    }
+
+    fn list_breakpoints<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        if self.breakpoints.iter().all(|x| x.is_none()) {
+            return writeln!(out, "No breakpoints.");
+        }
+        for (i, x) in self.breakpoints.iter().enumerate() {
+            if let Some(b) = x {
+                write!(out, "{}: ", i + 1)?;
+                describe_breakpoint(b, out)?;
+            }
+        }
+        Ok(())
+    }
+
+    // prints the instruction that runs next
+    fn location<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let frame = match self.exec.current() {
+            Some(f) => f,
+            None => {return Ok(());},
+        };
+        if frame.finished() {
+            return writeln!(out, "{}at the end of the method", frame_name(frame));
+        }
+        let inst = match frame.next_instruction() {
+            Ok((inst, _)) => {
+                let operands: Vec<String> = inst.operands().iter().map(|x| format!("0x{:03x}", x)).collect();
+                format!("{} {}", op_name(inst.opcode()), operands.join(" "))
+            },
+            Err(s) => s.to_string(),
+        };
+        write!(out, "{}0x{:04x}: {}", frame_name(frame), frame.count, inst)?;
+        match self.source_line(frame) {
+            Some(l) => writeln!(out, "  (line {}: {})", l, self.source[l-1].trim()),
+            None => writeln!(out),
+        }
+    }
+
+    fn source_line(&self, frame: &Frame) -> Option<usize> {
+        if frame.depth != 0 {
+            return None;
+        }
+        self.debug.line_of(frame.count).filter(|x| *x >= 1 && *x <= self.source.len())
+    }
+
+    fn show_writes<W: Write>(&self, events: &[TraceEvent], out: &mut W) -> io::Result<()> {
+        for e in events.iter() {
+            for (k, v) in e.writes.iter() {
+                writeln!(out, "{}{} = {:?}", "  ".repeat(e.depth + 1), self.var_name(e.depth, k), v)?;
+            }
+        }
+        Ok(())
+    }
+
+    // names the variables of the script being debugged; methods from the dictionary only have numbers
+    fn var_name(&self, depth: usize, target: &str) -> String {
+        let named = target.strip_prefix('v').and_then(|x| x.parse::<usize>().ok())
+            .filter(|_| depth == 0).and_then(|x| self.debug.vars.get(x));
+        match named {
+            Some(s) => s.clone(),
+            None => target.to_string(),
+        }
+    }
+
+    fn print<W: Write>(&self, what: &str, out: &mut W) -> io::Result<()> {
+        let frame = match self.exec.current() {
+            Some(f) => f,
+            None => {
+                return match (what, &self.output) {
+                    ("output", Some(s)) => out.write_all(s.as_bytes()),
+                    _ => writeln!(out, "The method is not running."),
+                };
+            },
+        };
+        match what {
+            "vars" => {
+                if frame.vars.is_empty() {
+                    writeln!(out, "No variables.")?;
+                }
+                for (i, x) in frame.vars.iter().enumerate() {
+                    writeln!(out, "{} = {:?}", self.var_name(frame.depth, &format!("v{}", i)), x)?;
+                }
+                Ok(())
+            },
+            "last_tr" | "_" => writeln!(out, "{:?}", frame.last_tr),
+            "last_op" | "_o" => writeln!(out, "{:?}", frame.last_op),
+            "output" => writeln!(out, "{:?}", frame.output),
+            "args" => {
+                for (i, x) in frame.args.iter().enumerate() {
+                    writeln!(out, "_{} = {:?}", i, x)?;
+                }
+                Ok(())
+            },
+            "stdin" | "_stdin" => {
+                writeln!(out, "position {} of {}", frame.in_ptr, frame.input.len())?;
+                if frame.in_ptr < frame.input.len() {
+                    let rest = &frame.input.as_bytes()[frame.in_ptr..];
+                    let line = rest.split(|x| *x == b'\n').next().unwrap_or(&[]);
+                    writeln!(out, "next line: {:?}", Value(line.to_vec()))?;
+                }
+                Ok(())
+            },
+            _ => {
+                let n = if frame.depth == 0 {
+                    self.debug.vars.iter().position(|x| x == what)
+                } else {
+                    None
+                };
+                let n = n.or_else(|| what.strip_prefix('v').and_then(|x| x.parse().ok()));
+                match n.and_then(|x| frame.vars.get(x)) {
+                    Some(s) => writeln!(out, "{:?}", s),
+                    None => writeln!(out, "Unknown variable '{}'", what),
+                }
+            },
+        }
+    }
+
+    fn backtrace<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        for (i, f) in self.exec.frames.iter().enumerate().rev() {
+            write!(out, "#{} {} at 0x{:04x}", i, if f.name.is_empty() { "<script>" } else { &f.name[..] }, f.count)?;
+            match self.source_line(f) {
+                Some(l) => writeln!(out, ", line {}", l)?,
+                None => writeln!(out)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn list<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let current = self.exec.frames.first().and_then(|f| self.source_line(f));
+        let centre = current.unwrap_or(1);
+        let start = centre.saturating_sub(5).max(1);
+        let end = (centre + 5).min(self.source.len());
+        for l in start..=end {
+            let mark = if Some(l) == current { "=>" } else { "  " };
+            writeln!(out, "{} {:>4} {}", mark, l, self.source[l-1])?;
+        }
+        Ok(())
+    }
+}
+
+fn frame_name(frame: &Frame) -> String {
+    if frame.name.is_empty() {
+        String::new()
+    } else {
+        format!("{}{} ", "  ".repeat(frame.depth), frame.name)
+    }
+}
+
+fn describe_breakpoint<W: Write>(bp: &Breakpoint, out: &mut W) -> io::Result<()> {
+    match bp {
+        Breakpoint::Line(l, off) => writeln!(out, "at line {} (0x{:04x})", l, off),
+        Breakpoint::Offset(off) => writeln!(out, "at 0x{:04x}", off),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCRIPT: &str = "%argmin=0,argmax=0\nvar x\nvar y\nload x from _stdin\napply upper<> to x\nload y from x\nwrite y to _stdout\n";
+
+    fn session(commands: &str) -> String {
+        let mut dbg = Debugger::new(SCRIPT, "abc", &[]).unwrap();
+        let mut out: Vec<u8> = Vec::new();
+        dbg.run(commands.as_bytes(), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn stepping_shows_writes_and_source_lines() {
+        let out = session("step\nstep\nprint x\nq\n");
+        assert!(out.starts_with("0x0010: load"), "{}", out);
+        assert!(out.contains("(line 4: load x from _stdin)"), "{}", out);
+        assert!(out.contains("  x = \"abc\"\n"), "{}", out);
+        assert!(out.contains("(line 5: apply upper<> to x)"), "{}", out);
+        assert!(out.contains("  x = \"ABC\"\n"), "{}", out);
+        assert!(out.contains("(line 6: load y from x)"), "{}", out);
+        assert!(out.ends_with("(cdb) \"ABC\"\n(cdb) "), "{}", out);
+    }
+
+    #[test]
+    fn continue_stops_at_line_breakpoints() {
+        let out = session("break 6\nbreakpoints\ncontinue\nprint vars\ncontinue\ncontinue\n");
+        assert!(out.contains("Breakpoint 1 at line 6 (0x"), "{}", out);
+        assert!(out.contains("Breakpoint 1\n"), "{}", out);
+        assert!(out.contains("x = \"ABC\"\ny = \"\"\n"), "{}", out);
+        assert!(out.contains("Method finished. Output:\nABC\n"), "{}", out);
+        assert!(out.contains("The method is not running."), "{}", out);
+    }
+
+    #[test]
+    fn deleted_breakpoints_are_skipped() {
+        let out = session("break 5\ndelete 1\ndelete 1\nbreakpoints\ncontinue\n");
+        assert!(out.contains("No breakpoint number 1"), "{}", out);
+        assert!(out.contains("No breakpoints."), "{}", out);
+        assert!(!out.contains("Breakpoint 1\n"), "{}", out);
+        assert!(out.contains("Method finished."), "{}", out);
+    }
+
+    #[test]
+    fn bad_commands_are_reported() {
+        let out = session("break nine\nbreak 99\nprint z\njump\n");
+        assert!(out.contains("Invalid line 'nine'"));
+        assert!(out.contains("No code at or after line 99"));
+        assert!(out.contains("Unknown variable 'z'"));
+        assert!(out.contains("Unknown command 'jump'"));
+    }
+}
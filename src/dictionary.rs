@@ -0,0 +1,812 @@
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::fmt;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde_json::{json, Map};
+use sha2::{Digest, Sha256};
+use crate::*;
+
+// A dictionary is a directory of compiled methods, named by their file stems, and an index
+// recording where each came from. Methods copied into the directory by hand are still
+// found; they just have no index entry until they are reinstalled.
+// Methods are looked up along a search path of dictionaries, ending with ~/.ciplang/methods.
+
+const INDEX_FILE: &str = "index.json";
+
+// A method's name: namespaces, a base name and a version, as in team/vig@2.
+// In a dictionary it is the file team/vig@2.cpth.
+#[derive(Debug,Clone,PartialEq)]
+pub struct MethodName {
+    pub namespace: Vec<String>,
+    pub name: String,
+    pub version: Option<String>,
+}
+
+impl MethodName {
+    pub fn parse(st: &str) -> Result<MethodName, CError> {
+        let invalid = || CError::from(format!("Error: invalid method name '{}'", st));
+        let (path, version) = match st.split_once('@') {
+            Some((a, b)) => (a, Some(b.to_string())),
+            None => (st, None),
+        };
+        let word = |x: &str, extra: &[char]| !x.is_empty() && !x.starts_with('.')
+            && x.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-' || extra.contains(&c));
+        if version.as_ref().is_some_and(|x| !word(x, &['.'])) {
+            return Err(invalid());
+        }
+        let mut parts: Vec<String> = path.split('/').map(|x| x.to_string()).collect();
+        if !parts.iter().all(|x| word(x, &[])) {
+            return Err(invalid());
+        }
+        let name = parts.pop().unwrap();
+        Ok(MethodName { namespace: parts, name, version })
+    }
+
+    pub fn with_version(&self, version: Option<String>) -> MethodName {
+        MethodName { version, ..self.clone() }
+    }
+
+    fn file_name(&self) -> String {
+        match &self.version {
+            Some(v) => format!("{}@{}.cpth", self.name, v),
+            None => format!("{}.cpth", self.name),
+        }
+    }
+
+    // where the method is kept, relative to a dictionary
+    pub fn relative_path(&self) -> PathBuf {
+        let mut path: PathBuf = self.namespace.iter().collect();
+        path.push(self.file_name());
+        path
+    }
+
+    // finds the method in a dictionary directory. Without a version, a method installed
+    // without one is used if there is one, and otherwise the highest version
+    pub fn resolve(&self, dir: &Path) -> Option<(MethodName, PathBuf)> {
+        let exact = dir.join(self.relative_path());
+        if exact.is_file() {
+            return Some((self.clone(), exact));
+        }
+        if self.version.is_some() {
+            return None;
+        }
+        let parent: PathBuf = dir.join(self.namespace.iter().collect::<PathBuf>());
+        let prefix = format!("{}@", self.name);
+        fs::read_dir(&parent).ok()?.filter_map(|x| {
+            let file = x.ok()?.file_name().into_string().ok()?;
+            let version = file.strip_suffix(".cpth")?.strip_prefix(&prefix)?.to_string();
+            Some(version)
+        }).filter(|x| MethodName::parse(&format!("{}@{}", self.name, x)).is_ok())
+            .max_by(|a, b| compare_versions(a, b))
+            .map(|v| {
+                let name = self.with_version(Some(v));
+                let path = dir.join(name.relative_path());
+                (name, path)
+            })
+    }
+}
+
+impl fmt::Display for MethodName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for x in self.namespace.iter() {
+            write!(f, "{}/", x)?;
+        }
+        write!(f, "{}", self.name)?;
+        if let Some(v) = &self.version {
+            write!(f, "@{}", v)?;
+        }
+        Ok(())
+    }
+}
+
+// orders versions by their dot-separated parts, comparing the leading digits of each part
+// numerically. A pre-release, such as 1.0-rc1, comes before the release it leads up to
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let (a, a_pre) = match a.split_once('-') {
+        Some((x, y)) => (x, Some(y)),
+        None => (a, None),
+    };
+    let (b, b_pre) = match b.split_once('-') {
+        Some((x, y)) => (x, Some(y)),
+        None => (b, None),
+    };
+    compare_parts(a, b).then_with(|| match (a_pre, b_pre) {
+        (None, None) => Ordering::Equal,
+        (None, Some(_)) => Ordering::Greater,
+        (Some(_), None) => Ordering::Less,
+        (Some(x), Some(y)) => compare_parts(x, y),
+    })
+}
+
+fn compare_parts(a: &str, b: &str) -> Ordering {
+    let mut x = a.split('.');
+    let mut y = b.split('.');
+    loop {
+        let order = match (x.next(), y.next()) {
+            (None, None) => {return Ordering::Equal;},
+            (None, Some(_)) => Ordering::Less,
+            (Some(_), None) => Ordering::Greater,
+            (Some(p), Some(q)) => {
+                //the leading digits of each part, and the rest of it
+                let (p_num, p_rest) = p.split_at(p.find(|c: char| !c.is_ascii_digit()).unwrap_or(p.len()));
+                let (q_num, q_rest) = q.split_at(q.find(|c: char| !c.is_ascii_digit()).unwrap_or(q.len()));
+                match (p_num.parse::<u64>(), q_num.parse::<u64>()) {
+                    (Ok(m), Ok(n)) => m.cmp(&n).then_with(|| p_rest.cmp(q_rest)),
+                    _ => p.cmp(q),
+                }
+            },
+        };
+        if order != Ordering::Equal {
+            return order;
+        }
+    }
+}
+
+#[derive(Debug,Clone,Default,PartialEq)]
+pub struct DictEntry {
+    pub name: String, //including the version, if it has one
+    pub path: PathBuf,
+    pub hash: String, //sha-256 of the method file, in hex
+    pub installed: Option<u64>, //seconds since the unix epoch
+    pub source: Option<String>, //the script it was compiled from
+    pub meta: Metadata,
+    pub indexed: bool,
+    pub modified: bool, //the file no longer matches the hash in the index
+}
+
+pub struct Dictionary {
+    dir: PathBuf,
+    index: Map<String, serde_json::Value>,
+}
+
+impl Dictionary {
+    // the dictionary in the home directory
+    pub fn open() -> Result<Dictionary, CError> {
+        match dictionary_dir() {
+            Some(s) => Dictionary::at(s),
+            None => Err(CError::from_slice("Error: could not find the home directory")),
+        }
+    }
+
+    pub fn at(dir: PathBuf) -> Result<Dictionary, CError> {
+        let index = match fs::read_to_string(dir.join(INDEX_FILE)) {
+            Ok(s) => match serde_json::from_str::<serde_json::Value>(&s) {
+                Ok(serde_json::Value::Object(m)) => m,
+                _ => {return Err(CError::from(format!("Error: {} is not a valid index", dir.join(INDEX_FILE).display())));},
+            },
+            Err(_) => Map::new(),
+        };
+        Ok(Dictionary { dir, index })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    // the method a name refers to, and its file
+    pub fn resolve(&self, name: &str) -> Option<(MethodName, PathBuf)> {
+        MethodName::parse(name).ok()?.resolve(&self.dir)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.resolve(name).is_some()
+    }
+
+    // names of the methods, with their namespaces and versions, sorted
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        let mut pending: Vec<PathBuf> = vec![PathBuf::new()];
+        while let Some(sub) = pending.pop() {
+            let entries = match fs::read_dir(self.dir.join(&sub)) {
+                Ok(s) => s,
+                Err(_) => {continue;},
+            };
+            for x in entries.filter_map(|x| x.ok()) {
+                let file = match x.file_name().into_string() {
+                    Ok(s) => s,
+                    Err(_) => {continue;},
+                };
+                if x.path().is_dir() {
+                    pending.push(sub.join(file));
+                } else if let Some(stem) = file.strip_suffix(".cpth") {
+                    let name = sub.join(stem).to_string_lossy().replace(std::path::MAIN_SEPARATOR, "/");
+                    if MethodName::parse(&name).is_ok() {
+                        names.push(name);
+                    }
+                }
+            }
+        }
+        names.sort();
+        names
+    }
+
+    pub fn load(&self, name: &str) -> Result<Vec<u8>, CError> {
+        match self.resolve(name) {
+            Some((_, path)) => Ok(fs::read(path)?),
+            None => Err(CError::from(format!("Error: no method named '{}' in the dictionary", name))),
+        }
+    }
+
+    // adds a method, replacing any with the same name and version. A version in the name
+    // is recorded in the method's metadata; otherwise the metadata's version, if any, is
+    // added to the name
+    pub fn install(&mut self, name: &str, method: &[u8], source: Option<&Path>) -> Result<DictEntry, CError> {
+        let mut name = MethodName::parse(name)?;
+        let mut meta = read_metadata(method)?;
+        let mut method = method.to_vec();
+        match (&name.version, &meta.version) {
+            (Some(a), Some(b)) if a != b => {
+                return Err(CError::from(format!("Error: the method's version is {}, not {}", b, a)));
+            },
+            (Some(a), None) => {
+                meta.version = Some(a.clone());
+                method = write_metadata(&method, &meta)?;
+            },
+            (None, Some(b)) => {
+                name = name.with_version(Some(b.clone()));
+                MethodName::parse(&name.to_string())?;
+            },
+            _ => {},
+        }
+        let path = self.dir.join(name.relative_path());
+        fs::create_dir_all(path.parent().unwrap_or(&self.dir))?;
+        fs::write(&path, &method)?;
+        let installed = SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or(0);
+        //sources are recorded as absolute paths, so they can be found from anywhere
+        let source = source.map(|x| fs::canonicalize(x).unwrap_or(x.to_path_buf()).display().to_string());
+        self.index.insert(name.to_string(), json!({
+            "hash": hash(&method),
+            "installed": installed,
+            "source": source,
+        }));
+        self.save()?;
+        self.describe(&name.to_string())
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<(), CError> {
+        let (name, path) = match self.resolve(name) {
+            Some(s) => s,
+            None => {return Err(CError::from(format!("Error: no method named '{}' in the dictionary", name)));},
+        };
+        fs::remove_file(&path)?;
+        //namespaces that are now empty are removed too
+        for x in path.ancestors().skip(1).take(name.namespace.len()) {
+            if fs::remove_dir(x).is_err() {
+                break;
+            }
+        }
+        if self.index.remove(&name.to_string()).is_some() {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    // renames a method. Its version is kept unless the new name gives one
+    pub fn rename(&mut self, old: &str, new: &str) -> Result<(), CError> {
+        let (old, path) = match self.resolve(old) {
+            Some(s) => s,
+            None => {return Err(CError::from(format!("Error: no method named '{}' in the dictionary", old)));},
+        };
+        let mut new = MethodName::parse(new)?;
+        if new.version.is_none() {
+            new = new.with_version(old.version.clone());
+        }
+        if new.version != old.version {
+            return Err(CError::from_slice("Error: a method's version can't be changed by renaming it"));
+        }
+        let target = self.dir.join(new.relative_path());
+        if target.exists() {
+            return Err(CError::from(format!("Error: there is already a method named '{}'", new)));
+        }
+        fs::create_dir_all(target.parent().unwrap_or(&self.dir))?;
+        fs::rename(&path, &target)?;
+        if let Some(e) = self.index.remove(&old.to_string()) {
+            self.index.insert(new.to_string(), e);
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    // what is known about a method: its index entry and the metadata in its file
+    pub fn describe(&self, name: &str) -> Result<DictEntry, CError> {
+        let (name, path) = match self.resolve(name) {
+            Some(s) => s,
+            None => {return Err(CError::from(format!("Error: no method named '{}' in the dictionary", name)));},
+        };
+        let method = fs::read(&path)?;
+        let mut entry = DictEntry {
+            name: name.to_string(),
+            path,
+            hash: hash(&method),
+            meta: read_metadata(&method)?,
+            ..DictEntry::default()
+        };
+        if let Some(e) = self.index.get(&entry.name) {
+            entry.indexed = true;
+            entry.installed = e["installed"].as_u64();
+            entry.source = e["source"].as_str().map(|x| x.to_string());
+            entry.modified = e["hash"].as_str() != Some(&entry.hash[..]);
+        }
+        Ok(entry)
+    }
+
+    fn save(&self) -> Result<(), CError> {
+        let text = serde_json::to_string_pretty(&self.index).unwrap_or_default();
+        fs::write(self.dir.join(INDEX_FILE), text + "\n")?;
+        Ok(())
+    }
+}
+
+// where a directory on the search path came from
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum PathSource {
+    Flag,
+    Env,
+    Project,
+    Config,
+    Home,
+}
+
+impl PathSource {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PathSource::Flag => "--dict",
+            PathSource::Env => "CIPLANG_PATH",
+            PathSource::Project => "project",
+            PathSource::Config => "config",
+            PathSource::Home => "home",
+        }
+    }
+}
+
+#[derive(Debug,Default)]
+pub struct SearchPath {
+    pub dirs: Vec<(PathBuf, PathSource)>,
+    warned: RefCell<HashSet<String>>, //names already reported as shadowed
+}
+
+impl SearchPath {
+    // the search path in lookup order: the given directories (from --dict), CIPLANG_PATH,
+    // the nearest project .ciplang/methods, the config file's path, then ~/.ciplang/methods
+    pub fn new(flags: &[PathBuf]) -> Result<SearchPath, CError> {
+        let mut dirs: Vec<(PathBuf, PathSource)> = flags.iter().map(|x| (x.clone(), PathSource::Flag)).collect();
+        if let Some(s) = env::var_os("CIPLANG_PATH") {
+            dirs.extend(env::split_paths(&s).filter(|x| !x.as_os_str().is_empty()).map(|x| (x, PathSource::Env)));
+        }
+        if let Some(s) = project_dir() {
+            dirs.push((s, PathSource::Project));
+        }
+        dirs.extend(config_path()?.into_iter().map(|x| (x, PathSource::Config)));
+        if let Some(s) = dictionary_dir() {
+            dirs.push((s, PathSource::Home));
+        }
+        //a directory listed twice is searched the first time
+        let mut seen: HashSet<PathBuf> = HashSet::new();
+        dirs.retain(|(x, _)| seen.insert(fs::canonicalize(x).unwrap_or(x.clone())));
+        Ok(SearchPath { dirs, warned: RefCell::new(HashSet::new()) })
+    }
+
+    pub fn from_env() -> Result<SearchPath, CError> {
+        SearchPath::new(&[])
+    }
+
+    // the dictionaries holding a method, in lookup order, with the file each would use.
+    // The first is the one used
+    pub fn locate(&self, name: &str) -> Vec<(PathBuf, PathBuf)> {
+        let name = match MethodName::parse(name) {
+            Ok(s) => s,
+            Err(_) => {return Vec::new();},
+        };
+        self.dirs.iter().filter_map(|(x, _)| Some((x.clone(), name.resolve(x)?.1))).collect()
+    }
+
+    // the method file used for a name. Warns (once per name) if it shadows others
+    pub fn find(&self, name: &str) -> Option<PathBuf> {
+        let found = self.locate(name);
+        if found.len() > 1 && self.warned.borrow_mut().insert(name.to_string()) {
+            let others: Vec<String> = found[1..].iter().map(|x| x.0.display().to_string()).collect();
+            eprintln!("warning: method '{}' in {} shadows the one in {}", name, found[0].0.display(), others.join(", "));
+        }
+        found.into_iter().next().map(|x| x.1)
+    }
+
+    pub fn load(&self, name: &str) -> Result<Vec<u8>, CError> {
+        match self.find(name) {
+            Some(s) => Ok(fs::read(s)?),
+            None => Err(CError::from(format!("Error: no method named '{}' in the dictionary", name))),
+        }
+    }
+
+    // loads the method run for a transform that isn't built in
+    pub fn load_transform(&self, name: &str) -> Result<Vec<u8>, CError> {
+        match self.find(name) {
+            Some(s) => Ok(fs::read(s)?),
+            None => Err(CError::from(format!("Error: unknown transform '{}'", name))),
+        }
+    }
+
+    // names of the methods in every dictionary, sorted
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        for (x, _) in self.dirs.iter() {
+            if let Ok(d) = Dictionary::at(x.clone()) {
+                names.extend(d.names());
+            }
+        }
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    // the dictionary methods are installed into: the first --dict directory, or ~/.ciplang/methods
+    pub fn primary(&self) -> Result<Dictionary, CError> {
+        match self.dirs.iter().find(|x| x.1 == PathSource::Flag || x.1 == PathSource::Home) {
+            Some((s, _)) => Dictionary::at(s.clone()),
+            None => Err(CError::from_slice("Error: could not find the home directory")),
+        }
+    }
+}
+
+// a method and the dictionary methods it uses as transforms, and theirs in turn
+#[derive(Debug,Clone,PartialEq)]
+pub struct DepTree {
+    pub name: String,
+    pub status: DepStatus,
+    pub uses: Vec<DepTree>,
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum DepStatus {
+    Found(PathBuf),
+    Local, //the method the tree was built for, when it isn't in a dictionary
+    Missing,
+    Cycle, //the method uses itself, through the methods above it
+    Invalid(String), //the method couldn't be read
+}
+
+impl DepTree {
+    // the problems in the tree, each as the chain of methods leading to it
+    pub fn problems(&self) -> Vec<(Vec<String>, DepStatus)> {
+        let mut out = Vec::new();
+        self.collect_problems(&mut Vec::new(), &mut out);
+        out
+    }
+
+    fn collect_problems(&self, chain: &mut Vec<String>, out: &mut Vec<(Vec<String>, DepStatus)>) {
+        chain.push(self.name.clone());
+        match &self.status {
+            DepStatus::Found(_) | DepStatus::Local => {
+                for x in self.uses.iter() {
+                    x.collect_problems(chain, out);
+                }
+            },
+            s => {out.push((chain.clone(), s.clone()));},
+        }
+        chain.pop();
+    }
+}
+
+// the dictionary methods a method uses: those recorded by the compiler, or for methods
+// compiled before they were recorded, those found in its instructions
+pub fn method_dependencies(method: &[u8]) -> Result<Vec<String>, CError> {
+    let meta = read_metadata(method)?;
+    if !meta.depends.is_empty() {
+        return Ok(meta.depends);
+    }
+    crate::interpret::external_transforms(method)
+}
+
+impl SearchPath {
+    // the dependency tree of a method, looking up what it uses along the search path.
+    // path is where the method itself is, if it's in a dictionary
+    pub fn dependencies(&self, name: &str, method: &[u8], path: Option<PathBuf>) -> DepTree {
+        let status = match &path {
+            Some(s) => DepStatus::Found(s.clone()),
+            None => DepStatus::Local,
+        };
+        let mut stack: Vec<PathBuf> = path.into_iter().collect();
+        let uses = self.dependency_list(method, &mut stack);
+        let uses = match uses {
+            Ok(s) => s,
+            Err(e) => {return DepTree { name: name.to_string(), status: DepStatus::Invalid(e.to_string()), uses: Vec::new() };},
+        };
+        DepTree { name: name.to_string(), status, uses }
+    }
+
+    fn dependency_list(&self, method: &[u8], stack: &mut Vec<PathBuf>) -> Result<Vec<DepTree>, CError> {
+        let mut out = Vec::new();
+        for name in method_dependencies(method)? {
+            let path = match self.locate(&name).into_iter().next() {
+                Some((_, s)) => s,
+                None => {
+                    out.push(DepTree { name, status: DepStatus::Missing, uses: Vec::new() });
+                    continue;
+                },
+            };
+            if stack.contains(&path) {
+                out.push(DepTree { name, status: DepStatus::Cycle, uses: Vec::new() });
+                continue;
+            }
+            stack.push(path.clone());
+            let uses = fs::read(&path).map_err(CError::from).and_then(|x| self.dependency_list(&x, stack));
+            stack.pop();
+            out.push(match uses {
+                Ok(s) => DepTree { name, status: DepStatus::Found(path), uses: s },
+                Err(e) => DepTree { name, status: DepStatus::Invalid(e.to_string()), uses: Vec::new() },
+            });
+        }
+        Ok(out)
+    }
+}
+
+// the nearest .ciplang/methods in the current directory or one of its parents,
+// other than the one in the home directory
+fn project_dir() -> Option<PathBuf> {
+    let home = dirs::home_dir().map(|x| x.join(".ciplang"));
+    let cwd = env::current_dir().ok()?;
+    cwd.ancestors().map(|x| x.join(".ciplang")).find(|x| Some(x) != home.as_ref() && x.join("methods").is_dir())
+        .map(|x| x.join("methods"))
+}
+
+// the directories listed by `path = [...]` in $XDG_CONFIG_HOME/ciplang/config.toml
+// (~/.config/ciplang/config.toml by default). `~/` means the home directory, and other
+// relative paths are relative to the config file
+fn config_path() -> Result<Vec<PathBuf>, CError> {
+    let home = dirs::home_dir();
+    let base = match env::var_os("XDG_CONFIG_HOME").filter(|x| !x.is_empty()) {
+        Some(s) => PathBuf::from(s),
+        None => match &home {
+            Some(s) => s.join(".config"),
+            None => {return Ok(Vec::new());},
+        },
+    };
+    let file = base.join("ciplang/config.toml");
+    let text = match fs::read_to_string(&file) {
+        Ok(s) => s,
+        Err(_) => {return Ok(Vec::new());},
+    };
+    let config = match text.parse::<toml::Table>() {
+        Ok(s) => s,
+        Err(e) => {return Err(CError::from(format!("Error: {}: {}", file.display(), e.message())));},
+    };
+    let list = match config.get("path") {
+        Some(toml::Value::Array(s)) => s,
+        Some(_) => {return Err(CError::from(format!("Error: {}: path must be a list of directories", file.display())));},
+        None => {return Ok(Vec::new());},
+    };
+    let mut dirs: Vec<PathBuf> = Vec::new();
+    for x in list {
+        let s = match x.as_str() {
+            Some(s) => s,
+            None => {return Err(CError::from(format!("Error: {}: path must be a list of directories", file.display())));},
+        };
+        dirs.push(match (s.strip_prefix("~/"), &home) {
+            (Some(rest), Some(h)) => h.join(rest),
+            _ => base.join("ciplang").join(s),
+        });
+    }
+    Ok(dirs)
+}
+
+pub fn hash(method: &[u8]) -> String {
+    Sha256::digest(method).iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+// a time in seconds since the unix epoch as "YYYY-MM-DD HH:MM:SS UTC"
+pub fn format_time(secs: u64) -> String {
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+    //converts days since 1970-01-01 to a civil date
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, rem / 3600, rem / 60 % 60, rem % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile::convert_to_method;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cipherlang-dict-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn method(name: &str) -> Vec<u8> {
+        convert_to_method(&format!("%argmin=0,argmax=0,name={},description=\"test method\"\nvar x\n", name)).unwrap()
+    }
+
+    #[test]
+    fn install_records_the_method_in_the_index() {
+        let dir = scratch("install");
+        let mut dict = Dictionary::at(dir.clone()).unwrap();
+        let m = method("upper");
+        let entry = dict.install("upper", &m, None).unwrap();
+        assert_eq!(entry.hash, hash(&m));
+        assert!(entry.indexed && !entry.modified && entry.installed.is_some());
+        assert_eq!(entry.meta.description.as_deref(), Some("test method"));
+        assert_eq!(dict.load("upper").unwrap(), m);
+
+        //a fresh Dictionary reads the same index back
+        let index: serde_json::Value = serde_json::from_str(&fs::read_to_string(dir.join(INDEX_FILE)).unwrap()).unwrap();
+        assert_eq!(index["upper"]["hash"], entry.hash);
+        assert_eq!(Dictionary::at(dir.clone()).unwrap().describe("upper").unwrap(), entry);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn remove_and_rename_keep_the_index_in_step() {
+        let dir = scratch("rename");
+        let mut dict = Dictionary::at(dir.clone()).unwrap();
+        dict.install("a", &method("a"), None).unwrap();
+        dict.install("b", &method("b"), None).unwrap();
+        dict.rename("a", "c").unwrap();
+        assert_eq!(dict.names(), vec!["b", "c"]);
+        assert!(dict.describe("c").unwrap().indexed);
+        assert!(dict.rename("c", "b").unwrap_err().to_string().contains("already a method named 'b'"));
+        assert!(dict.rename("a", "d").is_err());
+        dict.remove("b").unwrap();
+        assert!(dict.remove("b").is_err());
+        let index = fs::read_to_string(dir.join(INDEX_FILE)).unwrap();
+        assert!(!index.contains("\"a\"") && !index.contains("\"b\"") && index.contains("\"c\""), "{}", index);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hand_made_changes_are_noticed() {
+        let dir = scratch("modified");
+        let mut dict = Dictionary::at(dir.clone()).unwrap();
+        dict.install("a", &method("a"), None).unwrap();
+        fs::write(dir.join("a.cpth"), method("changed")).unwrap();
+        fs::write(dir.join("copied.cpth"), method("copied")).unwrap();
+        assert!(dict.describe("a").unwrap().modified);
+        let copied = dict.describe("copied").unwrap();
+        assert!(!copied.indexed && copied.installed.is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn names_must_be_file_names() {
+        let mut dict = Dictionary::at(scratch("names")).unwrap();
+        for x in ["", ".hidden", "a\\b", "../a", "a/.b", "a//b", "a b", "a@", "a@1@2", "a@1/2"] {
+            assert!(dict.install(x, &method("x"), None).is_err(), "{:?}", x);
+        }
+    }
+
+    #[test]
+    fn method_names_have_namespaces_and_versions() {
+        let n = MethodName::parse("team/ciphers/vig@1.10.0-rc1").unwrap();
+        assert_eq!((&n.namespace[..], &n.name[..]), (&["team".to_string(), "ciphers".to_string()][..], "vig"));
+        assert_eq!(n.version.as_deref(), Some("1.10.0-rc1"));
+        assert_eq!(n.to_string(), "team/ciphers/vig@1.10.0-rc1");
+        assert_eq!(n.relative_path(), PathBuf::from("team/ciphers/vig@1.10.0-rc1.cpth"));
+        assert_eq!(n.with_version(None).relative_path(), PathBuf::from("team/ciphers/vig.cpth"));
+        assert_eq!(MethodName::parse("caesar").unwrap().to_string(), "caesar");
+    }
+
+    #[test]
+    fn versions_compare_part_by_part() {
+        assert_eq!(compare_versions("1.10", "1.9"), Ordering::Greater);
+        assert_eq!(compare_versions("2", "10"), Ordering::Less);
+        assert_eq!(compare_versions("1.0", "1"), Ordering::Greater);
+        assert_eq!(compare_versions("1.2.3", "1.2.3"), Ordering::Equal);
+        assert_eq!(compare_versions("1.b", "1.a"), Ordering::Greater);
+        assert_eq!(compare_versions("1.10a", "1.9b"), Ordering::Greater);
+        assert_eq!(compare_versions("1.0-rc1", "1.0"), Ordering::Less);
+        assert_eq!(compare_versions("1.0-rc1", "1.0-rc2"), Ordering::Less);
+        assert_eq!(compare_versions("1.9", "1.10-rc1"), Ordering::Less);
+        assert_eq!(compare_versions("2.0-beta.10", "2.0-beta.9"), Ordering::Greater);
+    }
+
+    #[test]
+    fn resolves_the_highest_version() {
+        let dir = scratch("versions");
+        let mut dict = Dictionary::at(dir.clone()).unwrap();
+        for x in ["team/vig@1", "team/vig@1.9", "team/vig@1.10", "team/vigenere",
+            "team/rot@1.0-rc1", "team/rot@1.0", "team/pre@1.9", "team/pre@1.10-rc1"] {
+            dict.install(x, &method("vig"), None).unwrap();
+        }
+        let resolved = |s: &str| dict.resolve(s).map(|x| x.0.to_string());
+        assert_eq!(resolved("team/vig"), Some("team/vig@1.10".to_string()));
+        assert_eq!(resolved("team/vig@1.9"), Some("team/vig@1.9".to_string()));
+        assert_eq!(resolved("team/vig@2"), None);
+        assert_eq!(resolved("team/vigenere"), Some("team/vigenere".to_string()));
+        assert_eq!(dict.describe("team/vig@1").unwrap().meta.version.as_deref(), Some("1"));
+        //pre-releases come before their release, but after earlier releases
+        assert_eq!(resolved("team/rot"), Some("team/rot@1.0".to_string()));
+        assert_eq!(resolved("team/pre"), Some("team/pre@1.10-rc1".to_string()));
+        //a method installed without a version comes before any versioned one
+        fs::write(dir.join("team/vig.cpth"), method("vig")).unwrap();
+        assert_eq!(resolved("team/vig"), Some("team/vig".to_string()));
+
+        //removing the last method in a namespace removes the namespace
+        for x in dict.names() {
+            dict.remove(&x).unwrap();
+        }
+        assert!(!dir.join("team").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn versions_in_names_and_metadata_agree() {
+        let dir = scratch("metaversion");
+        let mut dict = Dictionary::at(dir.clone()).unwrap();
+        let versioned = convert_to_method("%argmin=0,argmax=0,version=2.1\nvar x\n").unwrap();
+        assert_eq!(dict.install("rot", &versioned, None).unwrap().name, "rot@2.1");
+        assert!(dict.install("rot@3", &versioned, None).unwrap_err().to_string().contains("version is 2.1, not 3"));
+        assert!(dict.rename("rot", "rot@4").unwrap_err().to_string().contains("can't be changed"));
+        dict.rename("rot", "team/rot").unwrap();
+        assert_eq!(dict.names(), vec!["team/rot@2.1"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn times_are_shown_in_utc() {
+        assert_eq!(format_time(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_time(951782400), "2000-02-29 00:00:00 UTC");
+        assert_eq!(format_time(1700000000), "2023-11-14 22:13:20 UTC");
+    }
+
+    #[test]
+    fn the_first_dictionary_with_a_method_wins() {
+        let dir = scratch("search");
+        let search = SearchPath {
+            dirs: ["first", "second", "third"].iter().map(|x| (dir.join(x), PathSource::Flag)).collect(),
+            ..SearchPath::default()
+        };
+        for x in ["second", "third"] {
+            Dictionary::at(dir.join(x)).unwrap().install("m", &method(x), None).unwrap();
+        }
+        Dictionary::at(dir.join("third")).unwrap().install("only", &method("only"), None).unwrap();
+        let found: Vec<PathBuf> = search.locate("m").into_iter().map(|x| x.0).collect();
+        assert_eq!(found, vec![dir.join("second"), dir.join("third")]);
+        assert_eq!(search.find("m"), Some(dir.join("second").join("m.cpth")));
+        assert!(search.warned.borrow().contains("m"));
+        assert_eq!(search.load("m").unwrap(), method("second"));
+        assert_eq!(search.find("only"), Some(dir.join("third").join("only.cpth")));
+        assert!(!search.warned.borrow().contains("only"));
+        assert_eq!(search.names(), vec!["m", "only"]);
+        assert!(search.load_transform("none").unwrap_err().to_string().contains("unknown transform 'none'"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dependency_trees_show_missing_methods_and_cycles() {
+        let dir = scratch("deps");
+        let mut dict = Dictionary::at(dir.clone()).unwrap();
+        let uses = |t: &[&str]| {
+            let body: String = t.iter().map(|x| format!("apply {}<> to x\n", x)).collect();
+            convert_to_method(&format!("%argmin=0,argmax=0\nvar x\n{}", body)).unwrap()
+        };
+        dict.install("a", &uses(&["b", "upper"]), None).unwrap();
+        dict.install("b", &uses(&["a", "gone"]), None).unwrap();
+        dict.install("leaf", &uses(&[]), None).unwrap();
+        assert_eq!(read_metadata(&dict.load("b").unwrap()).unwrap().depends, vec!["a", "gone"]);
+        let mut search = SearchPath::default();
+        search.dirs.push((dir.clone(), PathSource::Flag));
+
+        let tree = search.dependencies("top", &uses(&["a", "leaf"]), None);
+        assert_eq!(tree.status, DepStatus::Local);
+        let names: Vec<&str> = tree.uses.iter().map(|x| &x.name[..]).collect();
+        assert_eq!(names, vec!["a", "leaf"]);
+        let chains: Vec<(String, DepStatus)> = tree.problems().into_iter().map(|(c, s)| (c.join(" -> "), s)).collect();
+        assert_eq!(chains, vec![
+            ("top -> a -> b -> a".to_string(), DepStatus::Cycle),
+            ("top -> a -> b -> gone".to_string(), DepStatus::Missing),
+        ]);
+        let leaf = search.dependencies("leaf", &dict.load("leaf").unwrap(), Some(dir.join("leaf.cpth")));
+        assert!(leaf.problems().is_empty() && leaf.uses.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
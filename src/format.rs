@@ -0,0 +1,240 @@
+use crate::ast::*;
+
+// Canonical formatter for scripts.
+// Statements are separated by single spaces, argument lists by ", ", and runs of
+// blank lines are collapsed. Directives are moved to the top of the script in a
+// fixed order, one key per line (argmin and argmax share a line).
+
+const DIRECTIVE_ORDER: [&str; 8] = ["name", "version", "author", "description", "usage",
+    "argmin", "argmax", "argnames"];
+
+// a directive key or argument declaration, with the comments attached to it
+struct DirectiveItem {
+    rank: usize,
+    line: String,
+    comments: Vec<String>,
+    trailing: bool, //line ends with a comment
+}
+
+pub fn format_script(script: &Script) -> String {
+    let mut header: Vec<String> = Vec::new();
+    let mut directives: Vec<DirectiveItem> = Vec::new();
+    let mut body: Vec<String> = Vec::new();
+
+    //comments directly above a statement belong to it
+    let mut attached: Vec<String> = Vec::new();
+    let mut blank_before = false;
+    let mut prev_end: Option<usize> = None;
+    for st in script.statements.iter() {
+        let gap = matches!(prev_end, Some(n) if st.span.line > n + 1);
+        prev_end = Some(st.span.end_line);
+        if gap {
+            //comments separated from the next statement by a blank line stay in place
+            flush(&mut attached, &mut body, &mut header, blank_before);
+            blank_before = true;
+        }
+        if let StatementKind::Comment(s) = &st.kind {
+            attached.push(format!("#{}", s));
+            continue;
+        }
+        match &st.kind {
+            StatementKind::Directive(entries) => {
+                let mut comments = std::mem::take(&mut attached);
+                directive_items(entries, st, &mut comments, &mut directives);
+            },
+            StatementKind::Arg(_, _) => {
+                directives.push(DirectiveItem {
+                    rank: DIRECTIVE_ORDER.len() + 1,
+                    line: format_statement(st),
+                    comments: std::mem::take(&mut attached),
+                    trailing: st.comment.is_some(),
+                });
+            },
+            _ => {
+                if blank_before && !body.is_empty() {
+                    body.push(String::new());
+                }
+                body.append(&mut attached);
+                body.push(format_statement(st));
+            },
+        }
+        blank_before = false;
+    }
+    flush(&mut attached, &mut body, &mut header, blank_before);
+
+    //stable, so repeated keys and %arg declarations keep their order
+    directives.sort_by_key(|x| x.rank);
+    let mut out: Vec<String> = Vec::new();
+    let sections = [header, merge_arg_counts(directives), body];
+    for x in sections.into_iter().filter(|x| !x.is_empty()) {
+        if !out.is_empty() {
+            out.push(String::new());
+        }
+        out.extend(x);
+    }
+    let mut text = out.join("\n");
+    text.push('\n');
+    text
+}
+
+// comments not attached to a statement go to the header before any statement is seen
+fn flush(attached: &mut Vec<String>, body: &mut Vec<String>, header: &mut Vec<String>, blank_before: bool) {
+    if attached.is_empty() {
+        return;
+    }
+    if body.is_empty() && !blank_before {
+        header.append(attached);
+    } else if body.is_empty() {
+        if !header.is_empty() {
+            header.push(String::new());
+        }
+        header.append(attached);
+    } else {
+        if blank_before {
+            body.push(String::new());
+        }
+        body.append(attached);
+    }
+}
+
+fn directive_items(entries: &[(String, String)], st: &Statement, comments: &mut Vec<String>, out: &mut Vec<DirectiveItem>) {
+    for (i, (k, v)) in entries.iter().enumerate() {
+        let rank = DIRECTIVE_ORDER.iter().position(|x| x == k).unwrap_or(DIRECTIVE_ORDER.len());
+        let mut line = format!("%{}={}", k, directive_value(v));
+        let trailing = i == 0 && st.comment.is_some();
+        if trailing {
+            line += &format!(" #{}", st.comment.as_ref().unwrap());
+        }
+        out.push(DirectiveItem { rank, line, comments: std::mem::take(comments), trailing });
+    }
+}
+
+// joins "%argmin=.." and "%argmax=.." into a single directive line
+fn merge_arg_counts(items: Vec<DirectiveItem>) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    let mut items = items.into_iter().peekable();
+    while let Some(mut x) = items.next() {
+        let argmin = DIRECTIVE_ORDER.iter().position(|x| *x == "argmin").unwrap();
+        if x.rank == argmin && !x.trailing {
+            if let Some(y) = items.next_if(|y| y.rank == argmin + 1 && y.comments.is_empty()) {
+                x.line = format!("{},{}", x.line, &y.line[1..]);
+            }
+        }
+        lines.append(&mut x.comments);
+        lines.push(x.line);
+    }
+    lines
+}
+
+pub fn format_statement(st: &Statement) -> String {
+    let mut line = match &st.kind {
+        StatementKind::Var(x) => format!("var {}", x),
+        StatementKind::Const(x, v) => format!("const {} {}", x, quote(v)),
+        StatementKind::Load(x, e) => format!("load {} from {}", x, format_expr(e)),
+        StatementKind::Write(e, x) => format!("write {} to {}", format_expr(e), x),
+        StatementKind::Apply(t, x) => format!("apply {} to {}", format_call(t), x),
+        StatementKind::Directive(entries) => {
+            let v: Vec<String> = entries.iter().map(|(k, v)| format!("{}={}", k, directive_value(v))).collect();
+            format!("%{}", v.join(","))
+        },
+        StatementKind::Arg(x, Some(d)) => format!("%arg {} = {}", x, quote(d)),
+        StatementKind::Arg(x, None) => format!("%arg {}", x),
+        StatementKind::Comment(s) => {return format!("#{}", s);},
+    };
+    if let Some(c) = &st.comment {
+        line += &format!(" #{}", c);
+    }
+    line
+}
+
+pub fn format_expr(e: &Expr) -> String {
+    match e {
+        Expr::Ident(s, _) => s.clone(),
+        Expr::Str(s, _) => quote(s),
+        Expr::Operation(l, op, r) => format!("{} ${} {}", format_expr(l), op, format_expr(r)),
+    }
+}
+
+pub fn format_call(t: &TransformCall) -> String {
+    let args: Vec<String> = t.args.iter().map(|x| transform_arg(x)).collect();
+    format!("{}<{}>", t.name, args.join(", "))
+}
+
+fn transform_arg(arg: &str) -> String {
+    let bare = |s: &str| !s.is_empty() && s.trim() == s &&
+        !s.chars().any(|c| c.is_whitespace() || c.is_control() || ",<>\"#\\".contains(c));
+    if bare(arg) {
+        return arg.to_string();
+    }
+    //named arguments keep their key outside the quotes
+    if let Some(n) = arg.find('=') {
+        let key = &arg[..n];
+        if bare(key) && !key.contains('=') {
+            return format!("{}={}", key, quote(&arg[n+1..]));
+        }
+    }
+    quote(arg)
+}
+
+fn directive_value(v: &str) -> String {
+    if !v.is_empty() && v.chars().all(|c| c.is_ascii_alphanumeric() || "._-/@+".contains(c)) {
+        v.to_string()
+    } else {
+        quote(v)
+    }
+}
+
+// writes a string constant, escaping quotes, backslashes and control characters
+pub fn quote(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => {out += "\\\"";},
+            '\\' => {out += "\\\\";},
+            '\n' => {out += "\\n";},
+            '\t' => {out += "\\t";},
+            '\r' => {out += "\\r";},
+            c if c.is_control() => {out += &format!("\\u{{{:x}}}", c as u32);},
+            c => {out.push(c);},
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile::convert_to_method;
+
+    const MESSY: &str = "# caesar shift\nvar   x\n%argmax=1,argmin=1\n\n\n\n# read it\nload x   from _stdin   # all of it\napply sub< \"a, b\" ,c> to x\n%name=caesar,description=\"shifts, then substitutes\"\nconst bang \"!\"\nwrite x $cat bang to _stdout\n";
+
+    fn format(script: &str) -> String {
+        format_script(&parse_script(script).unwrap())
+    }
+
+    #[test]
+    fn canonical_form() {
+        let expected = "%name=caesar\n%description=\"shifts, then substitutes\"\n%argmin=1,argmax=1\n\n\
+            # caesar shift\nvar x\n\n# read it\nload x from _stdin # all of it\napply sub<\"a, b\", c> to x\n\
+            const bang \"!\"\nwrite x $cat bang to _stdout\n";
+        assert_eq!(format(MESSY), expected);
+    }
+
+    #[test]
+    fn formatting_is_idempotent_and_keeps_the_method() {
+        let once = format(MESSY);
+        assert_eq!(format(&once), once);
+        assert_eq!(convert_to_method(&once).unwrap(), convert_to_method(MESSY).unwrap());
+    }
+
+    #[test]
+    fn arguments_are_quoted_only_when_needed() {
+        assert_eq!(quote("say \"hi\"\n"), "\"say \\\"hi\\\"\\n\"");
+        let t = match parse_script("apply tag<sep=\"a, b\", x, \"\"> to y").unwrap().statements[0].kind.clone() {
+            StatementKind::Apply(t, _) => t,
+            k => panic!("expected apply, found {:?}", k),
+        };
+        assert_eq!(format_call(&t), "tag<sep=\"a, b\", x, \"\">");
+    }
+}
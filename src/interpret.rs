@@ -1,8 +1,16 @@
-use cipherlang::*;
+use crate::*;
 
 use crate::transform::*;
+use crate::alphabet::Alphabet;
+use crate::trace::*;
+use std::time::{Duration, Instant};
+use std::io::{BufRead, Write};
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use crate::dictionary::SearchPath;
 
-pub fn get_const_offset(method: &Vec<u8>, offset: u16, num: u16) -> Result<u16,CError> {
+pub fn get_const_offset(method: &[u8], offset: u16, num: u16) -> Result<u16,CError> {
     if method[0xb] as u16 <= num {
         return Err(CError::from_slice("Error: out-of-bounds const read"));
     }
@@ -12,259 +20,789 @@ pub fn get_const_offset(method: &Vec<u8>, offset: u16, num: u16) -> Result<u16,C
     )
 }
 
-pub fn get_const(method: &Vec<u8>, offset: u16, num: u16) -> Result<String, CError> {
-    let t = get_const_offset(&method, offset, num)? as usize;
-    let mut end = t;
-    while method[end] != 0 {
-        end += 1;
-    }
+pub fn get_const(method: &[u8], offset: u16, num: u16) -> Result<String, CError> {
+    let t = get_const_offset(method, offset, num)? as usize;
+    //a constant runs to the next NUL; a method without one is corrupt
+    let end = match method.get(t..).and_then(|x| x.iter().position(|&b| b == 0)) {
+        Some(n) => t + n,
+        None => return Err(CError::from_slice("Error: invalid method: unterminated constant")),
+    };
     match String::from_utf8(method[t..end].to_vec()) {
         Ok(s) => Ok(s),
         Err(_) => Err(CError::from_slice("Error: Could not get constant")),
     }
 }
 
-pub fn interpret(method: &Vec<u8>, input: &String, args: &Vec<&str>, depth: usize) -> Result<String, CError> {
-    dbg!(&args);
-    let len = method.len();
-    if len < (16 as usize) {
-        return Err(CError::from(format!("Error: Invalid method file")));
-    }
-    if method[0] != 'C' as u8  ||  method[1] != 'P' as u8 || method[2] != 'T' as u8 || method[3] != 'H' as u8 || method[7] != 0x0 {
-        return Err(CError::from(format!("Error: Invalid header")));
-    }
-    let const_offset: usize = ((method[0xc] as usize) << 8) | (method[0xd] as usize);
-    let argmin = method[0x8];
-    let argmax = method[0x9];
-    let const_wid = method[0xa];
-    let num_consts = method[0xb];
-    let num_vars = method[0xe];
-
-    if args.len() > argmax.into() || args.len() < argmin.into() {
-        return Err(CError::from(format!("Error: incorrect argument number {}: max: {}, min: {}", args.len(), argmax, argmin)));
-    }
-    let mut last_tr = String::new(); //result of last transform
-    let mut last_op = String::new(); //result of last operation
-
-    let mut output = String::new(); //output string
-    let mut vars: Vec<String> = vec![String::new(); num_vars.into()]; //variables
-    let mut count: usize = 0x10; //???
-
-    let mut in_ptr = 0;
-    loop {
-        let inst: Vec<u8> = match method[count] {
-            0 => {
-                method[count..count+5].to_vec()
+// a constant as bytes; constants don't have to be UTF-8
+pub fn get_const_bytes(method: &[u8], offset: u16, num: u16) -> Result<Value, CError> {
+    let t = get_const_offset(method, offset, num)? as usize;
+    match method.get(t..).and_then(|x| x.iter().position(|&b| b == 0)) {
+        Some(n) => Ok(Value(method[t..t+n].to_vec())),
+        None => Err(CError::from_slice("Error: invalid method: unterminated constant")),
+    }
+}
+
+pub fn interpret(method: &[u8], input: &str, args: &[&str], depth: usize) -> Result<String, CError> {
+    Vm::new(method.to_vec(), input.as_bytes(), args, depth)?.run(&mut NullTracer)?.into_text()
+}
+
+// A decoded instruction. Operands are identifier numbers: variables below 0x100,
+// constants from 0x100 and special variables from 0x300.
+#[derive(Debug,Clone,PartialEq)]
+pub enum Instruction {
+    Load { source: u16, target: u16 },
+    Apply { target: u16, transform: u16, args: Vec<u16> }, //args hold a form in the top 6 bits
+    Operation { first: u16, second: u16, operation: u16 },
+}
+
+impl Instruction {
+    // decodes the instruction at offset, returning it and its length in bytes
+    pub fn decode(method: &[u8], offset: usize) -> Result<(Instruction, usize), CError> {
+        let word = |n: usize| ((method[offset+n] as u16) << 8) | method[offset+n+1] as u16;
+        let len = match method.get(offset) {
+            Some(0) => 5,
+            Some(1) if offset + 5 < method.len() => 6 + 2 * method[offset+5] as usize,
+            Some(2) => 7,
+            Some(s) => {return Err(CError::from(format!("Error: unrecognized opcode '0x{:X}'", s)));},
+            None => {return Err(CError::from_slice("Error: instruction is out of bounds"));},
+        };
+        if offset + len > method.len() {
+            return Err(CError::from_slice("Error: instruction is out of bounds"));
+        }
+        let inst = match method[offset] {
+            0 => Instruction::Load { source: word(1), target: word(3) },
+            1 => Instruction::Apply {
+                target: word(1),
+                transform: word(3),
+                args: (0..method[offset+5] as usize).map(|i| word(6 + 2*i)).collect(),
             },
-            1 => {
-                method[count..(count+6 + (2 * method[count+5]) as usize ) ].to_vec()
+            _ => Instruction::Operation { first: word(1), second: word(3), operation: word(5) },
+        };
+        Ok((inst, len))
+    }
+
+    pub fn opcode(&self) -> u8 {
+        match self {
+            Instruction::Load { .. } => 0,
+            Instruction::Apply { .. } => 1,
+            Instruction::Operation { .. } => 2,
+        }
+    }
+
+    pub fn operands(&self) -> Vec<u16> {
+        match self {
+            Instruction::Load { source, target } => vec![*source, *target],
+            Instruction::Apply { target, transform, args } => [&[*target, *transform][..], &args[..]].concat(),
+            Instruction::Operation { first, second, operation } => vec![*first, *second, *operation],
+        }
+    }
+}
+
+// a readable listing of a method: its header, instructions and constants
+pub fn disassemble(method: &[u8]) -> Result<String, CError> {
+    if method.len() < HEADER_LEN || &method[0..4] != b"CPTH" {
+        return Err(CError::from_slice("Error: Invalid method file"));
+    }
+    let const_offset = ((method[0xc] as usize) << 8) | method[0xd] as usize;
+    let consts: Vec<Value> = (0..method[0xb] as u16)
+        .map(|i| get_const_bytes(method, const_offset as u16, i))
+        .collect::<Result<_, _>>()?;
+    let name = |id: u16| match id {
+        0..=0xff => format!("v{}", id),
+        0x100..=0x2ff => match consts.get((id - 0x100) as usize) {
+            Some(v) => format!("{:?}", v),
+            None => format!("c{}", id - 0x100),
+        },
+        0x340..=0x35f => format!("_{}", id - 0x340),
+        0x300..=0x3ff => crate::compile::SPECIAL_VARS.get((id - 0x300) as usize).copied().unwrap_or("special").to_string(),
+        _ => format!("0x{:03x}", id),
+    };
+    //transforms and operations are named by constants
+    let const_text = |id: u16| match consts.get(id.wrapping_sub(0x100) as usize) {
+        Some(v) => v.to_string_lossy(),
+        None => format!("0x{:03x}", id),
+    };
+    let arg = |a: u16| match (a & 0xfc00) >> 10 {
+        0x10 => name(a & 0x3ff),
+        0x00 => (a & 0x3ff).to_string(),
+        0x01 => format!("-{}", a & 0x3ff),
+        _ => format!("0x{:04x}", a),
+    };
+
+    let mut out = format!("arguments: {} to {}\nvariables: {}\nconstants: {}\n\n",
+        method[0x8], method[0x9], method[0xe], consts.len());
+    let mut offset = HEADER_LEN;
+    while offset < const_offset {
+        let (inst, len) = Instruction::decode(&method[..const_offset], offset)?;
+        let text = match &inst {
+            Instruction::Load { source, target } => format!("{} -> {}", name(*source), name(*target)),
+            Instruction::Apply { target, transform, args } => {
+                let args: Vec<String> = args.iter().map(|x| arg(*x)).collect();
+                format!("{}<{}> -> {}", const_text(*transform), args.join(", "), name(*target))
             },
-            2 => {
-                method[count..count+7].to_vec()
+            Instruction::Operation { first, second, operation } => {
+                format!("{} ${} {}", name(*first), const_text(*operation), name(*second))
             },
-            _ => {return Err(CError::from(format!("Error: unrecognized opcode '0x{:X}'", method[count])));},
         };
-        eprintln!("Running inst {:?}", inst);
-
-        //execute the instruction
-        let first: u16 = ((method[count+1] as u16) << 8) | method[count+2] as u16;
-        let second: u16 = ((method[count+3] as u16) << 8) | method[count+4] as u16;
-
-
-        //this is where the instruction logic goes
-        match inst[0] {
-            0 => { //load
-                let tempvar: String;
-                if first < 0x100 { //standard variable
-                    if vars.len() >= first as usize {
-                        tempvar = vars[first as usize].clone();
-                    } else {
-                        return Err(CError::from(format!("Error: out-of-range var read")));
-                    }
-                } else if first < 0x300 { //constant
-                    if first - 0x100 >= num_consts as u16 {
-                        return Err(CError::from(format!("Error: out-of-bounds const read")));
-                    }
-                    let t = get_const_offset(&method, const_offset as u16, first-0x100)? as usize;
-                    let mut end = t;
-                    while method[end] != 0 {
-                        end += 1;
-                    }
-                    tempvar = String::from_utf8(method[t..end].to_vec())?;
-                } else if first < 0x400 { //special var
-                    tempvar = read_special_var(first - 0x300, &last_tr, &last_op, &input, &mut in_ptr, &args)?;
+        out += &format!("0x{:04x}  {:<9} {}\n", offset, op_name(inst.opcode()), text);
+        offset += len;
+    }
+    if !consts.is_empty() {
+        out += "\n";
+    }
+    for (i, c) in consts.iter().enumerate() {
+        out += &format!("c{} = {:?}\n", i, c);
+    }
+    Ok(out)
+}
 
-                } else {
-                    return Err(CError::from(format!("Error: invalid identifier number")));
-                }
-                eprintln!("Writing '{}'",&tempvar);
-
-                //destination
-                if second < 0x100 { //normal variable
-                    if vars.len() <= second as usize {
-                        return Err(CError::from(format!("Error: out-of-bounds variable write")));
-                    } 
-                    vars[second as usize] = tempvar;
-                } else if second < 0x300 {
-                    return Err(CError::from(format!("Error: constant writes are prohibited")));
-                } else if second < 0x400 {
-                    write_special_var(second - 0x300, &tempvar[..], &mut output)?;
-                } else {
-                    return Err(CError::from(format!("Error: invalid identifier number")));
-                }
+// the transforms a method applies that aren't built in, which are looked up in the
+// dictionary when it runs. Sorted, without repeats
+pub fn external_transforms(method: &[u8]) -> Result<Vec<String>, CError> {
+    if method.len() < HEADER_LEN || &method[0..4] != b"CPTH" {
+        return Err(CError::from_slice("Error: Invalid method file"));
+    }
+    let const_offset = ((method[0xc] as usize) << 8) | method[0xd] as usize;
+    let mut names: Vec<String> = Vec::new();
+    let mut offset = HEADER_LEN;
+    while offset < const_offset {
+        let (inst, len) = Instruction::decode(&method[..const_offset], offset)?;
+        if let Instruction::Apply { transform, .. } = inst {
+            let name = get_const_bytes(method, const_offset as u16, transform.wrapping_sub(0x100))?.into_text()?;
+            if !is_builtin_transform(&name) && !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        offset += len;
+    }
+    names.sort();
+    Ok(names)
+}
 
-                //find dest
-            },
-            1 => { //apply
-                //load tempVar with target
-                let tempvar: String;
-                let varnum: usize; //which varnum to write to
-                if first < 0x100 { //variable
-                    if vars.len() >= first as usize {
-                        tempvar = vars[first as usize].clone();
-                        varnum = first as usize;
-                    } else {
-                        return Err(CError::from(format!("Error: out-of-bounds variable read")));
-                    }
-                } else if first < 0x300 {
-                    //const
-                    return Err(CError::from(format!("Error: Cannot transform constant")));
-                } else if first < 0x400 {
-                    //special var
-                    return Err(CError::from(format!("Error: Cannot transform special var")));
-                } else {
-                    return Err(CError::from(format!("Error: out-of-bounds identifier")));
-                }
+// Resource limits for a run. Each limit that is exceeded stops the run with its own error
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct Limits {
+    pub max_instructions: Option<usize>, //across all methods in the run
+    pub max_output: Option<usize>, //bytes written to _stdout by any one method
+    pub max_string: Option<usize>, //bytes in any value
+    pub timeout: Option<Duration>,
+    pub max_depth: usize, //nesting of external transforms
+}
 
-                //load transform name into a variable
-                let transform: String;
-                if second < 0x100 { //variable
-                    return Err(CError::from(format!("Error: cannot use variable for transform name")));
-                } else if second < 0x300 { //constant
-                    if second - 0x100 >= num_consts as u16 {
-                        return Err(CError::from(format!("Error: out-of-bounds const read")));
-                    }
-                    transform = match get_const(&method, const_offset as u16, second - 0x100) {
-                        Ok(s) => s,
-                        Err(_) => {return Err(CError::from(format!("Error: could not read const")));},
-                    };
-                } else if second < 0x400 { //special var
-                    return Err(CError::from(format!("Error: cannot use special var for transform name")));
-                } else {
-                    return Err(CError::from(format!("Error: invalid identifier")));
-                }
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_instructions: None,
+            max_output: None,
+            max_string: None,
+            timeout: None,
+            max_depth: MAX_RECURSION_DEPTH,
+        }
+    }
+}
 
-                //figure out args:
-                let argc = method[count+5] as usize;
-                let mut args: Vec<String> = Vec::new();
-                for i in 0..argc {
-                    let arg: u16 = ((method[count+6 + 2*i] as u16) << 8) |
-                        (method[count+7+2*i] as u16);
-                    let form: u16 = (arg & 0xfc00) >> 10;
-                    let value: u16 = arg & 0x3ff;
-                    if form == 0x10 {
-                        //const argument
-                        let v = get_const(&method, const_offset as u16, value-0x100)?;
-                        args.push(v);
-                    } else if form == 0x00 {
-                        // positive num argument
-                        args.push(value.to_string());
-                    } else if form == 0x01 {
-                        args.push("-".to_owned() + &value.to_string());
-                    } else {
-                        return Err(CError::from(format!("Error: invalid argument type {:X}", form)));
-                    }
+// What runs have used of their limits. Runs that share a budget, such as the stages of a
+// pipeline, share one instruction count, time limit and output limit
+#[derive(Debug,Clone,Copy,Default,PartialEq)]
+pub struct Budget {
+    pub executed: usize, //instructions
+    pub started: Option<Instant>, //when the first run began
+    pub written: usize, //bytes of output
+}
 
-                }
-                eprintln!("Performing transform {} with args: {:?}", &transform, &args);
+impl Budget {
+    pub fn new() -> Budget {
+        Budget::default()
+    }
 
-                
-                //perform transform, write back to specified var
-                // change args from Vec<String> to Vec<&str>
-                let argstr: Vec<&str> = args.iter().map(|x| &x[..]).collect();
-                let tempvar = apply_transform(&tempvar, &transform, &argstr, depth)?;
-
-                //write tempvar to a place
-                last_tr = tempvar.clone();
-                vars[varnum] = tempvar;
-            },
-            2 => { //operation
-                let tempvar: String;
-                let secondvar: String;
-                if first < 0x100 { //variable
-                    if first as usize >= vars.len() {
-                        return Err(CError::from(format!("Error: Invalid variable read")));
-                    }
-                    tempvar = vars[first as usize].clone();
+    // fails if the instruction or time limit has been reached, starting the clock on the first check
+    pub fn check(&mut self, limits: &Limits) -> Result<(), CError> {
+        if let Some(max) = limits.max_instructions {
+            if self.executed >= max {
+                return Err(CError::limit(Limit::Instructions, &format!("Error: instruction limit of {} exceeded", max)));
+            }
+        }
+        let started = *self.started.get_or_insert_with(Instant::now);
+        if let Some(t) = limits.timeout {
+            if started.elapsed() > t {
+                return Err(CError::limit(Limit::Timeout, &format!("Error: time limit of {:?} exceeded", t)));
+            }
+        }
+        Ok(())
+    }
 
-                } else if first < 0x300 { //const
-                    if first - 0x100 >= num_consts.into() {
-                        return Err(CError::from(format!("Error: out-of-bounds const read")));
+    // counts len bytes of output, failing if they would pass the output limit
+    pub fn write(&mut self, len: usize, limits: &Limits) -> Result<(), CError> {
+        if let Some(max) = limits.max_output {
+            if self.written + len > max {
+                return Err(CError::limit(Limit::Output, &format!("Error: output limit of {} bytes exceeded", max)));
+            }
+        }
+        self.written += len;
+        Ok(())
+    }
+}
+
+// A method run that can be paused between instructions.
+// Each method being run has a frame; running an external transform pushes a frame
+// for its method, which is popped (and its output written back) when it finishes.
+pub struct Vm {
+    pub frames: Vec<Frame>, //the top-level frame is kept after the method finishes
+    limits: Limits,
+    budget: Budget,
+    done: bool,
+}
+
+impl Vm {
+    pub fn new(method: Vec<u8>, input: &[u8], args: &[&str], depth: usize) -> Result<Vm, CError> {
+        Vm::with_method(Rc::new(LoadedMethod::new(method)?), "", input, args, depth)
+    }
+
+    // a run of a method that has already been loaded, called as the transform name
+    pub fn with_method(method: Rc<LoadedMethod>, name: &str, input: &[u8], args: &[&str], depth: usize) -> Result<Vm, CError> {
+        Ok(Vm {
+            frames: vec![Frame::with_method(method, name, input, args, depth)?],
+            limits: Limits::default(),
+            budget: Budget::new(),
+            done: false,
+        })
+    }
+
+    // reads _stdin a line at a time from reader instead of from the input string
+    pub fn with_input(mut self, reader: Box<dyn BufRead>) -> Vm {
+        self.frames[0].reader = Some(reader);
+        self
+    }
+
+    // writes _stdout to writer as it happens, instead of collecting it in the output string
+    pub fn with_output(mut self, writer: Box<dyn Write>) -> Vm {
+        self.frames[0].writer = Some(writer);
+        self
+    }
+
+    // the writer given to with_output, so more can be written after the run
+    pub fn take_output(&mut self) -> Option<Box<dyn Write>> {
+        self.frames.first_mut()?.writer.take()
+    }
+
+    // binary input and output: _stdin reads all of the remaining input at once, and
+    // _stdout writes values without adding a newline
+    pub fn with_binary_io(mut self) -> Vm {
+        self.frames[0].binary = true;
+        self
+    }
+
+    // where dictionary methods called as transforms are looked up, instead of the default search path
+    pub fn with_search_path(mut self, search: Rc<SearchPath>) -> Vm {
+        self.frames[0].cache = Rc::new(MethodCache::new(search));
+        self
+    }
+
+    // shares loaded dictionary methods with other runs. The cache's search path is used
+    pub fn with_cache(mut self, cache: Rc<MethodCache>) -> Vm {
+        self.frames[0].cache = cache;
+        self
+    }
+
+    pub fn with_limits(mut self, limits: Limits) -> Vm {
+        self.limits = limits;
+        for f in self.frames.iter_mut() {
+            f.limits = limits;
+        }
+        self
+    }
+
+    // counts instructions and time from an earlier run's budget instead of from zero
+    pub fn with_budget(mut self, budget: Budget) -> Vm {
+        self.budget = budget;
+        self
+    }
+
+    pub fn budget(&self) -> Budget {
+        self.budget
+    }
+
+    // the frame whose next instruction will run
+    pub fn current(&self) -> Option<&Frame> {
+        self.frames.last()
+    }
+
+    pub fn finished(&self) -> bool {
+        self.done
+    }
+
+    // executes one instruction. Returns the output once the method has finished
+    pub fn step(&mut self, tracer: &mut dyn Tracer) -> Result<Option<Value>, CError> {
+        if self.done {
+            return Err(CError::from_slice("Error: method has already finished"));
+        }
+        self.budget.check(&self.limits)?;
+        self.budget.executed += 1;
+        let mut result = match self.frames.last_mut() {
+            Some(f) => f.step(tracer)?,
+            None => {return Err(CError::from_slice("Error: method has already finished"));},
+        };
+        loop {
+            match result {
+                Step::Next => {return Ok(None);},
+                Step::Call(frame) => {
+                    self.frames.push(*frame);
+                    return Ok(None);
+                },
+                Step::Return(output) => {
+                    if self.frames.len() == 1 {
+                        self.done = true;
+                        return Ok(Some(output));
                     }
-                    tempvar = match get_const(&method, const_offset as u16, first - 0x100) {
-                        Ok(s) => s,
-                        Err(_) => {return Err(CError::from(format!("Error: could not read const")));},
-                    };
-                } else if first < 0x400 { //special var
-                    tempvar = read_special_var(first - 0x300, &last_tr, &last_op, &input, &mut in_ptr, &args)?;
-                } else {
-                    return Err(CError::from(format!("Invalid identifier")));
+                    self.frames.pop();
+                    let caller = self.frames.last_mut().unwrap();
+                    result = caller.resume(output, tracer)?;
+                },
+            }
+        }
+    }
+
+    // executes instructions until the method finishes
+    pub fn run(&mut self, tracer: &mut dyn Tracer) -> Result<Value, CError> {
+        loop {
+            if let Some(output) = self.step(tracer)? {
+                return Ok(output);
+            }
+        }
+    }
+}
+
+pub enum Step {
+    Next, //instruction finished
+    Call(Box<Frame>), //an external transform has to run before the instruction can finish
+    Return(Value), //method finished, with its output
+}
+
+// A method that has been checked and decoded, so that frames running it again don't
+// repeat the work
+#[derive(Debug)]
+pub struct LoadedMethod {
+    pub code: Vec<u8>,
+    pub meta: Metadata,
+    instructions: Vec<(usize, Instruction, usize)>, //offset, instruction and length
+    const_offset: usize,
+}
+
+impl LoadedMethod {
+    pub fn new(code: Vec<u8>) -> Result<LoadedMethod, CError> {
+        if code.len() < HEADER_LEN {
+            return Err(CError::from_slice("Error: Invalid method file"));
+        }
+        if &code[0..4] != b"CPTH" || code[7] != 0x0 {
+            return Err(CError::from_slice("Error: Invalid header"));
+        }
+        let const_offset: usize = ((code[0xc] as usize) << 8) | (code[0xd] as usize);
+        let meta = read_metadata(&code)?;
+        let mut instructions = Vec::new();
+        let mut offset = HEADER_LEN;
+        while offset < const_offset {
+            let (inst, len) = Instruction::decode(&code[..const_offset.min(code.len())], offset)?;
+            instructions.push((offset, inst, len));
+            offset += len;
+        }
+        Ok(LoadedMethod { code, meta, instructions, const_offset })
+    }
+
+    pub fn argmin(&self) -> u8 {
+        self.code[0x8]
+    }
+
+    pub fn argmax(&self) -> u8 {
+        self.code[0x9]
+    }
+
+    pub fn num_vars(&self) -> u8 {
+        self.code[0xe]
+    }
+
+    pub fn num_consts(&self) -> u8 {
+        self.code[0xb]
+    }
+
+    // the instruction at an offset, and its length
+    pub fn instruction(&self, offset: usize) -> Result<(Instruction, usize), CError> {
+        match self.instructions.binary_search_by_key(&offset, |x| x.0) {
+            Ok(i) => Ok((self.instructions[i].1.clone(), self.instructions[i].2)),
+            Err(_) => Err(CError::from_slice("Error: instruction is out of bounds")),
+        }
+    }
+}
+
+// Dictionary methods loaded during a run, by the transform name they were applied as.
+// The frames of a run share one cache, so a transform applied in a loop is read from
+// disk and checked only once
+#[derive(Default)]
+pub struct MethodCache {
+    search: RefCell<Option<Rc<SearchPath>>>, //found from the environment when first needed
+    methods: RefCell<HashMap<String, Rc<LoadedMethod>>>,
+}
+
+impl MethodCache {
+    pub fn new(search: Rc<SearchPath>) -> MethodCache {
+        MethodCache { search: RefCell::new(Some(search)), methods: RefCell::default() }
+    }
+
+    pub fn get(&self, name: &str) -> Result<Rc<LoadedMethod>, CError> {
+        if let Some(s) = self.methods.borrow().get(name) {
+            return Ok(s.clone());
+        }
+        let search = match self.search.borrow().clone() {
+            Some(s) => s,
+            None => Rc::new(SearchPath::from_env()?),
+        };
+        *self.search.borrow_mut() = Some(search.clone());
+        let method = Rc::new(LoadedMethod::new(search.load_transform(name)?)?);
+        self.methods.borrow_mut().insert(name.to_string(), method.clone());
+        Ok(method)
+    }
+
+    // forgets the loaded methods, so changes to the dictionary are seen
+    pub fn clear(&self) {
+        self.methods.borrow_mut().clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.methods.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.methods.borrow().is_empty()
+    }
+}
+
+// the state of one running method
+pub struct Frame {
+    pub method: Rc<LoadedMethod>,
+    pub name: String, //transform the method was called as, empty for the top-level method
+    pub depth: usize,
+    pub input: Value,
+    pub args: Vec<String>,
+    pub vars: Vec<Value>,
+    pub last_tr: Value, //result of last transform
+    pub last_op: Value, //result of last operation
+    pub output: Value,
+    pub in_ptr: usize, //read position in input
+    pub binary: bool, //input and output aren't split into lines
+    reader: Option<Box<dyn BufRead>>,
+    writer: Option<Box<dyn Write>>,
+    written: usize, //bytes written to _stdout
+    pub count: usize, //offset of the next instruction
+    pub executed: usize,
+    pending: Option<usize>, //variable waiting for the result of an external transform
+    limits: Limits,
+    cache: Rc<MethodCache>, //dictionary methods, shared with the frames it calls
+}
+
+impl Frame {
+    pub fn new(method: Vec<u8>, name: &str, input: &[u8], args: &[&str], depth: usize) -> Result<Frame, CError> {
+        Frame::with_method(Rc::new(LoadedMethod::new(method)?), name, input, args, depth)
+    }
+
+    // a frame running a method that has already been loaded
+    pub fn with_method(method: Rc<LoadedMethod>, name: &str, input: &[u8], args: &[&str], depth: usize) -> Result<Frame, CError> {
+        let (argmin, argmax) = (method.argmin(), method.argmax());
+        if args.len() > argmax.into() || args.len() < argmin.into() {
+            return Err(CError::from(format!("Error: incorrect argument number {}: max: {}, min: {}{}",
+                args.len(), argmax, argmin, method.meta.usage_hint())));
+        }
+        let args = bind_args(&method.meta, &args.to_vec())?;
+        let num_vars = method.num_vars();
+        Ok(Frame {
+            method,
+            name: name.to_string(),
+            depth,
+            input: Value(input.to_vec()),
+            args,
+            vars: vec![Value::new(); num_vars.into()],
+            last_tr: Value::new(),
+            last_op: Value::new(),
+            output: Value::new(),
+            in_ptr: 0,
+            binary: false,
+            reader: None,
+            writer: None,
+            written: 0,
+            count: 0x10,
+            executed: 0,
+            pending: None,
+            limits: Limits::default(),
+            cache: Rc::default(),
+        })
+    }
+
+    pub fn finished(&self) -> bool {
+        self.count >= self.method.const_offset
+    }
+
+    // the instruction that runs next, and its length
+    pub fn next_instruction(&self) -> Result<(Instruction, usize), CError> {
+        if self.finished() {
+            return Err(CError::from_slice("Error: method has already finished"));
+        }
+        self.method.instruction(self.count)
+    }
+
+    pub fn step(&mut self, tracer: &mut dyn Tracer) -> Result<Step, CError> {
+        if self.finished() {
+            return self.finish(tracer);
+        }
+        let (inst, len) = self.next_instruction()?;
+        let mut writes: Vec<(String, Value)> = Vec::new();
+        match inst {
+            Instruction::Load { source, target } => {
+                let value = self.read(source)?;
+                writes.push((self.write(target, value.clone())?, value));
+            },
+            Instruction::Apply { target, transform, ref args } => {
+                //only variables can be transformed
+                if target >= 0x400 {
+                    return Err(CError::from_slice("Error: out-of-bounds identifier"));
+                } else if target >= 0x300 {
+                    return Err(CError::from_slice("Error: Cannot transform special var"));
+                } else if target >= 0x100 {
+                    return Err(CError::from_slice("Error: Cannot transform constant"));
                 }
+                let value = self.read(target)?;
+                let name = match transform {
+                    0x100..=0x2ff => self.constant(transform)?,
+                    _ => {return Err(CError::from_slice("Error: transform name must be a constant"));},
+                };
+                let targs = args.iter().map(|x| self.transform_arg(*x)).collect::<Result<Vec<String>, CError>>()?;
+                let argstr: Vec<&str> = targs.iter().map(|x| &x[..]).collect();
+                let result = match apply_transform(&value, &name, &argstr)? {
+                    Some(s) => s,
+                    None => {
+                        //not built in, so the dictionary method runs in a frame of its own
+                        if self.depth >= self.limits.max_depth {
+                            return Err(CError::limit(Limit::Depth, "Maximum Recursion Depth exceeded"));
+                        }
+                        let callee = self.cache.get(&name)?;
+                        let mut frame = Frame::with_method(callee, &name, value.as_bytes(), &argstr, self.depth + 1)?;
+                        frame.limits = self.limits;
+                        frame.cache = self.cache.clone();
+                        frame.binary = self.binary;
+                        self.pending = Some(target as usize);
+                        return Ok(Step::Call(Box::new(frame)));
+                    },
+                };
+                self.check_len(&result)?;
+                writes.push((format!("v{}", target), result.clone()));
+                self.last_tr = result.clone();
+                self.vars[target as usize] = result;
+            },
+            Instruction::Operation { first, second, operation } => {
+                let a = self.read(first)?;
+                let b = self.read(second)?;
+                let name = match operation {
+                    0x100..=0x2ff => self.constant(operation)?,
+                    _ => {return Err(CError::from_slice("Error: attempt to use var as operation name"));},
+                };
+                let result = apply_operation(&a, &b, &name, self.limits.max_string)?;
+                self.check_len(&result)?;
+                writes.push(("_o".to_string(), result.clone()));
+                self.last_op = result;
+            },
+        }
+        self.complete(inst, len, writes, tracer)
+    }
 
+    // finishes an apply that was waiting on an external transform
+    pub fn resume(&mut self, result: Value, tracer: &mut dyn Tracer) -> Result<Step, CError> {
+        let varnum = match self.pending.take() {
+            Some(s) => s,
+            None => {return Err(CError::from_slice("Error: no transform is waiting for a result"));},
+        };
+        self.check_len(&result)?;
+        let writes = vec![(format!("v{}", varnum), result.clone())];
+        self.last_tr = result.clone();
+        self.vars[varnum] = result;
+        let (inst, len) = self.next_instruction()?;
+        self.complete(inst, len, writes, tracer)
+    }
 
-                if second < 0x100 { //variable
-                    if second as usize >= vars.len() {
-                        return Err(CError::from(format!("Error: Invalid variable read")));
-                    }
-                    secondvar = vars[second as usize].clone();
+    fn complete(&mut self, inst: Instruction, len: usize, writes: Vec<(String, Value)>, tracer: &mut dyn Tracer) -> Result<Step, CError> {
+        tracer.instruction(&TraceEvent {
+            depth: self.depth,
+            offset: self.count,
+            opcode: inst.opcode(),
+            operands: inst.operands(),
+            writes,
+        });
+        self.executed += 1;
+        self.count += len;
 
-                } else if second < 0x300 { //const
-                    if second - 0x100 >= num_consts.into() {
-                        return Err(CError::from(format!("Error: out-of-bounds const read")));
-                    }
-                    secondvar = match get_const(&method, const_offset as u16, second - 0x100) {
-                        Ok(s) => s,
-                        Err(_) => {return Err(CError::from(format!("Error: could not read const")));},
-                    };
-                } else if second < 0x400 { //special var
-                    secondvar = read_special_var(second - 0x300, &last_tr, &last_op, &input, &mut in_ptr, &args)?;
+        if self.finished() {
+            return self.finish(tracer);
+        }
+        Ok(Step::Next)
+    }
+
+    fn finish(&mut self, tracer: &mut dyn Tracer) -> Result<Step, CError> {
+        if let Some(w) = self.writer.as_mut() {
+            w.flush()?;
+        }
+        tracer.finish(self.depth, self.executed, self.written);
+        Ok(Step::Return(self.output.clone()))
+    }
+
+    // reads the value of a variable, constant or special variable
+    pub fn read(&mut self, id: u16) -> Result<Value, CError> {
+        match id {
+            0..=0xff => match self.vars.get(id as usize) {
+                Some(s) => Ok(s.clone()),
+                None => Err(CError::from_slice("Error: out-of-bounds variable read")),
+            },
+            0x100..=0x2ff => self.constant_value(id),
+            0x308 if self.reader.is_some() => {
+                let reader = self.reader.as_mut().unwrap();
+                let mut line: Vec<u8> = Vec::new();
+                let n = if self.binary {
+                    reader.read_to_end(&mut line)?
                 } else {
-                    return Err(CError::from(format!("Invalid identifier")));
+                    reader.read_until(b'\n', &mut line)?
+                };
+                //the length of a stream isn't known until it ends, so report what was read
+                if n == 0 {
+                    return Err(CError::from(format!("Error: exceeded input text: all {} bytes have been read", self.in_ptr)));
+                }
+                self.in_ptr += n;
+                if !self.binary && line.ends_with(b"\n") {
+                    line.pop();
                 }
+                Ok(Value(line))
+            },
+            0x300..=0x3ff => {
+                let args: Vec<&str> = self.args.iter().map(|x| &x[..]).collect();
+                read_special_var(id - 0x300, &self.last_tr, &self.last_op, &self.input, &mut self.in_ptr, self.binary, &args)
+            },
+            _ => Err(CError::from_slice("Error: invalid identifier number")),
+        }
+    }
 
-                let third: u16 = ((method[count+5] as u16) << 8) | (method[count+6] as u16);
-                let operation: String;
-                if third < 0x100 || third >= 0x300 {
-                    return Err(CError::from(format!("Error: attempt to use var as operation name")));
-                } else {
-                    if third - 0x100 >= num_consts as u16 {
-                        return Err(CError::from(format!("Error: out-of-bounds const read")));
+    // writes to a variable or special variable, returning the name of what was written
+    pub fn write(&mut self, id: u16, value: Value) -> Result<String, CError> {
+        match id {
+            0..=0xff => {
+                if self.vars.len() <= id as usize {
+                    return Err(CError::from_slice("Error: out-of-bounds variable write"));
+                }
+                self.vars[id as usize] = value;
+                Ok(format!("v{}", id))
+            },
+            0x100..=0x2ff => Err(CError::from_slice("Error: constant writes are prohibited")),
+            0x300..=0x3ff => {
+                //lines end with a newline unless the output is binary
+                let end: &[u8] = if self.binary { b"" } else { b"\n" };
+                if id == 0x309 && !value.is_empty() {
+                    if let Some(max) = self.limits.max_output {
+                        if self.written + value.len() + end.len() > max {
+                            return Err(CError::limit(Limit::Output, &format!("Error: output limit of {} bytes exceeded", max)));
+                        }
                     }
-                    operation = match get_const(&method, const_offset as u16, third - 0x100) {
-                        Ok(s) => s,
-                        Err(_) => {return Err(CError::from(format!("Error: const read failure")));},
-                    };
+                    self.written += value.len() + end.len();
                 }
-                eprintln!("Applying oper {} on {} and {}", operation, tempvar, secondvar);
-                let result = apply_operation(&tempvar, &secondvar, &operation)?;
-                last_op = result;
-
+                match self.writer.as_mut() {
+                    Some(w) if id == 0x309 => {
+                        if !value.is_empty() {
+                            w.write_all(value.as_bytes())?;
+                            w.write_all(end)?;
+                        }
+                    },
+                    _ => {write_special_var(id - 0x300, &value, end, &mut self.output)?;},
+                }
+                Ok(crate::compile::SPECIAL_VARS.get((id - 0x300) as usize).copied().unwrap_or("special").to_string())
+            },
+            _ => Err(CError::from_slice("Error: invalid identifier number")),
+        }
+    }
 
+    fn check_len(&self, value: &Value) -> Result<(), CError> {
+        match self.limits.max_string {
+            Some(max) if value.len() > max => {
+                Err(CError::limit(Limit::StringLength, &format!("Error: string length limit of {} bytes exceeded", max)))
             },
+            _ => Ok(()),
+        }
+    }
 
-            _ => {return Err(CError::from(format!("Error: unrecognized opcode '0x{:X}'", method[count])));},
+    fn constant(&self, id: u16) -> Result<String, CError> {
+        if id - 0x100 >= self.method.num_consts() as u16 {
+            return Err(CError::from_slice("Error: out-of-bounds const read"));
         }
+        get_const(&self.method.code, self.method.const_offset as u16, id - 0x100)
+    }
 
-        count += inst.len();
+    fn constant_value(&self, id: u16) -> Result<Value, CError> {
+        if id - 0x100 >= self.method.num_consts() as u16 {
+            return Err(CError::from_slice("Error: out-of-bounds const read"));
+        }
+        get_const_bytes(&self.method.code, self.method.const_offset as u16, id - 0x100)
+    }
 
-        if count >= const_offset {
-            eprintln!("Interpretation successful\n=====================\n");
-            return Ok(output);
+    // an argument of apply: a constant, or a number stored in the instruction
+    fn transform_arg(&self, arg: u16) -> Result<String, CError> {
+        let form: u16 = (arg & 0xfc00) >> 10;
+        let value: u16 = arg & 0x3ff;
+        match form {
+            0x10 => self.constant(value),
+            0x00 => Ok(value.to_string()),
+            0x01 => Ok("-".to_owned() + &value.to_string()),
+            _ => Err(CError::from(format!("Error: invalid argument type {:X}", form))),
         }
     }
 }
 
-fn read_special_var(num: u16, last_tr: &String, last_op: &String, input: &String, in_ptr: &mut usize, args: &Vec<&str>) -> Result<String, CError> {
+// maps positional and `key=value` arguments onto argument slots, filling in defaults
+pub fn bind_args(meta: &Metadata, args: &Vec<&str>) -> Result<Vec<String>, CError> {
+    if meta.args.is_empty() {
+        return Ok(args.iter().map(|x| x.to_string()).collect());
+    }
+    let mut slots: Vec<Option<String>> = vec![None; meta.args.len()];
+    let mut pos: usize = 0;
+    for x in args.iter() {
+        //only a declared name before the '=' makes an argument named, so values such as
+        //key=abc can still be given by position
+        let named = match x.split_once('=') {
+            Some((k, v)) => meta.arg_slot(k).map(|n| (k, v, n)),
+            None => None,
+        };
+        if let Some((k, v, n)) = named {
+            if slots[n].is_some() {
+                return Err(CError::from(format!("Error: argument '{}' given more than once", k)));
+            }
+            slots[n] = Some(v.to_string());
+        } else {
+            //positional arguments fill the first unset slots
+            while pos < slots.len() && slots[pos].is_some() {
+                pos += 1;
+            }
+            if pos >= slots.len() {
+                slots.push(None);
+            }
+            slots[pos] = Some(x.to_string());
+        }
+    }
+    let mut bound: Vec<String> = Vec::new();
+    for (i, x) in slots.into_iter().enumerate() {
+        let spec = meta.args.get(i);
+        bound.push(match (x, spec) {
+            (Some(s), _) => s,
+            (None, Some(a)) if a.default.is_some() => a.default.clone().unwrap(),
+            (None, Some(a)) if a.required => {
+                return Err(CError::from(format!("Error: missing argument '{}'{}", a.name, meta.usage_hint())));
+            },
+            (None, _) => String::new(),
+        });
+    }
+    Ok(bound)
+}
+
+fn read_special_var(num: u16, last_tr: &Value, last_op: &Value, input: &Value, in_ptr: &mut usize, binary: bool, args: &[&str]) -> Result<Value, CError> {
     match num {
         0 => { // "_"
             Ok(last_tr.clone())
@@ -273,126 +811,464 @@ fn read_special_var(num: u16, last_tr: &String, last_op: &String, input: &String
             Ok(last_op.clone())
         },
         8 => { // "_stdin"
+            let input = input.as_bytes();
             if *in_ptr >= input.len() {
                 Err(CError::from(format!("Error: exceeded input text: ptr {}, len {}", *in_ptr, input.len())))
             } else {
+                let t = *in_ptr;
                 Ok(
-                match input[*in_ptr..].find('\n') {
-                    Some(s) => {let t = *in_ptr; *in_ptr += 1 + s;
-                        input[t..s].to_string()},
-                    None => {let t=input[*in_ptr..].to_string();*in_ptr = input.len();t},
+                match input[t..].iter().position(|x| *x == b'\n') {
+                    Some(s) if !binary => {*in_ptr += 1 + s;
+                        Value(input[t..t+s].to_vec())},
+                    _ => {*in_ptr = input.len(); Value(input[t..].to_vec())},
                 }
                 )
             }
         }
         9 => { // "_stdout"
-            Err(CError::from(format!("Error: cannot read from stdout")))
+            Err(CError::from_slice("Error: cannot read from stdout"))
         },
         0xa => { // "_null"
-            Ok(String::new())
+            Ok(Value::new())
         }
         0xb => { // "_c" : returns an arbitrary character
-            Ok(String::from(" "))
+            Ok(Value::from(" "))
         },
         0x40..=0x5f => { //arg #-0x40
             if args.len() <= num as usize - 0x40 {
-                Ok(String::new())
+                Ok(Value::new())
             } else {
-                Ok(args[num as usize - 0x40].to_string())
+                Ok(Value::from(args[num as usize - 0x40]))
             }
         },
-        _ => Err(CError::from(format!("That operation is not supported yet"))),
+        _ => Err(CError::from_slice("That operation is not supported yet")),
     }
 }
 
-fn write_special_var(num: u16, value: &str, output: &mut String) -> Result<(), CError> {
+fn write_special_var(num: u16, value: &Value, end: &[u8], output: &mut Value) -> Result<(), CError> {
     match num {
         9 => {
             if !value.is_empty() {
-                *output += value;
-                output.push('\n');
+                output.0.extend_from_slice(value.as_bytes());
+                output.0.extend_from_slice(end);
             }
             Ok(())
         },
-        _ => {return Err(CError::from(format!("Error: cannot write to specified special var")));},
+        _ => Err(CError::from_slice("Error: cannot write to specified special var")),
+    }
+}
+
+// applies a transform outside of a method: a built-in transform, or a dictionary method
+// found through the cache. With binary set, a dictionary method reads all of its input at
+// once and writes its output without adding newlines
+pub fn run_transform(input: &Value, name: &str, args: &[&str], cache: &Rc<MethodCache>, limits: Limits, binary: bool) -> Result<Value, CError> {
+    run_transform_within(input, name, args, cache, limits, &mut Budget::new(), binary)
+}
+
+// runs a transform, charging the instructions and time it takes to budget
+pub fn run_transform_within(input: &Value, name: &str, args: &[&str], cache: &Rc<MethodCache>, limits: Limits, budget: &mut Budget, binary: bool) -> Result<Value, CError> {
+    budget.check(&limits)?;
+    if let Some(s) = apply_transform(input, name, args)? {
+        return Ok(s);
     }
+    let vm = Vm::with_method(cache.get(name)?, name, input.as_bytes(), args, 1)?;
+    let vm = vm.with_cache(cache.clone()).with_limits(limits).with_budget(*budget);
+    let mut vm = if binary { vm.with_binary_io() } else { vm };
+    let result = vm.run(&mut NullTracer);
+    *budget = vm.budget();
+    result
 }
 
-fn apply_transform(input: &String, transform: &String, args: &Vec<&str>, depth: usize) -> Result<String, CError> {
+// runs a built-in transform. Returns None if there is no built-in transform with that name
+fn apply_transform(input: &Value, transform: &str, args: &[&str]) -> Result<Option<Value>, CError> {
+    let encoding = match transform_encoding(transform) {
+        Some(s) => s,
+        None => {return Ok(None);}, //not a built-in transform
+    };
+    if !encoding.accepts(input.as_bytes()) {
+        return Err(CError::from(format!("Error: {} requires {} input", transform, encoding.name())));
+    }
+    let bytes = input.as_bytes();
+    //the encoding check makes text transforms' input valid UTF-8
+    let text = || String::from_utf8_lossy(bytes).into_owned();
     //if type is a built-in transform, execute it:
-    match &transform[..] {
+    let result = match transform {
         "upper" => {
-            transform_upper(input)
+            transform_upper(bytes)
         },
         "lower" => {
-            transform_lower(input)
+            transform_lower(bytes)
         },
         "trim_numeric" => {
-            transform_trim_numeric(input)
+            transform_trim_numeric(bytes)
         },
         "trim_alpha" => {
-            transform_trim_alpha(input)
+            transform_trim_alpha(bytes)
         },
         "trim_special" => {
-            transform_trim_special(input)
+            transform_trim_special(bytes)
         },
         "trim_whitespace" => {
-            transform_trim_whitespace(input)
+            transform_trim_whitespace(bytes)
         },
         "prune" => {
-            transform_prune(input)
+            transform_prune(bytes)
         },
         "prune_numeric" => {
-            transform_prune_numeric(input)
+            transform_prune_numeric(bytes)
         },
         "prune_ascii" => {
-            transform_prune_ascii(input)
+            transform_prune_ascii(bytes)
+        },
+        "hex" => {
+            transform_hex(bytes)
+        },
+        "hex_dec" => {
+            transform_hex_decode(bytes)
+        },
+        "xor" => {
+            if args.len() != 1 {
+                return Err(CError::from_slice("xor requires a key"));
+            }
+            transform_xor(bytes, args[0].as_bytes())
         },
         "shift" => {
-            if args.len() < 1 || args[0].parse::<i16>().is_err() {
-                return Err(CError::from(format!("Shift requires a numeric argument")));
+            if args.is_empty() || args.len() > 2 || args[0].parse::<i16>().is_err() {
+                return Err(CError::from_slice("Shift requires a numeric argument"));
             }
-            //args[0] needs to be coerced to a i16
-            transform_shift(input, args[0].parse::<i16>().unwrap()) //unsafe
+            let alphabet = match args.get(1) {
+                Some(s) => Alphabet::from_name(s)?,
+                None => Alphabet::latin(),
+            };
+            transform_shift(bytes, args[0].parse::<i16>().unwrap(), &alphabet)
         },
         "rc" => {
             if args.len() != 1 || args[0].parse::<u16>().is_err() {
-                return Err(CError::from(format!("rc encode requires a numeric argument")));
+                return Err(CError::from_slice("rc encode requires a numeric argument"));
             }
-            transform_rc_encode(input, args[0].parse().unwrap())
+            transform_rc_encode(&text(), args[0].parse().unwrap()).map(String::into_bytes)
                 
         },
         "rc_dec" => {
             if args.len() != 1 || args[0].parse::<u16>().is_err() {
-                return Err(CError::from(format!("rc decode requires a numeric argument")));
+                return Err(CError::from_slice("rc decode requires a numeric argument"));
             }
-            transform_rc_decode(input, args[0].parse().unwrap())
+            transform_rc_decode(&text(), args[0].parse().unwrap()).map(String::into_bytes)
         },
-        _ => { //not a built-in transform
-               //check for transforms in the dictionary
-            external_transform(input, transform, args, depth)
+        name if polyalphabetic_cipher(name).is_some() => {
+            let (cipher, decode) = polyalphabetic_cipher(name).unwrap();
+            if args.is_empty() || args.len() > 3 {
+                return Err(CError::from(format!("Error: {} requires a key, and optionally an alphabet and how the key advances", name)));
+            }
+            let alphabet = match args.get(1) {
+                Some(s) if !s.is_empty() => Alphabet::from_name(s)?,
+                _ => Alphabet::latin(),
+            };
+            let advance = match args.get(2) {
+                Some(s) => match KeyAdvance::from_name(s) {
+                    Some(a) => a,
+                    None => {return Err(CError::from(format!("Error: the key advances at 'letters' or 'all', not '{}'", s)));},
+                },
+                None => KeyAdvance::Letters,
+            };
+            transform_polyalphabetic(bytes, cipher, args[0], decode, &alphabet, advance)
         },
-    }
+        _ => {
+            return Ok(None);
+        },
+    };
+    result.map(|x| Some(Value(x)))
 }
 
-fn apply_operation(in1: &String, in2: &String, op: &String) -> Result<String, String> {
+fn apply_operation(in1: &Value, in2: &Value, op: &String, max_len: Option<usize>) -> Result<Value, CError> {
     match &op[..] {
         "cat" => {
-            let t = in1.to_string() + in2;
-            Ok(t)
+            let t = [in1.as_bytes(), in2.as_bytes()].concat();
+            Ok(Value(t))
         },
         "eq" => {
-            Ok(if in1 == in2 { String::new() } else {"false".to_string()})
+            Ok(if in1 == in2 { Value::new() } else {Value::from("false")})
         },
         "repeat" => {
-            if let Ok(s) = in2.parse::<usize>() {
-                Ok(in1.repeat(s))
+            if let Some(s) = in2.as_text().ok().and_then(|x| x.parse::<usize>().ok()) {
+                //refuse before allocating the result
+                match max_len {
+                    Some(max) if in1.len().saturating_mul(s) > max => {
+                        Err(CError::limit(Limit::StringLength, &format!("Error: string length limit of {} bytes exceeded", max)))
+                    },
+                    _ => Ok(Value(in1.as_bytes().repeat(s))),
+                }
             } else {
-                Err(format!("Error: Invalid argument to $repeat"))
+                Err(CError::from_slice("Error: Invalid argument to $repeat"))
             }
         },
-        _ => {
-            return Err(format!("Error: invalid operation '{}'",op));
-        },
+        _ => Err(CError::from(format!("Error: invalid operation '{}'",op))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //prefix and suffix have defaults, sep must be given
+    fn tag_args() -> Metadata {
+        let mut meta = Metadata::default();
+        for x in ["prefix=<<", "suffix=>>", "sep!"] {
+            meta.set("arg", x).unwrap();
+        }
+        meta
+    }
+
+    #[test]
+    fn unterminated_constants_are_invalid() {
+        //one constant whose text runs off the end of the method
+        let mut method = vec![0u8; 0xc];
+        method[0xb] = 1;
+        method.extend_from_slice(&[0, 2, b'a', b'b']);
+        for e in [get_const(&method, 0xc, 0).unwrap_err(), get_const_bytes(&method, 0xc, 0).unwrap_err()] {
+            assert_eq!(e.to_string(), "Error: invalid method: unterminated constant");
+        }
+        method.push(0);
+        assert_eq!(get_const(&method, 0xc, 0).unwrap(), "ab");
+    }
+
+    #[test]
+    fn defaults_fill_missing_arguments() {
+        assert_eq!(bind_args(&tag_args(), &vec!["sep=-"]).unwrap(), vec!["<<", ">>", "-"]);
+    }
+
+    #[test]
+    fn positional_arguments_fill_the_remaining_slots() {
+        assert_eq!(bind_args(&tag_args(), &vec!["suffix=]", "[", "-"]).unwrap(), vec!["[", "]", "-"]);
+    }
+
+    #[test]
+    fn bad_argument_lists_are_rejected() {
+        let meta = tag_args();
+        let err = |args: Vec<&str>| bind_args(&meta, &args).unwrap_err().to_string();
+        assert!(err(vec!["sep=-", "sep=+"]).contains("more than once"));
+        assert!(err(vec!["prefix=("]).contains("missing argument 'sep'"));
+    }
+
+    #[test]
+    fn values_may_contain_equals_signs() {
+        //"a b" and "key" are not declared, so those arguments are positional
+        assert_eq!(bind_args(&tag_args(), &vec!["a b=c", "x", "sep=="]).unwrap(), vec!["a b=c", "x", "="]);
+        assert_eq!(bind_args(&tag_args(), &vec!["key=abc", "sep=-"]).unwrap(), vec!["key=abc", ">>", "-"]);
+        assert_eq!(bind_args(&tag_args(), &vec!["colour=red", "x", "y"]).unwrap(), vec!["colour=red", "x", "y"]);
+        //without declared arguments nothing is treated as named
+        assert_eq!(bind_args(&Metadata::default(), &vec!["a", "b=c"]).unwrap(), vec!["a", "b=c"]);
+    }
+
+    #[test]
+    fn tracing_reports_every_instruction() {
+        let script = "%argmin=0,argmax=0\nvar x\nload x from _stdin\napply upper<> to x\nwrite x to _stdout\n";
+        let method = crate::compile::convert_to_method(script).unwrap();
+        let mut buf: Vec<u8> = Vec::new();
+        let output = Vm::new(method, b"abc", &[], 0).unwrap().run(&mut StreamTracer::new(TraceLevel::Json, &mut buf)).unwrap();
+        assert_eq!(output, Value::from("ABC\n"));
+        let events: Vec<serde_json::Value> = String::from_utf8(buf).unwrap().lines().map(|x| serde_json::from_str(x).unwrap()).collect();
+        let ops: Vec<&str> = events.iter().filter_map(|x| x["op"].as_str()).collect();
+        assert_eq!(ops, vec!["load", "apply", "load"]);
+        assert_eq!(events[1]["writes"][0]["value"], "ABC");
+        assert_eq!(events[2]["writes"][0]["target"], "_stdout");
+        assert_eq!(events[3]["instructions"], 3);
+    }
+
+    #[test]
+    fn instructions_decode_from_the_method() {
+        let script = "%argmin=0,argmax=0\nvar x\nload x from _stdin\napply shift<3> to x\nwrite x $cat x to _stdout\n";
+        let method = crate::compile::convert_to_method(script).unwrap();
+        let (load, len) = Instruction::decode(&method, 0x10).unwrap();
+        assert_eq!((load.clone(), len), (Instruction::Load { source: 0x308, target: 0 }, 5));
+        assert_eq!(load.operands(), vec![0x308, 0]);
+        let (apply, len) = Instruction::decode(&method, 0x15).unwrap();
+        assert!(matches!(apply, Instruction::Apply { target: 0, ref args, .. } if args == &vec![3]), "{:?}", apply);
+        assert_eq!((apply.opcode(), len), (1, 8));
+        let (op, len) = Instruction::decode(&method, 0x1d).unwrap();
+        assert_eq!((op.opcode(), len), (2, 7));
+        //opcodes past the end of the text, and truncated instructions
+        assert!(Instruction::decode(&[9, 0, 0], 0).unwrap_err().to_string().contains("unrecognized opcode '0x9'"));
+        assert!(Instruction::decode(&method[..0x13], 0x10).is_err());
+        assert!(Instruction::decode(&method, method.len()).is_err());
+    }
+
+    #[test]
+    fn vm_steps_until_the_method_returns() {
+        let script = "%argmin=0,argmax=0\nvar x\nload x from _stdin\napply upper<> to x\nwrite x to _stdout\n";
+        let method = crate::compile::convert_to_method(script).unwrap();
+        let mut vm = Vm::new(method.clone(), b"abc", &[], 0).unwrap();
+        let mut steps = 0;
+        let output = loop {
+            steps += 1;
+            if let Some(s) = vm.step(&mut NullTracer).unwrap() {
+                break s;
+            }
+            assert!(!vm.finished());
+        };
+        assert_eq!((output, steps), (Value::from("ABC\n"), 3));
+        assert!(vm.finished());
+        assert_eq!(Vm::new(method, b"abc", &[], 0).unwrap().run(&mut NullTracer).unwrap(), Value::from("ABC\n"));
+    }
+
+    #[test]
+    fn vm_rejects_bad_methods() {
+        assert!(Vm::new(b"CPTH".to_vec(), b"", &[], 0).is_err());
+        let mut method = crate::compile::convert_to_method("%argmin=0,argmax=0\nvar x\n").unwrap();
+        method[0] = b'X';
+        match Vm::new(method, b"", &[], 0) {
+            Err(e) => assert!(e.to_string().contains("Invalid header"), "{}", e),
+            Ok(_) => panic!("a method without the magic number was loaded"),
+        }
+    }
+
+    fn run_limited(script: &str, input: &str, limits: Limits) -> Result<Value, CError> {
+        let method = crate::compile::convert_to_method(script).unwrap();
+        Vm::new(method, input.as_bytes(), &[], 0)?.with_limits(limits).run(&mut NullTracer)
+    }
+
+    #[test]
+    fn each_limit_stops_the_run_with_its_own_error() {
+        let echo = "%argmin=0,argmax=0\nvar x\nload x from _stdin\nwrite x to _stdout\nwrite x to _stdout\n";
+        let cases = [
+            (echo, Limits { max_instructions: Some(2), ..Limits::default() }, Limit::Instructions),
+            (echo, Limits { max_output: Some(6), ..Limits::default() }, Limit::Output),
+            ("%argmin=0,argmax=0\nvar x\nload x from _stdin\napply upper<> to x\n", Limits { max_string: Some(2), ..Limits::default() }, Limit::StringLength),
+            (echo, Limits { timeout: Some(Duration::ZERO), ..Limits::default() }, Limit::Timeout),
+            ("%argmin=0,argmax=0\nvar x\napply no_such_method<> to x\n", Limits { max_depth: 0, ..Limits::default() }, Limit::Depth),
+        ];
+        for (script, limits, kind) in cases {
+            let e = run_limited(script, "abc", limits).expect_err(&format!("{:?}", kind));
+            assert_eq!(e.limit_exceeded(), Some(kind), "{}", e);
+        }
+        //the same run fits within slightly larger limits
+        let fits = Limits { max_instructions: Some(3), max_output: Some(8), ..Limits::default() };
+        assert_eq!(run_limited(echo, "abc", fits).unwrap(), Value::from("abc\nabc\n"));
+    }
+
+    #[test]
+    fn repeat_is_refused_before_allocating() {
+        let script = "%argmin=0,argmax=0\nvar x\nconst n \"1000000000000\"\nload x from _stdin\nload x from x $repeat n\n";
+        let e = run_limited(script, "abc", Limits { max_string: Some(1024), ..Limits::default() }).unwrap_err();
+        assert_eq!(e.limit_exceeded(), Some(Limit::StringLength));
+        assert!(e.to_string().contains("1024 bytes"));
+        //other errors are not limit errors
+        let e = run_limited("%argmin=0,argmax=0\nvar x\nload x from x $nope x\n", "", Limits::default()).unwrap_err();
+        assert_eq!(e.limit_exceeded(), None);
+    }
+
+    //a writer whose contents can be read after the Vm that owns it is gone
+    #[derive(Clone, Default)]
+    struct Shared(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    const LINES: &str = "%argmin=0,argmax=0\nvar x\nload x from _stdin\nwrite x to _stdout\nload x from _stdin\napply upper<> to x\nwrite x to _stdout\n";
+
+    #[test]
+    fn streamed_input_is_read_a_line_at_a_time() {
+        let method = crate::compile::convert_to_method(LINES).unwrap();
+        let buffered = Vm::new(method.clone(), b"one\ntwo\nthree\n", &[], 0).unwrap().run(&mut NullTracer).unwrap();
+        assert_eq!(buffered, Value::from("one\nTWO\n"));
+
+        let out = Shared::default();
+        let input: Box<dyn BufRead> = Box::new(&b"one\ntwo\nthree\n"[..]);
+        let returned = Vm::new(method, b"", &[], 0).unwrap().with_input(input).with_output(Box::new(out.clone()))
+            .run(&mut NullTracer).unwrap();
+        assert!(returned.is_empty());
+        assert_eq!(out.0.take(), buffered.0);
+    }
+
+    #[test]
+    fn streamed_input_can_run_out() {
+        let method = crate::compile::convert_to_method(LINES).unwrap();
+        let input: Box<dyn BufRead> = Box::new(&b"only\n"[..]);
+        let e = Vm::new(method, b"", &[], 0).unwrap().with_input(input).with_output(Box::new(Shared::default()))
+            .run(&mut NullTracer).unwrap_err();
+        assert!(e.to_string().contains("exceeded input text: all 5 bytes have been read"), "{}", e);
+    }
+
+    #[test]
+    fn streamed_output_counts_towards_the_limit() {
+        let method = crate::compile::convert_to_method(LINES).unwrap();
+        let out = Shared::default();
+        let input: Box<dyn BufRead> = Box::new(&b"one\ntwo\n"[..]);
+        let e = Vm::new(method, b"", &[], 0).unwrap().with_input(input).with_output(Box::new(out.clone()))
+            .with_limits(Limits { max_output: Some(6), ..Limits::default() }).run(&mut NullTracer).unwrap_err();
+        assert_eq!(e.limit_exceeded(), Some(Limit::Output));
+        assert_eq!(String::from_utf8(out.0.take()).unwrap(), "one\n");
+    }
+
+    #[test]
+    fn binary_io_passes_bytes_through_unchanged() {
+        let script = "%argmin=0,argmax=0\nvar x\nload x from _stdin\napply xor<k> to x\nwrite x to _stdout\napply xor<k> to x\nwrite x to _stdout\n";
+        let method = crate::compile::convert_to_method(script).unwrap();
+        let input: &[u8] = b"\xff\x00a\nb\n";
+        let output = Vm::new(method.clone(), input, &[], 0).unwrap().with_binary_io().run(&mut NullTracer).unwrap();
+        //all of the input is read at once, and nothing is added after each write
+        let xored: Vec<u8> = input.iter().map(|x| x ^ b'k').collect();
+        assert_eq!(output.0, [&xored[..], input].concat());
+        assert!(output.as_text().is_err());
+
+        //line mode reads up to the first newline and ends each write with one
+        let output = Vm::new(method, input, &[], 0).unwrap().run(&mut NullTracer).unwrap();
+        assert_eq!(output.0, [&xored[..3], b"\n", &input[..3], b"\n"].concat());
+    }
+
+    #[test]
+    fn streamed_binary_input_is_read_whole() {
+        let script = "%argmin=0,argmax=0\nvar x\nload x from _stdin\napply hex<> to x\nwrite x to _stdout\n";
+        let method = crate::compile::convert_to_method(script).unwrap();
+        let out = Shared::default();
+        let input: Box<dyn BufRead> = Box::new(&b"\x01\n\xfe"[..]);
+        Vm::new(method, b"", &[], 0).unwrap().with_binary_io().with_input(input).with_output(Box::new(out.clone()))
+            .run(&mut NullTracer).unwrap();
+        assert_eq!(out.0.take(), b"010afe");
+    }
+
+    #[test]
+    fn transforms_check_the_encoding_of_their_input() {
+        let script = "%argmin=0,argmax=0\nvar x\nload x from _stdin\napply hex_dec<> to x\nwrite x to _stdout\n";
+        let method = crate::compile::convert_to_method(script).unwrap();
+        let e = Vm::new(method.clone(), "caf\u{e9}".as_bytes(), &[], 0).unwrap().run(&mut NullTracer).unwrap_err();
+        assert_eq!(e.to_string(), "Error: hex_dec requires ascii input");
+        assert_eq!(Vm::new(method, b"6869", &[], 0).unwrap().run(&mut NullTracer).unwrap(), Value::from("hi\n"));
+        let script = "%argmin=0,argmax=0\nvar x\nload x from _stdin\napply rc<2> to x\nwrite x to _stdout\n";
+        let method = crate::compile::convert_to_method(script).unwrap();
+        let e = Vm::new(method.clone(), b"ab\xff", &[], 0).unwrap().run(&mut NullTracer).unwrap_err();
+        assert_eq!(e.to_string(), "Error: rc requires utf-8 input");
+        assert_eq!(Vm::new(method, "caf\u{e9}".as_bytes(), &[], 0).unwrap().run(&mut NullTracer).unwrap(), Value::from("cfa\u{e9}\n"));
+    }
+
+    #[test]
+    fn dictionary_methods_are_loaded_once_per_cache() {
+        use crate::compile::convert_to_method;
+        use crate::dictionary::{Dictionary, PathSource};
+        let dir = std::env::temp_dir().join(format!("cipherlang-cache-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let shout = convert_to_method("%argmin=0,argmax=0\nvar x\nload x from _stdin\napply upper<> to x\nwrite x to _stdout\n").unwrap();
+        Dictionary::at(dir.clone()).unwrap().install("shout", &shout, None).unwrap();
+        let mut search = SearchPath::default();
+        search.dirs.push((dir.clone(), PathSource::Flag));
+        let cache = Rc::new(MethodCache::new(Rc::new(search)));
+
+        let twice = convert_to_method("%argmin=0,argmax=0\nvar x\nload x from _stdin\napply shout<> to x\napply shout<> to x\nwrite x to _stdout\n").unwrap();
+        let run = |cache: &Rc<MethodCache>| Vm::new(twice.clone(), b"hi", &[], 0).unwrap().with_cache(cache.clone()).run(&mut NullTracer);
+        assert_eq!(run(&cache).unwrap(), Value::from("HI\n\n"));
+        assert_eq!(cache.len(), 1);
+
+        //a later run sharing the cache doesn't go back to the dictionary
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(run(&cache).unwrap(), Value::from("HI\n\n"));
+        cache.clear();
+        assert!(cache.is_empty());
+        assert!(run(&cache).unwrap_err().to_string().contains("shout"));
     }
 }
@@ -0,0 +1,338 @@
+use snailquote::unescape;
+use crate::*;
+
+// Converts a script into a list of tokens.
+// A newline ends a statement unless the line ends with '\'.
+// A '#' at the start of a word begins a comment, which runs to the end of the line.
+pub struct Lexer {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+    col: usize,
+    tokens: Vec<Token>,
+    keep_comments: bool,
+}
+
+impl Lexer {
+    pub fn new(script: &str) -> Lexer {
+        Lexer {
+            chars: script.chars().collect(),
+            pos: 0,
+            line: 1,
+            col: 1,
+            tokens: Vec::new(),
+            keep_comments: false,
+        }
+    }
+
+    // emit comments as tokens, for tools that need to reproduce the script
+    pub fn with_comments(mut self) -> Lexer {
+        self.keep_comments = true;
+        self
+    }
+
+    pub fn lex(mut self) -> Result<Vec<Token>, CError> {
+        while let Some(c) = self.peek() {
+            if c == '\n' {
+                self.end_statement();
+                self.advance();
+            } else if c.is_whitespace() {
+                self.advance();
+            } else if c == '#' {
+                self.skip_comment();
+            } else if c == '\\' && self.is_continuation() {
+                //skip to the start of the next line
+                while self.advance() != Some('\n') {}
+            } else if c == '"' {
+                let (line, col) = (self.line, self.col);
+                let raw = self.string_literal()?;
+                let value = match unescape(&raw) {
+                    Ok(s) => s,
+                    Err(_) => {return Err(CError::at(line, col, "Invalid string escape code"));},
+                };
+                self.tokens.push(Token::at(TType::Str(value), line, col));
+                self.check_separated()?;
+            } else {
+                self.word()?;
+            }
+        }
+        self.end_statement();
+        Ok(self.tokens)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn advance(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+        Some(c)
+    }
+
+    fn end_statement(&mut self) {
+        match self.tokens.last() {
+            None => {},
+            Some(t) if t.ttype == TType::EndStatement => {},
+            Some(_) => {
+                let t = Token::at(TType::EndStatement, self.line, self.col);
+                self.tokens.push(t);
+            },
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        let (line, col) = (self.line, self.col);
+        let mut text = String::new();
+        while let Some(c) = self.peek() {
+            if c == '\n' {
+                break;
+            }
+            text.push(c);
+            self.advance();
+        }
+        if self.keep_comments {
+            let text = text[1..].trim_end().to_string();
+            self.tokens.push(Token::at(TType::Comment(text), line, col));
+        }
+    }
+
+    // a backslash followed only by whitespace until the end of the line
+    fn is_continuation(&self) -> bool {
+        for c in self.chars[self.pos+1..].iter() {
+            if *c == '\n' {
+                return true;
+            } else if !c.is_whitespace() {
+                return false;
+            }
+        }
+        true
+    }
+
+    // words, strings and argument lists must be followed by whitespace or a comment
+    fn check_separated(&self) -> Result<(), CError> {
+        match self.peek() {
+            Some(c) if !c.is_whitespace() && c != '#' => {
+                Err(CError::at(self.line, self.col, &format!("Unexpected character '{}'", c)))
+            },
+            _ => Ok(()),
+        }
+    }
+
+    // reads a quoted string, including its quotes and escape sequences
+    fn string_literal(&mut self) -> Result<String, CError> {
+        let (line, col) = (self.line, self.col);
+        let mut raw = String::new();
+        raw.push(self.advance().unwrap());
+        loop {
+            match self.advance() {
+                Some('"') => {
+                    raw.push('"');
+                    return Ok(raw);
+                },
+                Some('\\') => {
+                    raw.push('\\');
+                    match self.advance() {
+                        Some('\n') | None => {break;},
+                        Some(c) => {raw.push(c);},
+                    }
+                },
+                Some('\n') | None => {break;},
+                Some(c) => {raw.push(c);},
+            }
+        }
+        Err(CError::at(line, col, "Unterminated string constant"))
+    }
+
+    // reads a keyword, identifier, operation, directive or transform
+    fn word(&mut self) -> Result<(), CError> {
+        let (line, col) = (self.line, self.col);
+        let mut s = String::new();
+        while let Some(c) = self.peek() {
+            if c.is_whitespace() {
+                break;
+            } else if c == '"' {
+                //quoted sections of directives keep their quotes and escapes
+                s += &self.string_literal()?;
+            } else if c == '<' && !s.is_empty() && !s.starts_with(['%', '$', '_']) {
+                self.advance();
+                let args = self.transform_args(line, col)?;
+                self.tokens.push(Token::at(TType::Transform(s, args), line, col));
+                return self.check_separated();
+            } else {
+                s.push(c);
+                self.advance();
+            }
+        }
+        let ttype = classify_word(&s, line, col)?;
+        self.tokens.push(Token::at(ttype, line, col));
+        Ok(())
+    }
+
+    // reads a comma-delimited argument list up to the closing '>'
+    // arguments may be quoted, and the list may span several lines
+    fn transform_args(&mut self, line: usize, col: usize) -> Result<Vec<String>, CError> {
+        let mut args: Vec<String> = Vec::new();
+        let mut current = String::new();
+        let mut quoted = false;
+        loop {
+            match self.peek() {
+                None => {return Err(CError::at(line, col, "Unterminated transform argument list"));},
+                Some(',') | Some('>') => {
+                    let c = self.advance().unwrap();
+                    let arg = if quoted { current } else { current.trim().to_string() };
+                    if quoted || !arg.is_empty() {
+                        args.push(arg);
+                    }
+                    current = String::new();
+                    quoted = false;
+                    if c == '>' {
+                        return Ok(args);
+                    }
+                },
+                Some('"') => {
+                    let (l, c) = (self.line, self.col);
+                    //a string may follow the key of a named argument: key="value"
+                    let key = match current.trim().strip_suffix('=') {
+                        Some(k) if !k.trim().is_empty() => format!("{}=", k.trim()),
+                        _ => String::new(),
+                    };
+                    if quoted || (key.is_empty() && !current.trim().is_empty()) {
+                        return Err(CError::at(l, c, "Unexpected string in transform argument"));
+                    }
+                    let raw = self.string_literal()?;
+                    current = match unescape(&raw) {
+                        Ok(s) => key + &s,
+                        Err(_) => {return Err(CError::at(l, c, "Invalid string escape code"));},
+                    };
+                    quoted = true;
+                },
+                Some('\\') if self.is_continuation() => {
+                    //skip to the start of the next line
+                    while self.advance() != Some('\n') {}
+                },
+                Some(c) if quoted && !c.is_whitespace() => {
+                    return Err(CError::at(self.line, self.col, "Expected ',' or '>' after string argument"));
+                },
+                Some(c) => {
+                    if !quoted {
+                        current.push(c);
+                    }
+                    self.advance();
+                },
+            }
+        }
+    }
+}
+
+fn classify_word(s: &str, line: usize, col: usize) -> Result<TType, CError> {
+    let ttype = if s.starts_with('_') {
+        //special variable: Identifier
+        TType::Identifier(s.to_string())
+    } else if let Some(op) = s.strip_prefix('$') {
+        if op.is_empty() {
+            return Err(CError::at(line, col, "Null operations are not permitted"));
+        }
+        TType::Operation(op.to_string())
+    } else if s.starts_with('!') {
+        return Err(CError::at(line, col, "File operation -- File operations are not implemented"));
+    } else if let Some(d) = s.strip_prefix('%') {
+        if d.is_empty() {
+            return Err(CError::at(line, col, "Empty directive line"));
+        }
+        TType::Directive(d.to_string())
+    } else {
+        match s {
+            "var" => TType::Var,
+            "const" => TType::Const,
+            "load" => TType::Load,
+            "write" => TType::Write,
+            "apply" => TType::Apply,
+            "to" => TType::To,
+            "from" => TType::From,
+            _ => TType::Identifier(s.to_string()),
+        }
+    };
+    Ok(ttype)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn types(script: &str) -> Vec<TType> {
+        Lexer::new(script).lex().unwrap().into_iter().map(|x| x.ttype).collect()
+    }
+
+    fn args_of(script: &str) -> Vec<String> {
+        match &types(script)[1] {
+            TType::Transform(_, args) => args.clone(),
+            t => panic!("expected a transform, found {:?}", t),
+        }
+    }
+
+    #[test]
+    fn continuation_joins_lines() {
+        let t = types("apply upper<> \\\n  to x\nvar y");
+        assert_eq!(t, vec![TType::Apply, TType::Transform("upper".to_string(), vec![]), TType::To,
+            TType::Identifier("x".to_string()), TType::EndStatement,
+            TType::Var, TType::Identifier("y".to_string()), TType::EndStatement]);
+    }
+
+    #[test]
+    fn backslash_in_the_middle_of_a_line_is_not_a_continuation() {
+        assert_eq!(args_of("apply sub<a\\b> to a"), vec!["a\\b"]);
+    }
+
+    #[test]
+    fn quoted_arguments() {
+        assert_eq!(args_of("apply sub<\"THE QUICK, BROWN\", \"say \\\"hi\\\"\", \"\"> to x"),
+            vec!["THE QUICK, BROWN", "say \"hi\"", ""]);
+        assert_eq!(args_of("apply sub<\"abc\",\n    \"xyz\"> to x"), vec!["abc", "xyz"]);
+    }
+
+    #[test]
+    fn continuation_inside_arguments() {
+        assert_eq!(args_of("apply sub<abc, \\\n    xyz> to x"), vec!["abc", "xyz"]);
+        assert_eq!(args_of("apply sub<\"abc\", \\\n\"xyz\"> to x"), vec!["abc", "xyz"]);
+    }
+
+    #[test]
+    fn comments_end_at_the_newline() {
+        assert_eq!(types("var x # the input\n# whole line\n  #indented"),
+            vec![TType::Var, TType::Identifier("x".to_string()), TType::EndStatement]);
+        //'#' inside a word is not a comment
+        assert_eq!(types("var x#y"), vec![TType::Var, TType::Identifier("x#y".to_string()), TType::EndStatement]);
+    }
+
+    #[test]
+    fn comments_are_kept_for_tools() {
+        let t: Vec<TType> = Lexer::new("var x # the input\n#whole line   \n").with_comments().lex().unwrap()
+            .into_iter().map(|x| x.ttype).collect();
+        assert!(t.contains(&TType::Comment(" the input".to_string())));
+        assert!(t.contains(&TType::Comment("whole line".to_string())));
+    }
+
+    #[test]
+    fn errors_report_their_position() {
+        let e = Lexer::new("var x\napply sub<\"a\" b> to x").lex().unwrap_err();
+        assert_eq!(e.position(), Some((2, 15)));
+        let e = Lexer::new("apply sub<a, \"b\" to x").lex().unwrap_err();
+        assert!(e.to_string().ends_with("Expected ',' or '>' after string argument"));
+        assert_eq!(Lexer::new("apply sub<a, b").lex().unwrap_err().position(), Some((1, 7)));
+        assert_eq!(Lexer::new("const k \"abc").lex().unwrap_err().position(), Some((1, 9)));
+    }
+
+    #[test]
+    fn tokens_carry_columns() {
+        let t = Lexer::new("var x\n  load x from _stdin").lex().unwrap();
+        let load = t.iter().find(|x| x.ttype == TType::Load).unwrap();
+        assert_eq!((load.line, load.col), (2, 3));
+    }
+}
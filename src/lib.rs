@@ -1,12 +1,33 @@
 use std::fs;
-use std::io::Read;
 use std::collections::HashMap;
+use std::path::PathBuf;
 
 use std::fmt;
 
+pub mod lexer;
+pub mod compile;
+pub mod ast;
+pub mod format;
+pub mod interpret;
+pub mod transform;
+pub mod alphabet;
+pub mod dictionary;
+pub mod cache;
+pub mod testing;
+pub mod roundtrip;
+pub mod pipeline;
+pub mod bundle;
+pub mod trace;
+pub mod debug;
+pub mod repl;
+
 pub const MAJOR_VERSION: u8 = 0x37;
 pub const MINOR_VERSION: u8 = 0x37;
 pub const PATCH_NUM: u8 = 0x37;
+// the version of the method format the compiler writes. Bump it whenever the layout of
+// compiled methods changes, so methods cached by an earlier compiler are compiled again.
+// 2: the META section of directives and dependencies
+pub const METHOD_FORMAT: u32 = 2;
 
 pub const VAR_OFFSET: u16 = 0;
 pub const CONST_OFFSET: u16 = 256;
@@ -14,12 +35,27 @@ pub const SPECIAL_VAR_OFFSET: u16 = 768;
 pub const MAX_CONST_LEN: i32 = 256;
 pub const CONST_HEADER_WIDTH: u16 = 2;
 pub const HEADER_LEN: usize = 16;
+pub const HEADER_FLAGS: usize = 0xf;
+pub const FLAG_METADATA: u8 = 0x1;
+pub const METADATA_MAGIC: &[u8; 4] = b"META";
 
 pub const MAX_RECURSION_DEPTH: usize = 64;
 
 #[derive(Debug)]
 pub struct CError {
     msg: String,
+    pos: Option<(usize, usize)>, //line, column
+    limit: Option<Limit>, //set when a resource limit stopped the interpreter
+}
+
+// the execution limits that can be exceeded
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Limit {
+    Instructions,
+    Output,
+    StringLength,
+    Timeout,
+    Depth,
 }
 
 impl std::fmt::Display for CError {
@@ -31,14 +67,40 @@ impl CError {
     pub fn from_slice(st: &str) -> CError {
         CError {
             msg: st.to_string(),
+            pos: None,
+            limit: None,
+        }
+    }
+    // an error at a position in a script
+    pub fn at(line: usize, col: usize, st: &str) -> CError {
+        CError {
+            msg: format!("Line {}, col {}: {}", line, col, st),
+            pos: Some((line, col)),
+            limit: None,
+        }
+    }
+    // an error caused by exceeding a resource limit
+    pub fn limit(limit: Limit, st: &str) -> CError {
+        CError {
+            msg: st.to_string(),
+            pos: None,
+            limit: Some(limit),
         }
     }
+    pub fn position(&self) -> Option<(usize, usize)> {
+        self.pos
+    }
+    pub fn limit_exceeded(&self) -> Option<Limit> {
+        self.limit
+    }
 }
 
 impl From<String> for CError {
     fn from(st: String) -> Self {
         CError {
             msg: st.clone(),
+            pos: None,
+            limit: None,
         }
     }
 }
@@ -47,6 +109,8 @@ impl From<&str> for CError {
     fn from(st: &str) -> Self {
         CError {
             msg: st.to_string(),
+            pos: None,
+            limit: None,
         }
     }
 }
@@ -55,6 +119,8 @@ impl From<std::num::ParseIntError> for CError {
     fn from(err: std::num::ParseIntError) -> Self {
         CError {
             msg: err.to_string(),
+            pos: None,
+            limit: None,
         }
     }
 }
@@ -62,6 +128,8 @@ impl From<std::string::FromUtf8Error> for CError {
     fn from(err: std::string::FromUtf8Error) -> Self {
         CError {
             msg: err.to_string(),
+            pos: None,
+            limit: None,
         }
     }
 }
@@ -70,10 +138,81 @@ impl From<std::io::Error> for CError {
     fn from(error: std::io::Error) -> Self {
         CError {
             msg: error.to_string(),
+            pos: None,
+            limit: None,
+        }
+    }
+}
+
+
+// A value held by a variable: a string of bytes, which is usually (but need not be) UTF-8.
+#[derive(Clone,Default,PartialEq,Eq)]
+pub struct Value(pub Vec<u8>);
+
+impl Value {
+    pub fn new() -> Value {
+        Value(Vec::new())
+    }
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    pub fn as_text(&self) -> Result<&str, CError> {
+        match std::str::from_utf8(&self.0) {
+            Ok(s) => Ok(s),
+            Err(_) => Err(CError::from_slice("Error: value is not valid UTF-8")),
+        }
+    }
+    pub fn into_text(self) -> Result<String, CError> {
+        Ok(String::from_utf8(self.0)?)
+    }
+    pub fn to_string_lossy(&self) -> String {
+        String::from_utf8_lossy(&self.0).into_owned()
+    }
+    // the value as a quoted string; bytes that aren't UTF-8 are written as \xNN
+    pub fn escaped(&self) -> String {
+        let mut out = String::from("\"");
+        for chunk in self.0.utf8_chunks() {
+            for c in chunk.valid().chars() {
+                out.extend(c.escape_debug());
+            }
+            for b in chunk.invalid() {
+                out += &format!("\\x{:02x}", b);
+            }
         }
+        out.push('"');
+        out
     }
 }
 
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.escaped())
+    }
+}
+
+impl From<&str> for Value {
+    fn from(st: &str) -> Self {
+        Value(st.as_bytes().to_vec())
+    }
+}
+
+impl From<String> for Value {
+    fn from(st: String) -> Self {
+        Value(st.into_bytes())
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(bytes: Vec<u8>) -> Self {
+        Value(bytes)
+    }
+}
 
 #[derive(Debug,Clone,PartialEq)]
 pub enum TType {
@@ -90,6 +229,7 @@ pub enum TType {
     FileOp(String),
     Str(String),
     Directive(String),
+    Comment(String),
     EndStatement,
     Nil,
 }
@@ -97,90 +237,65 @@ pub enum TType {
 pub struct Token {
     pub ttype: TType,
     pub line: usize,
+    pub col: usize,
+}
+impl Default for Token {
+    fn default() -> Self {
+        Self::new()
+    }
 }
+
 impl Token {
     pub fn new() -> Token {
         Token {
             ttype: TType::Nil,
             line: 0,
+            col: 0,
         }
     }
     pub fn new_val(t: TType, l: usize) -> Token {
         Token {
             ttype: t,
             line: l,
+            col: 0,
         }
     }
-    pub fn is_var(&self) -> bool {
-        if let TType::Var = &self.ttype {
-            true
-        } else {
-            false
+    pub fn at(t: TType, l: usize, c: usize) -> Token {
+        Token {
+            ttype: t,
+            line: l,
+            col: c,
         }
     }
+    pub fn is_var(&self) -> bool {
+        matches!(self.ttype, TType::Var)
+    }
     pub fn is_const(&self) -> bool {
-        if let TType::Const = &self.ttype {
-            true
-        } else {
-            false
-        }
+        matches!(self.ttype, TType::Const)
     }
     pub fn is_from(&self) -> bool {
-        if let TType::From = &self.ttype {
-            true
-        } else {
-            false
-        }
+        matches!(self.ttype, TType::From)
     }
     pub fn is_to(&self) -> bool {
-        if let TType::To = &self.ttype {
-            true
-        } else {
-            false
-        }
+        matches!(self.ttype, TType::To)
     }
     pub fn is_load(&self) -> bool {
-        if let TType::Load = &self.ttype {
-            true
-        } else {
-            false
-        }
+        matches!(self.ttype, TType::Load)
     }
     pub fn is_apply(&self) -> bool {
-        if let TType::Apply = &self.ttype {
-            true
-        } else {
-            false
-        }
+        matches!(self.ttype, TType::Apply)
     }
     pub fn is_write(&self) -> bool {
-        if let TType::Write = &self.ttype {
-            true
-        } else {
-            false
-        }
+        matches!(self.ttype, TType::Write)
     }
     pub fn is_ident(&self) -> bool {
-        if let TType::Identifier(_) = &self.ttype {
-            true
-        } else {
-            false
-        }
+        matches!(self.ttype, TType::Identifier(_))
     }
     pub fn is_transform(&self) -> bool {
-        if let TType::Transform(_, _) = &self.ttype {
-            true
-        } else {
-            false
-        }
+        matches!(self.ttype, TType::Transform(_, _))
     }
     pub fn is_oper(&self) -> bool {
-        if let TType::Operation(_) = &self.ttype {
-            true
-        } else {
-            false
-        }
-
+        matches!(self.ttype, TType::Operation(_))
     }
 }
 
@@ -212,6 +327,12 @@ pub struct ProtoInstruction {
     pub line: usize,
     pub arg_str: Vec<bool>,
 }
+impl Default for ProtoInstruction {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ProtoInstruction {
     pub fn clear(&mut self) {
         self.pitype = PIType::Nil;
@@ -261,14 +382,14 @@ impl ProtoInstruction {
             }
             if self.arg_str[i] {
               bi.args.push( 
-                ((const_base & 0x3f) << 0xA) as u16| (x.parse::<u16>().unwrap() & 0x3ff)
+                ((const_base & 0x3f) << 0xA)| (x.parse::<u16>().unwrap() & 0x3ff)
               );
             } else {
               let value: i16 = x.parse().unwrap();
               if value < 0 {
             
                 bi.args.push(
-                  ((negative_base & 0x3f) << 0xA) as u16 | (value.abs() as u16 & 0x3ff)
+                  ((negative_base & 0x3f) << 0xA) | (value.unsigned_abs() & 0x3ff)
                 );
               } else { //normal
                   bi.args.push( (
@@ -286,7 +407,7 @@ impl ProtoInstruction {
           bi.argc = 0;
         },
         PIType::Nil => {
-          return Err(format!("Error: Nil instruction during binarification"));
+          return Err("Error: Nil instruction during binarification".to_string());
         },
       }
       Ok(bi)
@@ -301,12 +422,17 @@ pub struct BinaryInstruction {
     pub argc: u8,
     pub args: Vec<u16>,
 }
+impl Default for BinaryInstruction {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl BinaryInstruction {
 
     pub fn binary(&self) -> Result<Vec<u8>, String> {
-      let mut v: Vec<u8> = Vec::new();
       //this will need to be edited for ciplang v2
-      v.resize(5 + (self.opcode == 1) as usize + self.args.len()*2 + 2*(self.opcode == 2) as usize, 0);
+      let mut v: Vec<u8> = vec![0; 5 + (self.opcode == 1) as usize + self.args.len()*2 + 2*(self.opcode == 2) as usize];
 
       v[0] = self.opcode;
       v[1] = ((self.first & 0xff00) >> 8) as u8;
@@ -326,7 +452,7 @@ impl BinaryInstruction {
       } else if self.opcode == 0 {
         //do nothing
       } else {
-        return Err(format!("Invalid opcode during text generation"));
+        return Err("Invalid opcode during text generation".to_string());
       }
       
 
@@ -343,31 +469,232 @@ impl BinaryInstruction {
       }
     }
 }
-pub fn read_bin_file(infile: &String) -> Vec<u8> {
-    let mut f = fs::File::open(&infile).expect("Could not open file.");
-    let meta = fs::metadata(&infile).expect("Could not read metadata.");
-    let mut buffer = vec![0; meta.len() as usize];
-    f.read(&mut buffer).expect("Buffer overflow error");
+// A named argument slot, declared with %arg or %argnames
+#[derive(Debug,Clone,PartialEq)]
+pub struct ArgSpec {
+    pub name: String,
+    pub default: Option<String>,
+    pub required: bool,
+}
+impl ArgSpec {
+    // encoded as "name=default", "name!" (required) or "name" (optional, no default)
+    fn encode(&self) -> String {
+        match &self.default {
+            Some(s) => format!("{}={}", self.name, s),
+            None if self.required => format!("{}!", self.name),
+            None => self.name.clone(),
+        }
+    }
+    fn decode(st: &str) -> ArgSpec {
+        if let Some(n) = st.find('=') {
+            ArgSpec { name: st[..n].to_string(), default: Some(st[n+1..].to_string()), required: false }
+        } else if let Some(s) = st.strip_suffix('!') {
+            ArgSpec { name: s.to_string(), default: None, required: true }
+        } else {
+            ArgSpec { name: st.to_string(), default: None, required: false }
+        }
+    }
+}
+
+// Method metadata, set with directives such as %name and %usage.
+// Stored after the constant table as NUL-terminated key/value pairs
+#[derive(Debug,Clone,Default,PartialEq)]
+pub struct Metadata {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub author: Option<String>,
+    pub version: Option<String>,
+    pub usage: Option<String>,
+    pub args: Vec<ArgSpec>,
+    pub depends: Vec<String>, //dictionary methods applied as transforms, found by the compiler
+}
+impl Metadata {
+    pub fn is_empty(&self) -> bool {
+        *self == Metadata::default()
+    }
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), CError> {
+        match key {
+            "name" => {self.name = Some(value.to_string());},
+            "description" => {self.description = Some(value.to_string());},
+            "author" => {self.author = Some(value.to_string());},
+            "version" => {self.version = Some(value.to_string());},
+            "usage" => {self.usage = Some(value.to_string());},
+            "argnames" => {
+                self.args = parse(value, ',').iter().map(
+                    |x| ArgSpec { name: x.trim().to_string(), default: None, required: false }
+                ).collect();
+            },
+            "arg" => {
+                self.args.push(ArgSpec::decode(value));
+            },
+            "depends" => {
+                self.depends.push(value.to_string());
+            },
+            _ => {return Err(CError::from(format!("Invalid metadata key {}", key)));},
+        }
+        Ok(())
+    }
+    fn entries(&self) -> Vec<(&str, String)> {
+        let mut v: Vec<(&str, String)> = Vec::new();
+        let fields = [("name", &self.name), ("description", &self.description),
+            ("author", &self.author), ("version", &self.version), ("usage", &self.usage)];
+        for (k, x) in fields {
+            if let Some(s) = x {
+                v.push((k, s.clone()));
+            }
+        }
+        for x in self.args.iter() {
+            v.push(("arg", x.encode()));
+        }
+        for x in self.depends.iter() {
+            v.push(("depends", x.clone()));
+        }
+        v
+    }
+    pub fn binary(&self) -> Vec<u8> {
+        let mut v: Vec<u8> = METADATA_MAGIC.to_vec();
+        for (k, x) in self.entries() {
+            v.extend(k.bytes());
+            v.push(0);
+            v.extend(x.bytes());
+            v.push(0);
+        }
+        v
+    }
+    pub fn from_binary(section: &[u8]) -> Result<Metadata, CError> {
+        if section.len() < 4 || &section[0..4] != METADATA_MAGIC {
+            return Err(CError::from_slice("Error: Invalid metadata section"));
+        }
+        let mut meta = Metadata::default();
+        let mut fields = section[4..].split(|x| *x == 0);
+        while let Some(k) = fields.next() {
+            if k.is_empty() {
+                break;
+            }
+            let v = match fields.next() {
+                Some(s) => String::from_utf8(s.to_vec())?,
+                None => {return Err(CError::from_slice("Error: Truncated metadata section"));},
+            };
+            // keys from newer compilers are skipped rather than rejected
+            let _ = meta.set(&String::from_utf8(k.to_vec())?, &v);
+        }
+        Ok(meta)
+    }
+    pub fn arg_slot(&self, name: &str) -> Option<usize> {
+        self.args.iter().position(|x| x.name == name)
+    }
+    // usage string and argument names, appended to argument errors
+    pub fn usage_hint(&self) -> String {
+        let mut msg = String::new();
+        if let Some(s) = &self.usage {
+            msg += &format!("\nUsage: {}", s);
+        }
+        if !self.args.is_empty() {
+            let names: Vec<String> = (0..self.args.len()).map(|i| self.arg_name(i)).collect();
+            msg += &format!("\nArguments: {}", names.join(", "));
+        }
+        msg
+    }
+    // name of argument #num, as used in error messages and `info`
+    pub fn arg_name(&self, num: usize) -> String {
+        match self.args.get(num) {
+            Some(s) => format!("_{} ({})", num, s.name),
+            None => format!("_{}", num),
+        }
+    }
+}
 
-    buffer
+// returns the offset of the end of the constant table
+pub fn const_table_end(method: &[u8]) -> Result<usize, CError> {
+    if method.len() < HEADER_LEN {
+        return Err(CError::from_slice("Error: Invalid method file"));
+    }
+    let const_offset: usize = ((method[0xc] as usize) << 8) | (method[0xd] as usize);
+    let num_consts = method[0xb] as usize;
+    let mut end = const_offset + CONST_HEADER_WIDTH as usize * num_consts;
+    for i in 0..num_consts {
+        let p = const_offset + CONST_HEADER_WIDTH as usize * i;
+        if p + 1 >= method.len() {
+            return Err(CError::from_slice("Error: out-of-bounds const read"));
+        }
+        let mut t = const_offset + (((method[p] as usize) << 8) | method[p+1] as usize);
+        while t < method.len() && method[t] != 0 {
+            t += 1;
+        }
+        end = end.max(t + 1);
+    }
+    Ok(end)
+}
+
+// reads the metadata section of a method. Methods without one yield empty metadata
+pub fn read_metadata(method: &[u8]) -> Result<Metadata, CError> {
+    if method.len() < HEADER_LEN || method[HEADER_FLAGS] & FLAG_METADATA == 0 {
+        return Ok(Metadata::default());
+    }
+    let start = const_table_end(method)?;
+    if start > method.len() {
+        return Err(CError::from_slice("Error: Invalid metadata section"));
+    }
+    Metadata::from_binary(&method[start..])
+}
+
+// replaces the metadata section of a method
+pub fn write_metadata(method: &[u8], meta: &Metadata) -> Result<Vec<u8>, CError> {
+    let mut out = method.to_vec();
+    if method.len() >= HEADER_LEN && method[HEADER_FLAGS] & FLAG_METADATA != 0 {
+        out.truncate(const_table_end(method)?);
+    }
+    if meta.is_empty() {
+        out[HEADER_FLAGS] &= !FLAG_METADATA;
+    } else {
+        out[HEADER_FLAGS] |= FLAG_METADATA;
+        out.extend(meta.binary());
+    }
+    Ok(out)
+}
+
+// the global dictionary directory, ~/.ciplang/methods
+pub fn dictionary_dir() -> Option<PathBuf> {
+    let mut hdir = dirs::home_dir()?;
+    hdir.push(".ciplang/methods");
+    Some(hdir)
+}
+
+// names of the methods in the dictionary
+pub fn dictionary_methods() -> Vec<String> {
+    match dictionary::SearchPath::from_env() {
+        Ok(s) => s.names(),
+        Err(_) => Vec::new(),
+    }
+}
+
+pub fn read_bin_file(infile: &String) -> Vec<u8> {
+    fs::read(infile).expect("Could not open file.")
 }
 
 pub fn read_file(infile: &String) -> Option<String> {
     let r = fs::read_to_string(infile);
-    match r {
-        Ok(s) => Some(s),
-        Err(_) => None,
-    }
+    r.ok()
 }
 
 // Splits a string up to a vector by delim
 pub fn parse(st: &str, delim: char) -> Vec<String> {
     let mut th: Vec<String> = Vec::new();
     let mut in_str: bool = false;
+    let mut escaped: bool = false;
     let mut cw = String::new();
     
     for ch in st.chars() {
-        if ch == delim {
+        if escaped {
+            //escaped characters in strings never end the string or split it
+            cw.push(ch);
+            escaped = false;
+        }
+        else if in_str && ch == '\\' {
+            cw.push(ch);
+            escaped = true;
+        }
+        else if ch == delim {
             if in_str {
                 cw.push(ch);
             } else {
@@ -397,6 +724,80 @@ pub fn value_in_str_map(map: &HashMap<String, String>, val: &String) -> Option<S
     map.iter().find_map(|(k, v)| if v == val {Some(k.to_string())} else {None})
 }
 
-pub fn index_of_vec_val(arr: &Vec<String>, val: &String) -> Option<usize> {
+pub fn index_of_vec_val(arr: &[String], val: &String) -> Option<usize> {
     arr.iter().position(|r| r == val)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metadata_survives_encoding() {
+        let mut meta = Metadata::default();
+        meta.set("name", "caesar").unwrap();
+        meta.set("description", "shifts letters, then stops").unwrap();
+        meta.set("usage", "caesar SHIFT").unwrap();
+        meta.set("argnames", "shift, alphabet").unwrap();
+        let names: Vec<&str> = meta.args.iter().map(|x| &x.name[..]).collect();
+        assert_eq!(names, vec!["shift", "alphabet"]);
+        assert_eq!(Metadata::from_binary(&meta.binary()).unwrap(), meta);
+    }
+
+    #[test]
+    fn metadata_decoding_skips_unknown_keys() {
+        let section = b"METAname\0vig\0colour\0blue\0version\x001.2\0";
+        let meta = Metadata::from_binary(section).unwrap();
+        assert_eq!(meta.name.as_deref(), Some("vig"));
+        assert_eq!(meta.version.as_deref(), Some("1.2"));
+    }
+
+    #[test]
+    fn malformed_metadata_is_an_error() {
+        assert!(Metadata::from_binary(b"ATEM").is_err());
+        assert!(Metadata::from_binary(b"METAname").is_err());
+        assert!(Metadata::default().set("colour", "blue").is_err());
+    }
+
+    #[test]
+    fn methods_without_the_flag_have_no_metadata() {
+        let mut method = vec![0u8; HEADER_LEN];
+        method[0..4].copy_from_slice(b"CPTH");
+        method.extend(b"METAname\0ignored\0");
+        assert!(read_metadata(&method).unwrap().is_empty());
+    }
+
+    #[test]
+    fn argument_specs_keep_defaults_and_requirements() {
+        let mut meta = Metadata::default();
+        meta.set("arg", "prefix=<<").unwrap();
+        meta.set("arg", "sep!").unwrap();
+        meta.set("arg", "suffix").unwrap();
+        meta.set("arg", "eq==").unwrap();
+        assert_eq!(meta.args[0], ArgSpec { name: "prefix".to_string(), default: Some("<<".to_string()), required: false });
+        assert_eq!(meta.args[1], ArgSpec { name: "sep".to_string(), default: None, required: true });
+        assert_eq!(meta.args[2], ArgSpec { name: "suffix".to_string(), default: None, required: false });
+        assert_eq!(meta.args[3].default.as_deref(), Some("="));
+        assert_eq!(Metadata::from_binary(&meta.binary()).unwrap(), meta);
+        assert_eq!(meta.arg_slot("suffix"), Some(2));
+        assert_eq!(meta.arg_slot("colour"), None);
+    }
+
+    #[test]
+    fn argument_names_in_messages() {
+        let mut meta = Metadata::default();
+        meta.set("argnames", "shift").unwrap();
+        assert_eq!(meta.arg_name(0), "_0 (shift)");
+        assert_eq!(meta.arg_name(1), "_1");
+    }
+
+    #[test]
+    fn values_escape_bytes_that_are_not_utf8() {
+        let v = Value(b"caf\xc3\xa9 \"\xff\n".to_vec());
+        assert_eq!(v.escaped(), "\"caf\u{e9} \\\"\\xff\\n\"");
+        assert_eq!(format!("{:?}", v), v.escaped());
+        assert_eq!(v.to_string_lossy(), "caf\u{e9} \"\u{fffd}\n");
+        assert!(v.clone().into_text().is_err());
+        assert_eq!(Value::from("abc").into_text().unwrap(), "abc");
+    }
+}
@@ -1,16 +1,25 @@
 
-use std::env;
 use std::fs;
-use std::io::Read;
+use std::io::{Read, BufRead, BufReader, Write, BufWriter};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
 use cipherlang::*;
 
-mod compile;
-use crate::compile::*;
-
-mod interpret;
-use crate::interpret::*;
-
-mod transform;
+use cipherlang::compile::*;
+use cipherlang::ast::*;
+use cipherlang::format::*;
+use cipherlang::interpret::*;
+use cipherlang::trace::*;
+use cipherlang::debug::*;
+use cipherlang::repl::*;
+use cipherlang::dictionary::*;
+use cipherlang::bundle::*;
+use cipherlang::cache::*;
+use cipherlang::testing::*;
+use cipherlang::pipeline::*;
 
 /* This is an attempt to rewrite parts of cipherlang using rust.
    Status:
@@ -18,216 +27,996 @@ mod transform;
    Interpreter: mostly compliant with original
    Transform Library: Mostly compliant with original
    Dictionary: Functional
-   External Transforms: Not implemented
+   External Transforms: Functional, as dictionary methods looked up on the search path
  */
 
 //For now, only the original featureset will be implemented, not the v2 additions
 //type Statement = Vec<Token>;
 
+// exit statuses, by the kind of error
+const EXIT_RUNTIME: i32 = 1; //the method failed while running
+const EXIT_USAGE: i32 = 2; //bad command line or method arguments (clap also exits with 2)
+const EXIT_COMPILE: i32 = 3; //a script didn't compile
+const EXIT_IO: i32 = 4; //a file couldn't be read or written, or a method wasn't found
+const EXIT_LIMIT: i32 = 5; //a resource limit was exceeded
 
-fn main() {
-    //parse command-line arguments:
-    let c_args = env::args();
-    let mut infile: String = String::new();
-    let mut outfile: String = String::new();
-    let mut method_file: String = String::new();
-    let mut method: String = String::new();
-    let mut args: String = String::new();
-    let mut define: bool = false;
-    let mut use_file: bool = false;
-    let mut help: bool = false;
-    let mut version: bool = false;
-
-    if env::args().len() == 1 {
-        println!("Error: No method specified\n");
-        std::process::exit(1);
-    }
-
-    let c_args: Vec<String> = c_args.collect();
-    let c_args = &c_args[1..];
-
-    for x in c_args.iter() {
-        if x.len() == 0 {
-            continue;
-        }
-        if x.len() >= 3 && &x[0..2] == "--" {
-            match &x[2..] {
-                "version" => {version = true;},
-                "help" => {help = true;},
-                _ => {eprintln!("Error: invalid argument {}",x) },
-            }
-        } else if x.chars().next().unwrap() == '-' {
-            if x.len() < 2 {
-                eprintln!("Error: invalid argument");
-                std::process::exit(1);
-            }
-            //since length is guaranteed to be nonzero, this is safe
-            match x.chars().nth(1).unwrap() {
-                'a' => {args = x[2..].to_string()},
-                'd' => {define = true},
-                'f' => {use_file = true; method_file = x[2..].to_string()},
-                'h' => {help = true;},
-                'i' => {infile = x[2..].to_string()},
-                'o' => {outfile = x[2..].to_string()},
-                _ => {eprintln!("Error: invalid argument"); std::process::exit(1);},
-            }
-
-        }
-        else if method.is_empty() {
-            method = x.to_string();
-        }
-        else {
-            eprintln!("Error: Only one non-flag argument may be provided\n");
-            std::process::exit(1);
-        }
-    }
-    // post-processing
-    if help {
-        eprintln!("cipherlang v0, made by xavenna.");
-        eprint!("Usage: ciplang");
-        eprintln!(" [-f]method [-h] {{[-d] | [-i<infile>] -o<outfile>] [-a<args>]}}");
-        eprintln!("if infile isn't specified, input is taken from stdin.");
-        eprintln!("if outfile isn't specified, outtput is taken from stdout.");
-        eprint!("if -f is set, method is presumed to be a local file. Otherwise, ");
-        eprintln!("method file is presumed to be in '~/.ciplang/methods'");
-        eprintln!("if -d is set, method is added to dictionary.");
-        eprintln!("-a specified arguments. args should be a comma-delimited list.");
-        eprintln!("Use -h to see this menu");
-        std::process::exit(0);
-    } else if version {
-        eprintln!("cipherlang-rs v0.0");
-        eprintln!("Copyright (C) 2024 xavenna <xavenna.v@gmail.com>");
-        eprintln!("This piece of software is released under the MIT License. See LICENSE for details.");
-        eprintln!("There is NO WARRANTY, to the extent permitted by law.");
-        eprintln!("Written by xavenna");
-
-    } else {
-        if use_file {
-            method = method_file;
-        }
-        transform_text(&infile, &outfile, &method, &args, use_file, define);
-    }
-}
-
-fn transform_text(infile: &String, outfile: &String, method_name: &String, args: &String, local: bool, define: bool) -> bool {
-    eprintln!("Transforming text");
-    let method: Vec<u8>;
-    if local {
-        //check for local file
-        if method_name.is_empty() {
-            eprintln!("Error: null local filename - invalid call");
-            return false;
-        }
-        if let Ok(_) = fs::metadata(&method_name) {
-            //method exists
-
-        } else {
-            println!("Error: could not open local file.\nIf you were trying to use a global method, omit the '-f' flag.");
-            return false
-        }
-        let mod_date = fs::metadata(&method_name).unwrap().modified().unwrap();
-        let cache_name = ".".to_string() + method_name + ".cpth";
-        let cache_exists: bool = match fs::metadata(&cache_name) {
-            Ok(_) => true,
-            Err(_) => false,
+#[derive(Parser)]
+#[command(name = "cipherlang", version, about = "Applies transforms to text using cipherlang methods",
+    long_version = concat!(env!("CARGO_PKG_VERSION"), "\nCopyright (C) 2024 xavenna <xavenna.v@gmail.com>\n",
+        "This piece of software is released under the MIT License. See LICENSE for details.\n",
+        "There is NO WARRANTY, to the extent permitted by law.\nWritten by xavenna"))]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+    /// Search DIR for methods before the other dictionaries. May be repeated
+    #[arg(long = "dict", global = true, value_name = "DIR")]
+    dicts: Vec<PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Compile a script to a method file
+    Compile {
+        script: String,
+        /// Where to write the method (SCRIPT with a .cpth extension by default)
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<String>,
+    },
+    /// Run a method on some input
+    Run(RunArgs),
+    /// Manage the dictionary of installed methods
+    #[command(subcommand)]
+    Dict(DictCommand),
+    /// Compile a script and add it to the dictionary (same as dict install)
+    Install {
+        script: String,
+        /// The name to install it under (the script's file name by default)
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// List the methods in the dictionary (same as dict list)
+    List,
+    /// Remove a method from the dictionary (same as dict remove)
+    Remove {
+        name: String,
+    },
+    /// Pack methods into a bundle file, or install the methods in one
+    #[command(subcommand)]
+    Bundle(BundleCommand),
+    /// Show the name, usage and arguments of a method
+    Info(MethodArg),
+    /// Print the instructions and constants of a method
+    Disasm(MethodArg),
+    /// Print the dictionary methods a method uses as transforms, and what they use in turn
+    Deps(MethodArg),
+    /// Report methods that use missing transforms or that use themselves. Checks every
+    /// method on the search path if none are named
+    Check {
+        names: Vec<String>,
+    },
+    /// Rewrite scripts in canonical form. With no files, stdin is formatted to stdout
+    Fmt {
+        /// Leave files unchanged, and exit with 1 if any would change
+        #[arg(long)]
+        check: bool,
+        files: Vec<String>,
+    },
+    /// Run a script one instruction at a time. Type help at its prompt for commands
+    Debug {
+        script: String,
+        /// Input for _stdin (stdin holds the debugger's commands)
+        #[arg(short, long, value_name = "FILE")]
+        input: Option<String>,
+        /// Arguments for the script, comma-delimited
+        #[arg(short, long = "args", value_name = "ARGS", value_delimiter = ',')]
+        args: Vec<String>,
+    },
+    /// Run statements and expressions as they are typed. Type :help for commands
+    Repl,
+    /// Run the cases in .ciptest files
+    Test {
+        /// Test files, or globs or directories matching them (all .ciptest files under the
+        /// current directory by default)
+        patterns: Vec<String>,
+        /// Only run cases whose names contain TEXT
+        #[arg(long, value_name = "TEXT")]
+        filter: Option<String>,
+        /// How to report results: text, tap or junit
+        #[arg(long, default_value = "text", value_parser = parse_report_format)]
+        format: ReportFormat,
+        /// Write the report to FILE instead of stdout
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<String>,
+    },
+    /// Manage the cache of compiled scripts
+    #[command(subcommand)]
+    Cache(CacheCommand),
+    /// Print a completion script for a shell
+    Completions {
+        shell: Shell,
+    },
+}
+
+#[derive(Subcommand)]
+enum DictCommand {
+    /// List the methods, with their descriptions
+    List,
+    /// Compile a script, or copy a method file, into the dictionary
+    Install {
+        script: String,
+        /// The name to install it under (the script's file name by default)
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// Remove a method
+    Remove {
+        name: String,
+    },
+    /// Rename a method
+    Rename {
+        old: String,
+        new: String,
+    },
+    /// Show where a method came from, its hash and its metadata
+    Info {
+        name: String,
+    },
+    /// Print the dictionaries that are searched, in order
+    Path,
+}
+
+#[derive(Subcommand)]
+enum CacheCommand {
+    /// Remove every compiled script from the cache
+    Clean,
+    /// Print the cache directory
+    Path,
+}
+
+#[derive(Subcommand)]
+enum BundleCommand {
+    /// Pack methods from the dictionary into a bundle
+    Create {
+        /// The bundle file to write
+        #[arg(short, long, value_name = "FILE")]
+        output: String,
+        /// Include the scripts the methods were compiled from, where they can be found
+        #[arg(long)]
+        sources: bool,
+        /// Also pack the dictionary methods they use as transforms, and theirs in turn
+        #[arg(long)]
+        deps: bool,
+        #[arg(required = true)]
+        names: Vec<String>,
+    },
+    /// Check a bundle and install its methods
+    Install {
+        file: String,
+        /// Install even if some methods use transforms that can't be found
+        #[arg(long)]
+        force: bool,
+        /// Write the scripts packed in the bundle to DIR
+        #[arg(long, value_name = "DIR")]
+        sources: Option<PathBuf>,
+    },
+    /// List the methods in a bundle
+    List {
+        file: String,
+    },
+}
+
+#[derive(Args)]
+struct MethodArg {
+    /// A method in the dictionary, or with -f a script or method file
+    method: String,
+    /// METHOD is a local file rather than a method in the dictionary
+    #[arg(short, long)]
+    file: bool,
+}
+
+#[derive(Args)]
+struct RunArgs {
+    /// A method in the dictionary, a pipeline of transforms such as 'upper | shift<3>',
+    /// or with -f a script or method file
+    #[arg(required_unless_present = "eval")]
+    method: Option<String>,
+    /// METHOD is a local file rather than a method in the dictionary
+    #[arg(short, long, conflicts_with = "eval")]
+    file: bool,
+    /// Run SCRIPT, given as text, instead of a method
+    #[arg(short, long = "eval", value_name = "SCRIPT", conflicts_with = "method")]
+    eval: Option<String>,
+    /// Read input from FILE instead of stdin
+    #[arg(short, long, value_name = "FILE")]
+    input: Option<String>,
+    /// Write output to FILE instead of stdout
+    #[arg(short, long, value_name = "FILE")]
+    output: Option<String>,
+    /// Arguments for the method, comma-delimited. key=value sets the named argument key
+    #[arg(short, long = "args", value_name = "ARGS", value_delimiter = ',')]
+    args: Vec<String>,
+    /// More arguments for the method, taken as they are
+    #[arg(last = true, value_name = "ARG")]
+    rest: Vec<String>,
+    /// Read input a line at a time and write output as it is produced
+    #[arg(long)]
+    stream: bool,
+    /// _stdin reads all remaining input at once and _stdout writes values without newlines
+    #[arg(long)]
+    binary: bool,
+    /// Compile a script even if it is in the script cache, and don't cache it
+    #[arg(long)]
+    no_cache: bool,
+    /// Report execution on stderr: off, summary, instructions (the default) or json
+    #[arg(long, value_name = "LEVEL", num_args = 0..=1, require_equals = true,
+        default_missing_value = "instructions", value_parser = parse_trace)]
+    trace: Option<TraceLevel>,
+    /// Limit the instructions run, including those of called methods
+    #[arg(long, value_name = "N")]
+    max_instructions: Option<usize>,
+    /// Limit what the method writes to _stdout
+    #[arg(long, value_name = "BYTES")]
+    max_output: Option<usize>,
+    /// Limit the length of any value
+    #[arg(long, value_name = "BYTES")]
+    max_string: Option<usize>,
+    /// Limit the time spent running
+    #[arg(long, value_name = "SECONDS", value_parser = parse_seconds)]
+    timeout: Option<Duration>,
+    /// Limit how deeply methods may call other methods
+    #[arg(long, value_name = "N")]
+    max_depth: Option<usize>,
+}
+
+impl RunArgs {
+    fn limits(&self) -> Limits {
+        let mut limits = Limits {
+            max_instructions: self.max_instructions,
+            max_output: self.max_output,
+            max_string: self.max_string,
+            timeout: self.timeout,
+            ..Limits::default()
         };
+        if let Some(s) = self.max_depth {
+            limits.max_depth = s;
+        }
+        limits
+    }
+
+    fn method_args(&self) -> Vec<&str> {
+        self.args.iter().filter(|x| !x.is_empty()).chain(self.rest.iter()).map(|x| &x[..]).collect()
+    }
+}
 
+fn parse_trace(s: &str) -> Result<TraceLevel, String> {
+    TraceLevel::from_name(s).ok_or(format!("unknown trace level '{}'", s))
+}
 
-        //
-        if cache_exists && fs::metadata(&cache_name).unwrap().modified().unwrap() >= mod_date {
-            eprintln!("Using cached bytecode file");
-            method = read_bin_file(&cache_name);
-        } else {
-            //compile
-            let script = fs::read_to_string(&method_name).expect("File read error");
-            method = match convert_to_method(&script) {
+fn parse_report_format(s: &str) -> Result<ReportFormat, String> {
+    ReportFormat::from_name(s).ok_or(format!("unknown report format '{}'", s))
+}
+
+fn parse_seconds(s: &str) -> Result<Duration, String> {
+    match s.parse::<f64>() {
+        Ok(x) if x.is_finite() && x >= 0.0 => Ok(Duration::from_secs_f64(x)),
+        _ => Err("expected a number of seconds".to_string()),
+    }
+}
+
+fn main() {
+    let Cli { command, dicts } = Cli::parse();
+    let status = match command {
+        Command::Compile { script, output } => compile_script(&script, output),
+        Command::Run(run) => with_search_path(&dicts, |d| run_method(&run, d)),
+        Command::Dict(cmd) => with_search_path(&dicts, |d| dictionary_command(cmd, d)),
+        Command::Install { script, name } => with_search_path(&dicts, |d| dictionary_command(DictCommand::Install { script, name }, d)),
+        Command::List => with_search_path(&dicts, |d| dictionary_command(DictCommand::List, d)),
+        Command::Remove { name } => with_search_path(&dicts, |d| dictionary_command(DictCommand::Remove { name }, d)),
+        Command::Bundle(cmd) => with_search_path(&dicts, |d| bundle_command(cmd, d)),
+        Command::Info(m) => with_search_path(&dicts, |d| method_info(&m.method, m.file, d)),
+        Command::Disasm(m) => with_search_path(&dicts, |d| disassemble_method(&m.method, m.file, d)),
+        Command::Deps(m) => with_search_path(&dicts, |d| method_dependencies_command(&m.method, m.file, d)),
+        Command::Check { names } => with_search_path(&dicts, |d| check_methods(&names, d)),
+        Command::Fmt { check, files } => format_files(&files, check),
+        Command::Debug { script, input, args } => with_search_path(&dicts, |d| debug_script(&script, input, &args, d)),
+        Command::Repl => with_search_path(&dicts, run_repl),
+        Command::Test { patterns, filter, format, output } => with_search_path(&dicts, |d| run_tests(&patterns, filter.as_deref(), format, output, d)),
+        Command::Cache(cmd) => cache_command(cmd),
+        Command::Completions { shell } => {
+            clap_complete::generate(shell, &mut Cli::command(), "cipherlang", &mut std::io::stdout());
+            0
+        },
+    };
+    std::process::exit(status);
+}
+
+// builds the dictionary search path, then runs a command that uses it
+fn with_search_path(dicts: &[PathBuf], f: impl FnOnce(&Rc<SearchPath>) -> i32) -> i32 {
+    match SearchPath::new(dicts) {
+        Ok(s) => f(&Rc::new(s)),
+        Err(s) => {eprintln!("{}", s); EXIT_IO},
+    }
+}
+
+// the exit status for an error raised while a method runs
+fn runtime_status(e: &CError) -> i32 {
+    match e.limit_exceeded() {
+        Some(_) => EXIT_LIMIT,
+        None => EXIT_RUNTIME,
+    }
+}
+
+// runs a method, reading all of the input first unless --stream is set.
+// returns the exit status
+fn run_method(run: &RunArgs, search: &Rc<SearchPath>) -> i32 {
+    let loaded = match (&run.eval, &run.method) {
+        (Some(script), _) => convert_to_method(script).map_err(|s| {eprintln!("-e: {}", s); EXIT_COMPILE}),
+        (None, Some(m)) if !run.file && Pipeline::is_pipeline(m) => {return run_pipeline(m, run, search);},
+        (None, Some(m)) => load_method(m, run.file, search, !run.no_cache),
+        (None, None) => Err(EXIT_USAGE), //clap requires one or the other
+    };
+    let method: Vec<u8> = match loaded {
+        Ok(s) => s,
+        Err(s) => {return s;},
+    };
+    if run.stream {
+        return stream_text(&method, run, search);
+    }
+
+    let mut input: Vec<u8> = Vec::new();
+    match &run.input {
+        None => {
+            if let Err(s) = std::io::stdin().read_to_end(&mut input) {
+                eprintln!("Error reading stdin: {s}");
+                return EXIT_IO;
+            }
+        },
+        Some(f) => {
+            input = match fs::read(f) {
                 Ok(s) => s,
-                Err(s) => {eprintln!("{}", s);return false},
+                Err(s) => {eprintln!("{}: {}", f, s); return EXIT_IO;},
             };
-            match fs::write(&cache_name, &method) {
-                Ok(_) => {},
-                Err(s) => {eprintln!("Cache Write Error: {s}");},
+        },
+    }
+
+    let mut tracer = StreamTracer::stderr(run.trace.unwrap_or(TraceLevel::Off));
+    let mut vm = match start_vm(method, &input, run) {
+        Ok(s) => s.with_limits(run.limits()).with_search_path(search.clone()),
+        Err(s) => {return s;},
+    };
+    let output = match vm.run(&mut tracer) {
+        Ok(s) => s,
+        Err(s) => {eprintln!("{}", s); return runtime_status(&s);},
+    };
+
+    //decide where to write `output`; the file gets the same bytes stdout would
+    let end: &[u8] = if run.binary { b"" } else { b"\n" };
+    match &run.output {
+        None => {
+            //uses stdout, not stderr, to let it work for scripts
+            let mut stdout = std::io::stdout().lock();
+            if let Err(s) = stdout.write_all(output.as_bytes()).and_then(|_| stdout.write_all(end)) {
+                eprintln!("{s}");
+                return EXIT_IO;
+            }
+        },
+        Some(f) => {
+            if let Err(s) = fs::write(f, [output.as_bytes(), end].concat()) {
+                eprintln!("Could not write to output file: {s}");
+                return EXIT_IO;
             }
+        },
+    }
+    0
+}
 
-        }
-    } else {
-        //check dir for method
+// starts a run of a method. Returns the exit status if the method isn't valid, or
+// doesn't accept the arguments
+fn start_vm(method: Vec<u8>, input: &[u8], run: &RunArgs) -> Result<Vm, i32> {
+    let method = match LoadedMethod::new(method) {
+        Ok(s) => Rc::new(s),
+        Err(s) => {eprintln!("{}", s); return Err(EXIT_COMPILE);},
+    };
+    match Vm::with_method(method, "", input, &run.method_args(), 0) {
+        Ok(s) if run.binary => Ok(s.with_binary_io()),
+        Ok(s) => Ok(s),
+        Err(s) => {eprintln!("{}", s); Err(EXIT_USAGE)},
+    }
+}
 
-        let mut hdir = dirs::home_dir().expect("Could not find home dir");
-        hdir.push(".ciplang/methods");
+// runs a method with _stdin read lazily from the input and _stdout written as it happens
+fn stream_text(method: &[u8], run: &RunArgs, search: &Rc<SearchPath>) -> i32 {
+    let reader: Box<dyn BufRead> = match &run.input {
+        None => Box::new(std::io::stdin().lock()),
+        Some(f) => match fs::File::open(f) {
+            Ok(s) => Box::new(BufReader::new(s)),
+            Err(s) => {eprintln!("{}: {}", f, s); return EXIT_IO;},
+        },
+    };
+    let writer: Box<dyn Write> = match &run.output {
+        None => Box::new(std::io::stdout().lock()),
+        Some(f) => match fs::File::create(f) {
+            Ok(s) => Box::new(BufWriter::new(s)),
+            Err(s) => {eprintln!("Could not write to output file: {s}"); return EXIT_IO;},
+        },
+    };
+    let mut tracer = StreamTracer::stderr(run.trace.unwrap_or(TraceLevel::Off));
+    let vm = match start_vm(method.to_vec(), b"", run) {
+        Ok(s) => s,
+        Err(s) => {return s;},
+    };
+    let mut vm = vm.with_input(reader).with_output(writer).with_limits(run.limits()).with_search_path(search.clone());
+    if let Err(s) = vm.run(&mut tracer) {
+        eprintln!("{}", s);
+        return runtime_status(&s);
+    }
+    //the output ends the same way as without --stream
+    let end: &[u8] = if run.binary { b"" } else { b"\n" };
+    if let Some(mut w) = vm.take_output() {
+        if let Err(s) = w.write_all(end).and_then(|_| w.flush()) {
+            eprintln!("{s}");
+            return EXIT_IO;
+        }
+    }
+    0
+}
 
-        //now, place the compiled method there
-        hdir.push(method_name);
-        hdir.set_extension("cpth");
-        let path = match hdir.to_str() {
-            Some(s) => s,
-            None => {return false;}
-        };
-        method = read_bin_file(&path.to_string());
+// runs a pipeline of transforms. Each line of input goes through it separately, and the
+// results are written as they are produced; with --binary the whole input goes through
+// once. returns the exit status
+fn run_pipeline(text: &str, run: &RunArgs, search: &Rc<SearchPath>) -> i32 {
+    let pipeline = match Pipeline::parse(text) {
+        Ok(s) => s,
+        Err(s) => {eprintln!("{}", s); return EXIT_USAGE;},
+    };
+    if !run.method_args().is_empty() {
+        eprintln!("Error: a pipeline takes no arguments; give them to its transforms, as in shift<3>");
+        return EXIT_USAGE;
+    }
+    let cache = Rc::new(MethodCache::new(search.clone()));
+    if let Err(s) = pipeline.check(&cache) {
+        eprintln!("{}", s);
+        return EXIT_IO;
     }
-    // add method file to dictionary
-    if define {
-        let mut hdir = match dirs::home_dir() {
-            Some(s) => s,
-            None => { eprintln!("Failed to get home directory");return false; },
+    let mut reader: Box<dyn BufRead> = match &run.input {
+        None => Box::new(std::io::stdin().lock()),
+        Some(f) => match fs::File::open(f) {
+            Ok(s) => Box::new(BufReader::new(s)),
+            Err(s) => {eprintln!("{}: {}", f, s); return EXIT_IO;},
+        },
+    };
+    let mut writer: Box<dyn Write> = match &run.output {
+        None => Box::new(std::io::stdout().lock()),
+        Some(f) => match fs::File::create(f) {
+            Ok(s) => Box::new(BufWriter::new(s)),
+            Err(s) => {eprintln!("Could not write to output file: {s}"); return EXIT_IO;},
+        },
+    };
+    //one budget for the whole run, however many lines and stages it has
+    let limits = run.limits();
+    let mut budget = Budget::new();
+    let mut line: Vec<u8> = Vec::new();
+    loop {
+        line.clear();
+        let read = if run.binary { reader.read_to_end(&mut line) } else { reader.read_until(b'\n', &mut line) };
+        match read {
+            Ok(0) if !run.binary => {break;},
+            Ok(_) => {},
+            Err(s) => {eprintln!("Error reading input: {s}"); return EXIT_IO;},
+        }
+        let end: &[u8] = match line.last() {
+            Some(b'\n') if !run.binary => {line.pop(); b"\n"},
+            _ if run.binary => b"",
+            _ => b"\n",
+        };
+        let output = match pipeline.run(&Value(line.clone()), &cache, limits, &mut budget)
+            .and_then(|x| budget.write(x.len() + end.len(), &limits).map(|_| x)) {
+            Ok(s) => s,
+            Err(s) => {eprintln!("{}", s); return runtime_status(&s);},
         };
-        hdir.push(".ciplang/methods");
-        if fs::create_dir_all(&hdir).is_err() {
-            eprintln!("Failed to open dictionary directory");
-            return false;
+        if let Err(s) = writer.write_all(output.as_bytes()).and_then(|_| writer.write_all(end)) {
+            eprintln!("{s}");
+            return EXIT_IO;
         }
-
-        //now, place the compiled method there
-        hdir.push(method_name);
-        hdir.set_extension("cpth");
-        if fs::write(&hdir, &method).is_err() {
-            eprintln!("Failed to write method to dictionary");
-            return false;
-        } else {
-            eprintln!("Successfully wrote method to dictionary");
+        if run.binary {
+            break;
         }
     }
-    //call interpreter using procured method
+    match writer.flush() {
+        Ok(_) => 0,
+        Err(s) => {eprintln!("{s}"); EXIT_IO},
+    }
+}
+
+// finds a method, either a local file or a method in the dictionary. Scripts are compiled
+// through the script cache unless cached is false.
+// On failure, the error has been printed and the exit status is returned
+fn load_method(method_name: &str, local: bool, search: &SearchPath, cached: bool) -> Result<Vec<u8>, i32> {
+    if !local {
+        return match search.load(method_name) {
+            Ok(s) => Ok(s),
+            Err(s) => {
+                eprintln!("{}", s);
+                eprintln!("If you were trying to use a local file, add the '-f' flag.");
+                Err(EXIT_IO)
+            },
+        };
+    }
+    //without a cache directory, scripts are just compiled every time
+    let cache = if cached { ScriptCache::open().ok() } else { None };
+    read_local_method(method_name, cache.as_ref())
+}
+
+// reads a method file, or compiles a script, using the cache if one is given
+fn read_local_method(file: &str, cache: Option<&ScriptCache>) -> Result<Vec<u8>, i32> {
+    let bytes = match fs::read(file) {
+        Ok(s) => s,
+        Err(s) => {eprintln!("{}: {}", file, s); return Err(EXIT_IO);},
+    };
+    if bytes.starts_with(b"CPTH") {
+        return Ok(bytes);
+    }
+    let script = match String::from_utf8(bytes) {
+        Ok(s) => s,
+        Err(_) => {eprintln!("{}: Error: script is not valid UTF-8", file); return Err(EXIT_COMPILE);},
+    };
+    let method = match cache {
+        Some(c) => c.compile(&script),
+        None => convert_to_method(&script),
+    };
+    method.map_err(|s| {eprintln!("{}: {}", file, s); EXIT_COMPILE})
+}
+
+// compiles a script to a method file. returns the exit status
+fn compile_script(script: &str, output: Option<String>) -> i32 {
+    let method = match read_local_method(script, None) {
+        Ok(s) => s,
+        Err(s) => {return s;},
+    };
+    let output = output.unwrap_or_else(|| Path::new(script).with_extension("cpth").to_string_lossy().into_owned());
+    match fs::write(&output, &method) {
+        Ok(_) => 0,
+        Err(s) => {eprintln!("{}: {}", output, s); EXIT_IO},
+    }
+}
 
-    let mut input = String::new();
-    if infile.is_empty() {
-        let mut stdin = std::io::stdin();
-        if let Err(s) = stdin.read_to_string(&mut input) {
-            eprintln!("File write error: {s}");
-            return false;
+// runs the test files matching patterns and reports the results. returns the exit status
+fn run_tests(patterns: &[String], filter: Option<&str>, format: ReportFormat, output: Option<String>, search: &Rc<SearchPath>) -> i32 {
+    let patterns = if patterns.is_empty() { vec![".".to_string()] } else { patterns.to_vec() };
+    let files = match find_tests(&patterns) {
+        Ok(s) if s.is_empty() => {eprintln!("Error: no test files found"); return EXIT_IO;},
+        Ok(s) => s,
+        Err(s) => {eprintln!("{}", s); return EXIT_USAGE;},
+    };
+    let mut results = Vec::new();
+    for f in files.iter() {
+        match TestFile::read(f) {
+            Ok(t) => results.push(t.run(search, filter)),
+            //a file that can't be read is reported as a failure, so the other files still run
+            Err(s) => results.push(FileResult {
+                path: f.clone(),
+                cases: vec![CaseResult { name: "(reading the test file)".to_string(), outcome: Outcome::Failed(s.to_string()), time: Duration::ZERO }],
+            }),
         }
+    }
+    let text = report(&results, format);
+    match output {
+        Some(f) => if let Err(s) = fs::write(&f, text) {
+            eprintln!("{}: {}", f, s);
+            return EXIT_IO;
+        },
+        None => print!("{}", text),
+    }
+    if results.iter().any(|x| x.failures() > 0) { EXIT_RUNTIME } else { 0 }
+}
 
-    } else {
-        input = match fs::read_to_string(&infile) {
-            Ok(s) => s,
-            Err(s) => {eprintln!("{s}");return false;},
-        };
+// runs a cache subcommand. returns the exit status
+fn cache_command(cmd: CacheCommand) -> i32 {
+    let cache = match ScriptCache::open() {
+        Ok(s) => s,
+        Err(s) => {eprintln!("{}", s); return EXIT_IO;},
     };
+    match cmd {
+        CacheCommand::Clean => match cache.clean() {
+            Ok(n) => {eprintln!("Removed {} compiled script{}", n, if n == 1 { "" } else { "s" }); 0},
+            Err(s) => {eprintln!("{}", s); EXIT_IO},
+        },
+        CacheCommand::Path => {println!("{}", cache.dir().display()); 0},
+    }
+}
 
-    let mut cargs: Vec<&str> = args.split(',').collect();
-    cargs.retain(|x| !x.is_empty());
+// the dictionary on the search path holding a method, which is the one it runs from
+fn locate_dictionary(search: &SearchPath, name: &str) -> Result<Dictionary, CError> {
+    match search.locate(name).into_iter().next() {
+        Some((s, _)) => Dictionary::at(s),
+        None => Err(CError::from(format!("Error: no method named '{}' in the dictionary", name))),
+    }
+}
 
+// runs a bundle subcommand. returns the exit status
+fn bundle_command(cmd: BundleCommand, search: &SearchPath) -> i32 {
+    let result = match cmd {
+        BundleCommand::Create { output, sources, deps, names } => {
+            let mut bundle = Bundle::new();
+            let mut pending = names;
+            let mut status = 0;
+            while let Some(name) = pending.pop() {
+                let entry = match locate_dictionary(search, &name).and_then(|x| x.describe(&name)) {
+                    Ok(s) => s,
+                    Err(s) => {eprintln!("{}", s); return EXIT_IO;},
+                };
+                if bundle.get(&entry.name).is_some() {
+                    continue;
+                }
+                let method = match fs::read(&entry.path) {
+                    Ok(s) => s,
+                    Err(s) => {eprintln!("{}: {}", entry.path.display(), s); return EXIT_IO;},
+                };
+                //sources are optional, so one that has moved is only a warning
+                let source = match (&entry.source, sources) {
+                    (Some(s), true) => match fs::read_to_string(s) {
+                        //a method installed from a method file has no script to pack
+                        Ok(t) if t.starts_with("CPTH") => None,
+                        Ok(t) => Path::new(s).file_name().map(|f| (f.to_string_lossy().into_owned(), t)),
+                        Err(_) => {eprintln!("warning: the source of '{}', {}, could not be read", entry.name, s); None},
+                    },
+                    (None, true) => {eprintln!("warning: '{}' has no recorded source", entry.name); None},
+                    _ => None,
+                };
+                if let Err(s) = bundle.add(&entry.name, &method, source) {
+                    eprintln!("{}", s);
+                    return EXIT_IO;
+                }
+                if deps {
+                    for d in bundle.get(&entry.name).map(|x| x.depends.clone()).unwrap_or_default() {
+                        if search.locate(&d).is_empty() {
+                            eprintln!("warning: '{}' uses '{}', which is not in the dictionary", entry.name, d);
+                            status = EXIT_IO;
+                        } else {
+                            pending.push(d);
+                        }
+                    }
+                }
+            }
+            bundle.methods.sort_by(|a, b| a.name.cmp(&b.name));
+            if let Err(s) = fs::write(&output, bundle.to_json()) {
+                eprintln!("{}: {}", output, s);
+                return EXIT_IO;
+            }
+            eprintln!("Packed {} method{} in {}", bundle.methods.len(), if bundle.methods.len() == 1 { "" } else { "s" }, output);
+            return status;
+        },
+        BundleCommand::Install { file, force, sources } => Bundle::read(Path::new(&file)).and_then(|bundle| {
+            let missing = bundle.missing(search);
+            for (method, dep) in missing.iter() {
+                eprintln!("{}: '{}' uses '{}', which is not in the bundle or the dictionary", if force { "warning" } else { "Error" }, method, dep);
+            }
+            if !missing.is_empty() && !force {
+                return Err(CError::from_slice("Error: nothing was installed; use --force to install anyway"));
+            }
+            let mut dict = search.primary()?;
+            for entry in bundle.install(&mut dict, Path::new(&file))? {
+                eprintln!("Installed {} in {}", entry.name, dict.dir().display());
+            }
+            if let Some(dir) = sources {
+                fs::create_dir_all(&dir)?;
+                for (name, text) in bundle.methods.iter().filter_map(|x| x.source.as_ref()) {
+                    //only the file name is used, so a bundle can't write outside DIR
+                    let path = dir.join(Path::new(name).file_name().unwrap_or_default());
+                    fs::write(&path, text)?;
+                }
+            }
+            Ok(())
+        }),
+        BundleCommand::List { file } => Bundle::read(Path::new(&file)).map(|bundle| {
+            for x in bundle.methods.iter() {
+                let mut line = x.name.clone();
+                if let Some(d) = read_metadata(&x.method).ok().and_then(|m| m.description) {
+                    line += &format!("  {}", d);
+                }
+                if !x.depends.is_empty() {
+                    line += &format!("  (uses {})", x.depends.join(", "));
+                }
+                if let Some((f, _)) = &x.source {
+                    line += &format!("  [source: {}]", f);
+                }
+                println!("{}", line);
+            }
+        }),
+    };
+    match result {
+        Ok(_) => 0,
+        Err(s) => {eprintln!("{}", s); EXIT_IO},
+    }
+}
 
-    //set up recursion tracker
-    let depth: usize = 0;
-    let output = match interpret(&method, &input, &cargs, depth) {
+// runs a dict subcommand. returns the exit status
+fn dictionary_command(cmd: DictCommand, search: &SearchPath) -> i32 {
+    let result = match cmd {
+        DictCommand::List => {
+            for name in search.names() {
+                let found = search.locate(&name);
+                let mut line = name.clone();
+                if let Some(d) = Dictionary::at(found[0].0.clone()).and_then(|x| x.describe(&name)).ok().and_then(|x| x.meta.description) {
+                    line += &format!("  {}", d);
+                }
+                //methods outside the home dictionary say where they are
+                if let Some((_, source)) = search.dirs.iter().find(|x| x.0 == found[0].0).filter(|x| x.1 != PathSource::Home) {
+                    line += &format!("  [{}: {}]", source.name(), found[0].0.display());
+                }
+                if found.len() > 1 {
+                    line += &format!("  (shadows {})", found[1].1.display());
+                }
+                println!("{}", line);
+            }
+            Ok(())
+        },
+        DictCommand::Install { script, name } => {
+            let method = match read_local_method(&script, None) {
+                Ok(s) => s,
+                Err(s) => {return s;},
+            };
+            let name = match name.or_else(|| Some(Path::new(&script).file_stem()?.to_str()?.to_string())) {
+                Some(s) => s,
+                None => {eprintln!("Error: could not name the method; use --name"); return EXIT_USAGE;},
+            };
+            search.primary().and_then(|mut x| {
+                let entry = x.install(&name, &method, Some(Path::new(&script)))?;
+                eprintln!("Installed {} in {}", entry.name, x.dir().display());
+                //installing doesn't change which method a name finds if another shadows it
+                if search.find(&entry.name) != Some(entry.path) {
+                    eprintln!("warning: '{}' is still found elsewhere first", entry.name);
+                }
+                Ok(())
+            })
+        },
+        DictCommand::Remove { name } => locate_dictionary(search, &name).and_then(|mut x| x.remove(&name)),
+        DictCommand::Rename { old, new } => locate_dictionary(search, &old).and_then(|mut x| x.rename(&old, &new)),
+        DictCommand::Info { name } => {
+            let entry = match locate_dictionary(search, &name).and_then(|x| x.describe(&name)) {
+                Ok(s) => s,
+                Err(s) => {eprintln!("{}", s); return EXIT_IO;},
+            };
+            println!("file: {}", entry.path.display());
+            println!("sha256: {}", entry.hash);
+            if let Some(t) = entry.installed {
+                println!("installed: {}", format_time(t));
+            }
+            if let Some(s) = &entry.source {
+                println!("source: {}", s);
+            }
+            if !entry.indexed {
+                println!("(not in the index; it was not installed with cipherlang)");
+            } else if entry.modified {
+                println!("(the file has changed since it was installed)");
+            }
+            for x in search.locate(&name).iter().skip(1) {
+                println!("shadows: {}", x.1.display());
+            }
+            return method_info(&name, false, search);
+        },
+        DictCommand::Path => {
+            for (dir, source) in search.dirs.iter() {
+                let missing = if dir.is_dir() { "" } else { " (missing)" };
+                println!("{}  [{}]{}", dir.display(), source.name(), missing);
+            }
+            Ok(())
+        },
+    };
+    match result {
+        Ok(_) => 0,
+        Err(s) => {eprintln!("{}", s); EXIT_IO},
+    }
+}
+
+// prints the instructions of a method. returns the exit status
+fn disassemble_method(method_name: &str, local: bool, search: &SearchPath) -> i32 {
+    let method = match load_method(method_name, local, search, true) {
         Ok(s) => s,
-        Err(s) => {eprintln!("{}",s);return false;},
-    };
-    
-    //decide where to write `output`
-    if outfile.is_empty() {
-        //uses stdout, not stderr, to let it work for scripts
-        println!("{output}");
-    } else {
-        //write to outfile
-        match fs::write(&outfile, &output) {
-            Ok(_) => {},
-            Err(s) => {eprintln!("Could not write to output file: {s}");return false;},
+        Err(s) => {return s;},
+    };
+    match disassemble(&method) {
+        Ok(s) => {print!("{s}"); 0},
+        Err(s) => {eprintln!("{}", s); EXIT_RUNTIME},
+    }
+}
+
+// prints the metadata of a method. returns the exit status
+fn method_info(method_name: &str, local: bool, search: &SearchPath) -> i32 {
+    let method = match load_method(method_name, local, search, true) {
+        Ok(s) => s,
+        Err(s) => {return s;},
+    };
+    let meta = match read_metadata(&method) {
+        Ok(s) => s,
+        Err(s) => {eprintln!("{}", s); return EXIT_RUNTIME;},
+    };
+    println!("name: {}", meta.name.as_deref().unwrap_or(method_name));
+    let fields = [("version", &meta.version), ("author", &meta.author),
+        ("description", &meta.description), ("usage", &meta.usage)];
+    for (k, x) in fields {
+        if let Some(s) = x {
+            println!("{}: {}", k, s);
         }
     }
+    let (argmin, argmax) = (method[0x8] as usize, method[0x9] as usize);
+    println!("arguments: {} to {}", argmin, argmax);
+    for i in 0..argmax.max(meta.args.len()) {
+        let opt = match meta.args.get(i) {
+            Some(a) if a.default.is_some() => format!(" = {:?}", a.default.as_ref().unwrap()),
+            Some(a) if a.required => String::new(),
+            _ if i < argmin => String::new(),
+            _ => " (optional)".to_string(),
+        };
+        println!("  {}{}", meta.arg_name(i), opt);
+    }
+    if !meta.depends.is_empty() {
+        println!("uses: {}", meta.depends.join(", "));
+    }
+    0
+}
 
-    false
+// the dependency tree of a method, from the dictionary or a local file
+fn dependency_tree(method_name: &str, local: bool, search: &SearchPath) -> Result<DepTree, i32> {
+    let method = load_method(method_name, local, search, true)?;
+    let path = if local { None } else { search.locate(method_name).into_iter().next().map(|x| x.1) };
+    Ok(search.dependencies(method_name, &method, path))
 }
 
+// prints a method's dependency tree. returns the exit status
+fn method_dependencies_command(method_name: &str, local: bool, search: &SearchPath) -> i32 {
+    let tree = match dependency_tree(method_name, local, search) {
+        Ok(s) => s,
+        Err(s) => {return s;},
+    };
+    fn print_tree(tree: &DepTree, depth: usize) {
+        let note = match &tree.status {
+            DepStatus::Found(_) | DepStatus::Local => String::new(),
+            DepStatus::Missing => "  (missing)".to_string(),
+            DepStatus::Cycle => "  (cycle)".to_string(),
+            DepStatus::Invalid(s) => format!("  ({})", s),
+        };
+        println!("{}{}{}", "  ".repeat(depth), tree.name, note);
+        for x in tree.uses.iter() {
+            print_tree(x, depth + 1);
+        }
+    }
+    print_tree(&tree, 0);
+    if tree.problems().is_empty() { 0 } else { EXIT_IO }
+}
+
+// reports missing and cyclic dependencies. returns the exit status
+fn check_methods(names: &[String], search: &SearchPath) -> i32 {
+    let names = if names.is_empty() { search.names() } else { names.to_vec() };
+    let mut status = 0;
+    let mut reported: Vec<String> = Vec::new();
+    for name in names.iter() {
+        let tree = match dependency_tree(name, false, search) {
+            Ok(s) => s,
+            Err(s) => {status = s; continue;},
+        };
+        for (chain, problem) in tree.problems() {
+            let msg = match problem {
+                DepStatus::Missing => format!("{}: uses '{}', which is not in the dictionary", chain[chain.len()-2], chain[chain.len()-1]),
+                DepStatus::Cycle => format!("{}: cycle: {}", chain[0], chain.join(" -> ")),
+                DepStatus::Invalid(s) => format!("{}: {}", chain[chain.len()-1], s),
+                _ => {continue;},
+            };
+            //a problem deep in the tree is found again from each method that uses it
+            if !reported.contains(&msg) {
+                println!("{}", msg);
+                reported.push(msg);
+            }
+            status = EXIT_IO;
+        }
+    }
+    if status == 0 {
+        eprintln!("{} method{} checked, no problems found", names.len(), if names.len() == 1 { "" } else { "s" });
+    }
+    status
+}
+
+// formats each script in place, or reports unformatted scripts with --check.
+// returns the exit status
+fn format_files(files: &[String], check: bool) -> i32 {
+    if files.is_empty() {
+        let mut script = String::new();
+        if let Err(s) = std::io::stdin().read_to_string(&mut script) {
+            eprintln!("{s}");
+            return EXIT_IO;
+        }
+        let formatted = match parse_script(&script) {
+            Ok(s) => format_script(&s),
+            Err(s) => {eprintln!("<stdin>: {}", s); return EXIT_COMPILE;},
+        };
+        if check {
+            return (formatted != script) as i32;
+        }
+        print!("{formatted}");
+        return 0;
+    }
+    let mut status = 0;
+    for x in files {
+        let script = match fs::read_to_string(x) {
+            Ok(s) => s,
+            Err(s) => {eprintln!("{}: {}", x, s); status = EXIT_IO; continue;},
+        };
+        let formatted = match parse_script(&script) {
+            Ok(s) => format_script(&s),
+            Err(s) => {eprintln!("{}: {}", x, s); status = EXIT_COMPILE; continue;},
+        };
+        if formatted == script {
+            continue;
+        }
+        if check {
+            println!("{} is not formatted", x);
+            status = status.max(1);
+        } else if let Err(s) = fs::write(x, &formatted) {
+            eprintln!("{}: {}", x, s);
+            status = EXIT_IO;
+        }
+    }
+    status
+}
+
+// runs the interactive debugger on a script, reading commands from stdin.
+// returns the exit status
+fn debug_script(script_file: &str, infile: Option<String>, args: &[String], search: &Rc<SearchPath>) -> i32 {
+    let script = match fs::read_to_string(script_file) {
+        Ok(s) => s,
+        Err(s) => {eprintln!("{}: {}", script_file, s); return EXIT_IO;},
+    };
+    //stdin holds the debugger's commands, so input can only come from a file
+    let input = match infile {
+        None => String::new(),
+        Some(f) => match fs::read_to_string(&f) {
+            Ok(s) => s,
+            Err(s) => {eprintln!("{}: {}", f, s); return EXIT_IO;},
+        },
+    };
+    let cargs: Vec<&str> = args.iter().filter(|x| !x.is_empty()).map(|x| &x[..]).collect();
+    let mut debugger = match Debugger::new(&script, &input, &cargs) {
+        Ok(s) => s.with_search_path(search.clone()),
+        Err(s) => {eprintln!("{}: {}", script_file, s); return EXIT_COMPILE;},
+    };
+    let stdin = std::io::stdin();
+    match debugger.run(stdin.lock(), &mut std::io::stdout()) {
+        Ok(_) => 0,
+        Err(s) => {eprintln!("{s}"); EXIT_IO},
+    }
+}
 
+// reads and runs lines until :quit or end of input. returns the exit status
+fn run_repl(search: &Rc<SearchPath>) -> i32 {
+    let mut editor = match rustyline::DefaultEditor::new() {
+        Ok(s) => s,
+        Err(s) => {eprintln!("{s}"); return EXIT_IO;},
+    };
+    let history = dirs::home_dir().map(|x| x.join(".ciplang/repl_history"));
+    if let Some(h) = &history {
+        let _ = editor.load_history(h);
+    }
+    let mut repl = Repl::new().with_search_path(search.clone());
+    while !repl.quit {
+        let line = match editor.readline("cip> ") {
+            Ok(s) => s,
+            Err(rustyline::error::ReadlineError::Interrupted) => {continue;},
+            Err(_) => {break;},
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(&line);
+        match repl.eval(&line) {
+            Ok(s) if s.is_empty() => {},
+            Ok(s) => {println!("{s}");},
+            Err(s) => {eprintln!("{s}");},
+        }
+    }
+    if let Some(h) = &history {
+        if h.parent().is_some_and(|x| fs::create_dir_all(x).is_ok()) {
+            let _ = editor.save_history(h);
+        }
+    }
+    0
+}
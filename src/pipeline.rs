@@ -0,0 +1,216 @@
+use std::rc::Rc;
+use snailquote::unescape;
+use crate::*;
+use crate::dictionary::MethodName;
+use crate::interpret::{run_transform_within, Budget, Limits, MethodCache};
+use crate::transform::is_builtin_transform;
+
+// A pipeline is a chain of transforms written as on the command line, such as
+// `upper | shift<3> | team/vig<KEY>`. Each stage takes the previous one's output; built-in
+// transforms run directly, and dictionary methods run as with --binary, taking their
+// input and giving their output as a whole. The stages of a run share one budget, so the
+// instruction, time and output limits apply to the whole run rather than to each stage.
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct Stage {
+    pub name: String,
+    pub args: Vec<String>,
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct Pipeline {
+    pub stages: Vec<Stage>,
+}
+
+impl Pipeline {
+    // whether a method given on the command line is written as a pipeline rather than
+    // the name of a method. A lone built-in transform is a pipeline of one stage
+    pub fn is_pipeline(st: &str) -> bool {
+        st.contains('|') || st.contains('<') || is_builtin_transform(st.trim())
+    }
+
+    pub fn parse(st: &str) -> Result<Pipeline, CError> {
+        let mut stages = Vec::new();
+        for part in split_stages(st)? {
+            stages.push(parse_stage(part.trim())?);
+        }
+        Ok(Pipeline { stages })
+    }
+
+    // checks that every stage is a built-in transform or a method in the dictionary, so a
+    // misspelled stage is caught before any input is read
+    pub fn check(&self, cache: &MethodCache) -> Result<(), CError> {
+        for x in self.stages.iter() {
+            if !is_builtin_transform(&x.name) {
+                cache.get(&x.name)?;
+            }
+        }
+        Ok(())
+    }
+
+    // runs every stage on input, charging them all to budget
+    pub fn run(&self, input: &Value, cache: &Rc<MethodCache>, limits: Limits, budget: &mut Budget) -> Result<Value, CError> {
+        let mut value = input.clone();
+        for x in self.stages.iter() {
+            let args: Vec<&str> = x.args.iter().map(|a| &a[..]).collect();
+            value = run_transform_within(&value, &x.name, &args, cache, limits, budget, true)?;
+        }
+        Ok(value)
+    }
+}
+
+// splits a pipeline at the bars that aren't inside a stage's arguments
+fn split_stages(st: &str) -> Result<Vec<&str>, CError> {
+    let mut parts = Vec::new();
+    let (mut depth, mut in_str, mut escaped) = (0, false, false);
+    let mut start = 0;
+    for (i, c) in st.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' if in_str => {escaped = true;},
+            '"' => {in_str = !in_str;},
+            '<' if !in_str => {depth += 1;},
+            '>' if !in_str => {depth -= 1;},
+            '|' if !in_str && depth == 0 => {
+                parts.push(&st[start..i]);
+                start = i + 1;
+            },
+            _ => {},
+        }
+    }
+    if in_str {
+        return Err(CError::from_slice("Error: unterminated string in pipeline"));
+    }
+    if depth != 0 {
+        return Err(CError::from_slice("Error: unbalanced '<' in pipeline"));
+    }
+    parts.push(&st[start..]);
+    Ok(parts)
+}
+
+// a stage: a transform name, with arguments in <> if it takes any
+fn parse_stage(st: &str) -> Result<Stage, CError> {
+    if st.is_empty() {
+        return Err(CError::from_slice("Error: empty stage in pipeline"));
+    }
+    let (name, args) = match st.find('<') {
+        Some(n) => match st.strip_suffix('>') {
+            Some(s) => (st[..n].trim(), &s[n+1..]),
+            None => {return Err(CError::from(format!("Error: expected '>' at the end of '{}'", st)));},
+        },
+        None => (st, ""),
+    };
+    if !is_builtin_transform(name) {
+        MethodName::parse(name).map_err(|_| CError::from(format!("Error: '{}' is not a transform", name)))?;
+    }
+    let mut list = Vec::new();
+    if !args.trim().is_empty() {
+        for x in parse(args, ',') {
+            let x = x.trim();
+            //quoted arguments may hold commas and escapes, as in scripts
+            if x.starts_with('"') {
+                list.push(unescape(x).map_err(|e| CError::from(format!("Error: bad argument {}: {}", x, e)))?);
+            } else {
+                list.push(x.to_string());
+            }
+        }
+    }
+    Ok(Stage { name: name.to_string(), args: list })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::dictionary::SearchPath;
+
+    fn stage(name: &str, args: &[&str]) -> Stage {
+        Stage { name: name.to_string(), args: args.iter().map(|x| x.to_string()).collect() }
+    }
+
+    fn cache() -> Rc<MethodCache> {
+        Rc::new(MethodCache::new(Rc::new(SearchPath::default())))
+    }
+
+    #[test]
+    fn recognises_pipelines() {
+        assert!(Pipeline::is_pipeline("upper | lower"));
+        assert!(Pipeline::is_pipeline("shift<3>"));
+        assert!(Pipeline::is_pipeline("upper"));
+        assert!(!Pipeline::is_pipeline("team/caesar"));
+        assert!(!Pipeline::is_pipeline("caesar@2"));
+    }
+
+    #[test]
+    fn splits_stages_outside_arguments() {
+        assert_eq!(split_stages("upper | shift<3> |lower").unwrap(), vec!["upper ", " shift<3> ", "lower"]);
+        assert_eq!(split_stages("sub<\"a|b\", c> | upper").unwrap(), vec!["sub<\"a|b\", c> ", " upper"]);
+        assert_eq!(split_stages("sub<\"say \\\"|\\\"\"> | upper").unwrap(), vec!["sub<\"say \\\"|\\\"\"> ", " upper"]);
+        assert!(split_stages("sub<\"a|b> | upper").unwrap_err().to_string().contains("unterminated string"));
+        assert!(split_stages("shift<3 | upper").unwrap_err().to_string().contains("unbalanced"));
+    }
+
+    #[test]
+    fn parses_stages() {
+        assert_eq!(parse_stage("upper").unwrap(), stage("upper", &[]));
+        assert_eq!(parse_stage("shift <3, german>").unwrap(), stage("shift", &["3", "german"]));
+        assert_eq!(parse_stage("team/vig@2<KEY>").unwrap(), stage("team/vig@2", &["KEY"]));
+        assert_eq!(parse_stage("sub<\"a, b\", \"\\\"q\\\"\">").unwrap(), stage("sub", &["a, b", "\"q\""]));
+        assert_eq!(parse_stage("upper<>").unwrap(), stage("upper", &[]));
+        assert!(parse_stage("").unwrap_err().to_string().contains("empty stage"));
+        assert!(parse_stage("shift<3> x").unwrap_err().to_string().contains("expected '>'"));
+        assert!(parse_stage("no such").unwrap_err().to_string().contains("'no such' is not a transform"));
+    }
+
+    #[test]
+    fn parses_pipelines() {
+        let p = Pipeline::parse("upper | shift<1>|rc<3>").unwrap();
+        assert_eq!(p.stages, vec![stage("upper", &[]), stage("shift", &["1"]), stage("rc", &["3"])]);
+        assert!(Pipeline::parse("upper ||lower").is_err());
+    }
+
+    #[test]
+    fn runs_stages_in_order() {
+        let p = Pipeline::parse("upper | shift<1> | hex").unwrap();
+        let out = p.run(&Value(b"abz".to_vec()), &cache(), Limits::default(), &mut Budget::new()).unwrap();
+        assert_eq!(out, Value(b"424341".to_vec()));
+    }
+
+    #[test]
+    fn check_finds_missing_methods() {
+        assert!(Pipeline::parse("upper | lower").unwrap().check(&cache()).is_ok());
+        assert!(Pipeline::parse("upper | team/missing").unwrap().check(&cache()).is_err());
+    }
+
+    #[test]
+    fn stages_share_the_instruction_limit() {
+        let dir = std::env::temp_dir().join(format!("cipherlang-test-pipeline-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let script = "%argmin=0,argmax=0\nvar x\nload x from _stdin\napply upper<> to x\nwrite x to _stdout\n";
+        std::fs::write(dir.join("up.cpth"), crate::compile::convert_to_method(script).unwrap()).unwrap();
+        let cache = Rc::new(MethodCache::new(Rc::new(SearchPath::new(std::slice::from_ref(&dir)).unwrap())));
+        let input = Value(b"a".to_vec());
+        let limits = Limits { max_instructions: Some(5), ..Limits::default() };
+        //one stage fits in the limit, but two don't
+        let one = Pipeline::parse("up").unwrap().run(&input, &cache, limits, &mut Budget::new());
+        let two = Pipeline::parse("up | up").unwrap().run(&input, &cache, limits, &mut Budget::new());
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(one.unwrap(), Value(b"A".to_vec()));
+        assert_eq!(two.unwrap_err().limit_exceeded(), Some(Limit::Instructions));
+    }
+
+    #[test]
+    fn stages_share_the_time_limit() {
+        let p = Pipeline::parse("upper | lower").unwrap();
+        let limits = Limits { timeout: Some(Duration::from_millis(1)), ..Limits::default() };
+        let mut budget = Budget::new();
+        p.run(&Value(b"a".to_vec()), &cache(), limits, &mut budget).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        //the clock started with the first run, so the next line is already over the limit
+        let e = p.run(&Value(b"a".to_vec()), &cache(), limits, &mut budget).unwrap_err();
+        assert_eq!(e.limit_exceeded(), Some(Limit::Timeout));
+    }
+}
@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+use std::fs;
+use std::rc::Rc;
+use crate::*;
+
+use crate::ast::*;
+use crate::compile::*;
+use crate::dictionary::SearchPath;
+use crate::format::*;
+use crate::interpret::*;
+use crate::trace::*;
+use crate::transform::BUILTIN_TRANSFORMS;
+
+// Interactive session. Declarations are remembered, and each statement is compiled
+// together with them into a small method, which runs with the current variable values.
+
+pub const REPL_HELP: &str = "\
+Statements (var, const, load, apply, write) run immediately. An expression such as
+x $cat k prints its value.
+:vars              show the variables and their values
+:consts            show the constants
+:load FILE         run the statements of a script
+:transforms        list the available transforms
+:help              show this message
+:quit              leave the repl";
+
+#[derive(Default)]
+pub struct Repl {
+    decls: Vec<String>, //var and const declarations, in order
+    vars: Vec<String>,
+    consts: Vec<(String, String)>,
+    values: HashMap<String, Value>,
+    last_tr: Value,
+    last_op: Value,
+    search: Option<Rc<SearchPath>>, //where dictionary methods are found, if not the default path
+    pub quit: bool,
+}
+
+impl Repl {
+    pub fn new() -> Repl {
+        Repl::default()
+    }
+
+    pub fn with_search_path(mut self, search: Rc<SearchPath>) -> Repl {
+        self.search = Some(search);
+        self
+    }
+
+    // evaluates a line of input, returning what should be printed
+    pub fn eval(&mut self, line: &str) -> Result<String, CError> {
+        let line = line.trim();
+        if let Some(cmd) = line.strip_prefix(':') {
+            return self.command(cmd);
+        }
+        let script = match parse_script(line) {
+            Ok(s) => s,
+            //anything that isn't a statement may be an expression
+            Err(e) => match parse_script(&format!("write {} to _stdout", line)) {
+                Ok(s) => {
+                    let out = self.run(&format_statement(&s.statements[0]))?;
+                    return Ok(format!("{:?}", out));
+                },
+                Err(_) => {return Err(e);},
+            },
+        };
+        self.eval_script(&script)
+    }
+
+    fn eval_script(&mut self, script: &Script) -> Result<String, CError> {
+        let mut out: Vec<String> = Vec::new();
+        for st in script.statements.iter() {
+            let text = format_statement(&Statement { comment: None, ..st.clone() });
+            match &st.kind {
+                StatementKind::Var(name) => {
+                    self.declare(&text)?;
+                    self.vars.push(name.clone());
+                },
+                StatementKind::Const(name, value) => {
+                    self.declare(&text)?;
+                    self.consts.push((name.clone(), value.clone()));
+                },
+                StatementKind::Load(target, _) | StatementKind::Apply(_, target) => {
+                    self.run(&text)?;
+                    let value = self.values.get(target).cloned().unwrap_or_default();
+                    out.push(format!("{} = {:?}", target, value));
+                },
+                StatementKind::Write(_, _) => {
+                    let s = self.run(&text)?;
+                    if !s.is_empty() {
+                        out.push(s.to_string_lossy());
+                    }
+                },
+                StatementKind::Directive(_) | StatementKind::Arg(_, _) => {
+                    return Err(CError::from_slice("Error: directives can't be used in the repl"));
+                },
+                StatementKind::Comment(_) => {},
+            }
+        }
+        Ok(out.join("\n"))
+    }
+
+    fn command(&mut self, cmd: &str) -> Result<String, CError> {
+        let (name, rest) = match cmd.split_once(char::is_whitespace) {
+            Some((a, b)) => (a, b.trim()),
+            None => (cmd, ""),
+        };
+        match name {
+            "vars" => {
+                let v: Vec<String> = self.vars.iter().map(|x| {
+                    format!("{} = {:?}", x, self.values.get(x).cloned().unwrap_or_default())
+                }).collect();
+                Ok(v.join("\n"))
+            },
+            "consts" => {
+                let v: Vec<String> = self.consts.iter().map(|(k, v)| format!("{} = {:?}", k, v)).collect();
+                Ok(v.join("\n"))
+            },
+            "load" if !rest.is_empty() => {
+                let text = match fs::read_to_string(rest) {
+                    Ok(s) => s,
+                    Err(s) => {return Err(CError::from(format!("{}: {}", rest, s)));},
+                };
+                //directives only matter to compiled methods, so they are skipped
+                let mut script = parse_script(&text)?;
+                script.statements.retain(|x| !matches!(x.kind, StatementKind::Directive(_) | StatementKind::Arg(_, _)));
+                self.eval_script(&script)
+            },
+            "transforms" => {
+                let mut v: Vec<String> = BUILTIN_TRANSFORMS.iter().map(|(n, a, e, d)| format!("{}<{}>  {} ({} input)", n, a, d, e.name())).collect();
+                let names = match &self.search {
+                    Some(s) => s.names(),
+                    None => dictionary_methods(),
+                };
+                for x in names {
+                    v.push(format!("{}  (dictionary)", x));
+                }
+                Ok(v.join("\n"))
+            },
+            "help" => Ok(REPL_HELP.to_string()),
+            "quit" | "q" => {
+                self.quit = true;
+                Ok(String::new())
+            },
+            _ => Err(CError::from(format!("Unknown command ':{}'. Type :help for a list of commands.", cmd))),
+        }
+    }
+
+    // adds a declaration once it is known to compile
+    fn declare(&mut self, text: &str) -> Result<(), CError> {
+        self.decls.push(text.to_string());
+        if let Err(e) = convert_to_method(&self.program("")) {
+            self.decls.pop();
+            return Err(without_position(e));
+        }
+        Ok(())
+    }
+
+    fn program(&self, statement: &str) -> String {
+        format!("%argmin=0,argmax=0\n{}\n{}\n", self.decls.join("\n"), statement)
+    }
+
+    // runs one statement, keeping the variables it changes. Returns its output without
+    // the final newline
+    fn run(&mut self, statement: &str) -> Result<Value, CError> {
+        let (method, debug) = convert_to_method_debug(&self.program(statement)).map_err(without_position)?;
+        let mut vm = Vm::new(method, b"", &[], 0)?;
+        if let Some(s) = &self.search {
+            vm = vm.with_search_path(s.clone());
+        }
+        {
+            let frame = &mut vm.frames[0];
+            for (i, name) in debug.vars.iter().enumerate() {
+                if let (Some(v), Some(x)) = (frame.vars.get_mut(i), self.values.get(name)) {
+                    *v = x.clone();
+                }
+            }
+            frame.last_tr = self.last_tr.clone();
+            frame.last_op = self.last_op.clone();
+        }
+        let mut output = vm.run(&mut NullTracer)?;
+        if output.0.ends_with(b"\n") {
+            output.0.pop();
+        }
+        let frame = &vm.frames[0];
+        for (name, v) in debug.vars.iter().zip(frame.vars.iter()) {
+            self.values.insert(name.clone(), v.clone());
+        }
+        self.last_tr = frame.last_tr.clone();
+        self.last_op = frame.last_op.clone();
+        Ok(output)
+    }
+}
+
+// positions refer to the generated program, not to anything that was typed
+fn without_position(e: CError) -> CError {
+    let msg = e.to_string();
+    match (e.position(), msg.split_once(": ")) {
+        (Some(_), Some((_, rest))) => CError::from_slice(rest),
+        _ => e,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_carries_between_lines() {
+        let mut repl = Repl::new();
+        assert_eq!(repl.eval("var x").unwrap(), "");
+        assert_eq!(repl.eval("const k \"lo\"").unwrap(), "");
+        assert_eq!(repl.eval("load x from k").unwrap(), "x = \"lo\"");
+        assert_eq!(repl.eval("apply upper<> to x").unwrap(), "x = \"LO\"");
+        assert_eq!(repl.eval("x $cat k").unwrap(), "\"LOlo\"");
+        //the last transform and operation survive too
+        assert_eq!(repl.eval("_o $cat _").unwrap(), "\"LOloLO\"");
+        assert_eq!(repl.eval("write x to _stdout").unwrap(), "LO");
+        assert_eq!(repl.eval(":vars").unwrap(), "x = \"LO\"");
+        assert_eq!(repl.eval(":consts").unwrap(), "k = \"lo\"");
+    }
+
+    #[test]
+    fn failed_lines_leave_the_session_unchanged() {
+        let mut repl = Repl::new();
+        repl.eval("var x").unwrap();
+        assert!(repl.eval("var x").is_err());
+        let e = repl.eval("load y from x").unwrap_err();
+        assert!(e.position().is_none(), "{}", e);
+        assert!(repl.eval("%argmin=1").is_err());
+        assert!(repl.eval(":frobnicate").unwrap_err().to_string().contains("Unknown command"));
+        repl.eval("var y").unwrap();
+        assert_eq!(repl.eval(":vars").unwrap(), "x = \"\"\ny = \"\"");
+    }
+
+    #[test]
+    fn quit_and_load() {
+        let mut repl = Repl::new();
+        let path = std::env::temp_dir().join(format!("repl-load-{}.cip", std::process::id()));
+        fs::write(&path, "%argmin=0,argmax=0\nvar w\nconst c \"hi\"\nload w from c\n").unwrap();
+        assert_eq!(repl.eval(&format!(":load {}", path.display())).unwrap(), "w = \"hi\"");
+        fs::remove_file(&path).unwrap();
+        assert!(!repl.quit);
+        repl.eval(":quit").unwrap();
+        assert!(repl.quit);
+    }
+}
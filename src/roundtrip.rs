@@ -0,0 +1,394 @@
+use std::rc::Rc;
+use crate::*;
+use crate::interpret::{run_transform, Limits, MethodCache};
+use crate::transform::{transform_encoding, Encoding};
+
+// Round-trip checks of transform pairs, such as rc and rc_dec: random inputs and arguments
+// are run through the encoder and then the decoder, which must give back the input. A
+// failing case is shrunk to a smaller one that still fails before it is reported.
+// Dictionary methods are run as with --binary, so each takes its input and gives its
+// output as a whole.
+
+// how many shrinking steps are tried before the smallest failure so far is reported
+const MAX_SHRINK_STEPS: usize = 2000;
+
+// the kinds of input a round trip can be checked on
+pub const INPUT_KINDS: [(&str, &str); 4] = [
+    ("text", "letters from several alphabets, digits, punctuation and spaces"),
+    ("ascii", "printable ASCII"),
+    ("letters", "a-z and A-Z"),
+    ("bytes", "any bytes"),
+];
+
+// a few letters from each alphabet, so that Unicode handling is exercised
+const TEXT_CHARS: &str = "abcxyzABCXYZäöüßẞабвёяАБЁЯαβσςωΑΣΩ0123456789 .,;!?'-";
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum InputKind {
+    Text,
+    Ascii,
+    Letters,
+    Bytes,
+}
+
+impl InputKind {
+    pub fn from_name(name: &str) -> Option<InputKind> {
+        match name {
+            "text" => Some(InputKind::Text),
+            "ascii" => Some(InputKind::Ascii),
+            "letters" => Some(InputKind::Letters),
+            "bytes" => Some(InputKind::Bytes),
+            _ => None,
+        }
+    }
+
+    // the input to check a transform on: ASCII for transforms that only accept it, and
+    // otherwise text, which even byte transforms such as xor accept
+    pub fn for_transform(name: &str) -> InputKind {
+        match transform_encoding(name) {
+            Some(Encoding::Ascii) => InputKind::Ascii,
+            _ => InputKind::Text,
+        }
+    }
+
+    fn generate(&self, rng: &mut fastrand::Rng, len: usize) -> Vec<u8> {
+        match self {
+            InputKind::Bytes => (0..len).map(|_| rng.u8(..)).collect(),
+            InputKind::Ascii => (0..len).map(|_| rng.u8(0x20..0x7f)).collect(),
+            InputKind::Letters => random_letters(rng, len).into_bytes(),
+            InputKind::Text => {
+                let chars: Vec<char> = TEXT_CHARS.chars().collect();
+                (0..len).map(|_| chars[rng.usize(..chars.len())]).collect::<String>().into_bytes()
+            },
+        }
+    }
+
+    // the simplest unit of input, which shrinking replaces others with
+    fn simplest(&self) -> &'static [u8] {
+        match self {
+            InputKind::Bytes => &[0],
+            _ => b"a",
+        }
+    }
+}
+
+fn random_letters(rng: &mut fastrand::Rng, len: usize) -> String {
+    (0..len).map(|_| if rng.bool() { rng.lowercase() } else { rng.uppercase() }).collect()
+}
+
+// how an argument of the pair is chosen for each case
+#[derive(Debug,Clone,PartialEq)]
+pub enum ArgGen {
+    Fixed(String),
+    Int(i64, i64), //a number in the range, inclusive
+    Letters(usize, usize), //a string of letters with a length in the range, inclusive
+}
+
+impl ArgGen {
+    // "int:A..B", "letters:A..B", or anything else for the argument as it is
+    pub fn parse(st: &str) -> Result<ArgGen, CError> {
+        let range = |s: &str| -> Result<(i64, i64), CError> {
+            let bad = || CError::from(format!("Error: invalid range '{}' in argument '{}'", s, st));
+            let (a, b) = s.split_once("..").ok_or_else(bad)?;
+            let (a, b) = (a.trim().parse::<i64>().map_err(|_| bad())?, b.trim().parse::<i64>().map_err(|_| bad())?);
+            if a > b {
+                return Err(bad());
+            }
+            Ok((a, b))
+        };
+        if let Some(s) = st.strip_prefix("int:") {
+            let (a, b) = range(s)?;
+            return Ok(ArgGen::Int(a, b));
+        }
+        if let Some(s) = st.strip_prefix("letters:") {
+            let (a, b) = range(s)?;
+            if a < 0 {
+                return Err(CError::from(format!("Error: negative length in argument '{}'", st)));
+            }
+            return Ok(ArgGen::Letters(a as usize, b as usize));
+        }
+        Ok(ArgGen::Fixed(st.to_string()))
+    }
+
+    fn generate(&self, rng: &mut fastrand::Rng) -> String {
+        match self {
+            ArgGen::Fixed(s) => s.clone(),
+            ArgGen::Int(a, b) => rng.i64(*a..=*b).to_string(),
+            ArgGen::Letters(a, b) => {
+                let len = rng.usize(*a..=*b);
+                random_letters(rng, len)
+            },
+        }
+    }
+
+    // simpler values of an argument that are still in range, simplest first
+    fn shrink(&self, value: &str) -> Vec<String> {
+        match self {
+            ArgGen::Fixed(_) => Vec::new(),
+            ArgGen::Int(a, b) => {
+                let n: i64 = match value.parse() {
+                    Ok(s) => s,
+                    Err(_) => {return Vec::new();},
+                };
+                //towards zero, or the end of the range nearest it
+                let target = 0.clamp(*a, *b);
+                let mut out = Vec::new();
+                for x in [target, (n + target) / 2, n - (n - target).signum()] {
+                    if x != n && !out.contains(&x.to_string()) {
+                        out.push(x.to_string());
+                    }
+                }
+                out
+            },
+            ArgGen::Letters(a, _) => {
+                let chars: Vec<char> = value.chars().collect();
+                let mut out = Vec::new();
+                if chars.len() > *a {
+                    for i in 0..chars.len() {
+                        out.push(chars[..i].iter().chain(chars[i+1..].iter()).collect());
+                    }
+                }
+                for i in 0..chars.len() {
+                    if chars[i] != 'a' {
+                        let mut c = chars.clone();
+                        c[i] = 'a';
+                        out.push(c.into_iter().collect());
+                    }
+                }
+                out
+            },
+        }
+    }
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct RoundTrip {
+    pub encode: String,
+    pub decode: String,
+    pub args: Vec<ArgGen>, //given to both transforms
+    pub input: InputKind,
+    pub min_len: usize,
+    pub max_len: usize,
+    pub cases: usize,
+    pub seed: u64,
+}
+
+impl RoundTrip {
+    // a check of a pair with default settings: 100 cases of up to 32 characters of the
+    // input suited to the encoder, and a random seed
+    pub fn new(encode: &str, decode: &str) -> RoundTrip {
+        RoundTrip {
+            encode: encode.to_string(),
+            decode: decode.to_string(),
+            args: Vec::new(),
+            input: InputKind::for_transform(encode),
+            min_len: 0,
+            max_len: 32,
+            cases: 100,
+            seed: fastrand::u64(..),
+        }
+    }
+
+    pub fn name(&self) -> String {
+        format!("round trip {} -> {}", self.encode, self.decode)
+    }
+}
+
+// an input and arguments for which the round trip fails
+#[derive(Debug,Clone,PartialEq)]
+pub struct Counterexample {
+    pub input: Value,
+    pub args: Vec<String>,
+    pub problem: String,
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct RoundTripReport {
+    pub cases: usize, //run before the first failure, or all of them
+    pub seed: u64,
+    pub failure: Option<Counterexample>, //shrunk
+    pub original: Option<Counterexample>, //as first found
+}
+
+// runs one case, returning what went wrong if it fails
+fn try_case(rt: &RoundTrip, input: &Value, args: &[String], cache: &Rc<MethodCache>, limits: Limits) -> Option<String> {
+    let args: Vec<&str> = args.iter().map(|x| &x[..]).collect();
+    let encoded = match run_transform(input, &rt.encode, &args, cache, limits, true) {
+        Ok(s) => s,
+        Err(e) => {return Some(format!("{} failed: {}", rt.encode, e));},
+    };
+    match run_transform(&encoded, &rt.decode, &args, cache, limits, true) {
+        Ok(s) if s == *input => None,
+        Ok(s) => Some(format!("{} gave {:?}, which {} decoded to {:?}", rt.encode, encoded, rt.decode, s)),
+        Err(e) => Some(format!("{} gave {:?}, which {} failed on: {}", rt.encode, encoded, rt.decode, e)),
+    }
+}
+
+// the units an input is shrunk by: characters of text, or bytes
+fn units(kind: InputKind, input: &Value) -> Vec<Vec<u8>> {
+    match (kind, input.as_text().ok()) {
+        (InputKind::Bytes, _) | (_, None) => input.as_bytes().iter().map(|x| vec![*x]).collect(),
+        (_, Some(s)) => s.chars().map(|c| c.to_string().into_bytes()).collect(),
+    }
+}
+
+// smaller variants of a failing case, the most promising first: halves of the input,
+// the input without one unit, the input with one unit simplified, then simpler arguments
+fn shrink_candidates(rt: &RoundTrip, input: &Value, args: &[String]) -> Vec<(Value, Vec<String>)> {
+    let parts = units(rt.input, input);
+    let join = |x: &[Vec<u8>]| Value(x.concat());
+    let mut out: Vec<(Value, Vec<String>)> = Vec::new();
+    let half = parts.len() / 2;
+    if half >= rt.min_len.max(1) {
+        out.push((join(&parts[..half]), args.to_vec()));
+        out.push((join(&parts[half..]), args.to_vec()));
+    }
+    if parts.len() > rt.min_len {
+        for i in 0..parts.len() {
+            out.push((join(&[&parts[..i], &parts[i+1..]].concat()), args.to_vec()));
+        }
+    }
+    let simplest = rt.input.simplest().to_vec();
+    for i in 0..parts.len() {
+        if parts[i] != simplest {
+            let mut p = parts.clone();
+            p[i] = simplest.clone();
+            out.push((join(&p), args.to_vec()));
+        }
+    }
+    for (i, gen) in rt.args.iter().enumerate() {
+        for x in gen.shrink(&args[i]) {
+            let mut a = args.to_vec();
+            a[i] = x;
+            out.push((input.clone(), a));
+        }
+    }
+    out
+}
+
+// checks that the decoder undoes the encoder on random cases. Dictionary methods are found
+// through the cache, and each transform runs under limits
+pub fn check_round_trip(rt: &RoundTrip, cache: &Rc<MethodCache>, limits: Limits) -> RoundTripReport {
+    let mut rng = fastrand::Rng::with_seed(rt.seed);
+    for n in 0..rt.cases {
+        //lengths grow over the run, so early failures are already small
+        let max = (rt.max_len * (n + 1)).div_ceil(rt.cases.max(1)).max(rt.min_len);
+        let len = rng.usize(rt.min_len..=max);
+        let input = Value(rt.input.generate(&mut rng, len));
+        let args: Vec<String> = rt.args.iter().map(|x| x.generate(&mut rng)).collect();
+        let problem = match try_case(rt, &input, &args, cache, limits) {
+            Some(s) => s,
+            None => {continue;},
+        };
+        let original = Counterexample { input, args, problem };
+        let mut smallest = original.clone();
+        let mut steps = 0;
+        'shrink: while steps < MAX_SHRINK_STEPS {
+            for (input, args) in shrink_candidates(rt, &smallest.input, &smallest.args) {
+                steps += 1;
+                if let Some(problem) = try_case(rt, &input, &args, cache, limits) {
+                    smallest = Counterexample { input, args, problem };
+                    continue 'shrink;
+                }
+                if steps >= MAX_SHRINK_STEPS {
+                    break;
+                }
+            }
+            break;
+        }
+        return RoundTripReport { cases: n + 1, seed: rt.seed, failure: Some(smallest), original: Some(original) };
+    }
+    RoundTripReport { cases: rt.cases, seed: rt.seed, failure: None, original: None }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dictionary::SearchPath;
+
+    fn check(rt: &RoundTrip) -> RoundTripReport {
+        check_round_trip(rt, &Rc::new(MethodCache::new(Rc::new(SearchPath::default()))), Limits::default())
+    }
+
+    fn pair(encode: &str, decode: &str, args: &[&str], seed: u64) -> RoundTrip {
+        RoundTrip {
+            args: args.iter().map(|x| ArgGen::parse(x).unwrap()).collect(),
+            seed,
+            ..RoundTrip::new(encode, decode)
+        }
+    }
+
+    #[test]
+    fn passing_pairs_run_every_case() {
+        let report = check(&pair("rc", "rc_dec", &["int:2..10"], 7));
+        assert_eq!(report.cases, 100);
+        assert_eq!(report.failure, None);
+        assert_eq!(report.original, None);
+    }
+
+    #[test]
+    fn shrinks_input_to_one_unit() {
+        let rt = RoundTrip { input: InputKind::Letters, ..pair("upper", "lower", &[], 3) };
+        let report = check(&rt);
+        let failure = report.failure.unwrap();
+        //"a" survives upper and lower, so the smallest failure is one capital letter
+        let text = failure.input.as_text().unwrap().to_string();
+        assert_eq!(text.chars().count(), 1);
+        assert!(text.chars().all(|c| c.is_ascii_uppercase()), "{:?}", text);
+        assert!(report.original.unwrap().input.len() >= failure.input.len());
+    }
+
+    #[test]
+    fn shrinks_arguments_towards_zero() {
+        //shifting twice by 13 happens to undo itself, but no other distance does
+        let report = check(&pair("shift", "shift", &["int:1..25"], 11));
+        let failure = report.failure.unwrap();
+        assert_eq!(failure.input, Value(b"a".to_vec()));
+        assert_eq!(failure.args, vec!["1"]);
+        assert_eq!(failure.problem, "shift gave \"b\", which shift decoded to \"c\"");
+    }
+
+    #[test]
+    fn respects_the_minimum_length() {
+        let rt = RoundTrip { min_len: 3, input: InputKind::Letters, ..pair("upper", "lower", &[], 5) };
+        let failure = check(&rt).failure.unwrap();
+        assert_eq!(failure.input.len(), 3);
+    }
+
+    #[test]
+    fn a_seed_reproduces_a_run() {
+        let rt = pair("shift", "shift", &["int:1..25", "latin"], 99);
+        assert_eq!(check(&rt), check(&rt));
+    }
+
+    #[test]
+    fn errors_are_failures() {
+        //hex_dec can't read most of what upper gives it
+        let report = check(&pair("upper", "hex_dec", &[], 1));
+        assert!(report.failure.unwrap().problem.contains("hex_dec failed on"));
+    }
+
+    #[test]
+    fn parses_argument_generators() {
+        assert_eq!(ArgGen::parse("int:-3..3").unwrap(), ArgGen::Int(-3, 3));
+        assert_eq!(ArgGen::parse("letters:0..4").unwrap(), ArgGen::Letters(0, 4));
+        assert_eq!(ArgGen::parse("german").unwrap(), ArgGen::Fixed("german".to_string()));
+        assert!(ArgGen::parse("int:3..1").is_err());
+        assert!(ArgGen::parse("int:a..b").is_err());
+        assert!(ArgGen::parse("letters:-1..2").is_err());
+    }
+
+    #[test]
+    fn generated_arguments_stay_in_range() {
+        let mut rng = fastrand::Rng::with_seed(1);
+        for _ in 0..200 {
+            let n: i64 = ArgGen::Int(-2, 5).generate(&mut rng).parse().unwrap();
+            assert!((-2..=5).contains(&n));
+            let s = ArgGen::Letters(1, 3).generate(&mut rng);
+            assert!((1..=3).contains(&s.len()) && s.chars().all(|c| c.is_ascii_alphabetic()));
+        }
+        assert_eq!(ArgGen::Int(-10, 10).shrink("8"), vec!["0", "4", "7"]);
+        assert_eq!(ArgGen::Int(2, 10).shrink("2"), Vec::<String>::new());
+        assert_eq!(ArgGen::Letters(1, 3).shrink("ab"), vec!["b", "a", "aa"]);
+    }
+}
@@ -0,0 +1,589 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use crate::*;
+use crate::compile::convert_to_method;
+use crate::dictionary::SearchPath;
+use crate::interpret::{Limits, MethodCache, Vm};
+use crate::roundtrip::*;
+use crate::trace::NullTracer;
+
+// Tests for methods are kept in .ciptest files, TOML documents naming the method under
+// test and listing cases, each with an input, arguments, and the output or error expected:
+//
+//   script = "caesar.cip"   # or method = "team/caesar" for a dictionary method
+//
+//   [[case]]
+//   name = "shifts letters"
+//   args = ["3"]
+//   input = "abc"
+//   output = "def\n"
+//
+// Without script or method, the script is the test file's name with a .cip extension.
+// Pairs of transforms that undo each other are checked on random inputs with
+//
+//   [[roundtrip]]
+//   encode = "rc"
+//   decode = "rc_dec"
+//   args = ["int:2..10"]
+
+pub const TEST_EXTENSION: &str = "ciptest";
+
+// how long a case may run before it fails, unless the file sets timeout
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum MethodRef {
+    Script(PathBuf), //a script or method file
+    Dictionary(String),
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum Expect {
+    Output(Value),
+    Error(String), //the error message must contain this
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct TestCase {
+    pub name: String,
+    pub input: Value,
+    pub args: Vec<String>,
+    pub binary: bool,
+    pub expect: Expect,
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct TestFile {
+    pub path: PathBuf,
+    pub method: MethodRef,
+    pub timeout: Duration,
+    pub cases: Vec<TestCase>,
+    pub roundtrips: Vec<RoundTrip>,
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum Outcome {
+    Passed,
+    Failed(String), //what went wrong, with a diff for unexpected output
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct CaseResult {
+    pub name: String,
+    pub outcome: Outcome,
+    pub time: Duration,
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct FileResult {
+    pub path: PathBuf,
+    pub cases: Vec<CaseResult>,
+}
+
+impl FileResult {
+    pub fn failures(&self) -> usize {
+        self.cases.iter().filter(|x| x.outcome != Outcome::Passed).count()
+    }
+}
+
+// a string, or the bytes given in hex by the same key with _hex appended
+fn value_field(table: &toml::Table, key: &str, file: &str) -> Result<Option<Value>, CError> {
+    if let Some(x) = table.get(key) {
+        return match x.as_str() {
+            Some(s) => Ok(Some(Value::from(s))),
+            None => Err(CError::from(format!("Error: {}: {} must be a string", file, key))),
+        };
+    }
+    match table.get(&format!("{}_hex", key)).map(|x| x.as_str()) {
+        Some(Some(s)) => match transform::transform_hex_decode(s.as_bytes()) {
+            Ok(v) => Ok(Some(Value(v))),
+            Err(e) => Err(CError::from(format!("{}: {}_hex: {}", file, key, e))),
+        },
+        Some(None) => Err(CError::from(format!("Error: {}: {}_hex must be a string", file, key))),
+        None => Ok(None),
+    }
+}
+
+impl TestFile {
+    pub fn read(path: &Path) -> Result<TestFile, CError> {
+        TestFile::parse(path, &fs::read_to_string(path)?)
+    }
+
+    pub fn parse(path: &Path, text: &str) -> Result<TestFile, CError> {
+        let file = path.display().to_string();
+        let err = |s: &str| CError::from(format!("Error: {}: {}", file, s));
+        let doc = match text.parse::<toml::Table>() {
+            Ok(s) => s,
+            Err(e) => {return Err(err(e.message()));},
+        };
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let method = match (doc.get("script"), doc.get("method")) {
+            (Some(_), Some(_)) => {return Err(err("give either script or method, not both"));},
+            (Some(x), None) => MethodRef::Script(dir.join(x.as_str().ok_or(err("script must be a string"))?)),
+            (None, Some(x)) => MethodRef::Dictionary(x.as_str().ok_or(err("method must be a string"))?.to_string()),
+            (None, None) => MethodRef::Script(path.with_extension("cip")),
+        };
+        let timeout = match doc.get("timeout") {
+            Some(x) => match x.as_float().or(x.as_integer().map(|i| i as f64)) {
+                Some(t) if t.is_finite() && t > 0.0 => Duration::from_secs_f64(t),
+                _ => {return Err(err("timeout must be a positive number of seconds"));},
+            },
+            None => DEFAULT_TIMEOUT,
+        };
+        let mut cases = Vec::new();
+        let list = match doc.get("case") {
+            Some(toml::Value::Array(s)) => &s[..],
+            Some(_) => {return Err(err("case must be an array of tables, written [[case]]"));},
+            None => &[],
+        };
+        for (i, x) in list.iter().enumerate() {
+            let table = x.as_table().ok_or(err("case must be an array of tables, written [[case]]"))?;
+            let name = match table.get("name") {
+                Some(s) => s.as_str().ok_or(err("a case's name must be a string"))?.to_string(),
+                None => format!("case {}", i + 1),
+            };
+            let at = format!("{} ({})", file, name);
+            let args = match table.get("args") {
+                Some(toml::Value::Array(a)) => a.iter().map(|x| match x {
+                    toml::Value::String(s) => Ok(s.clone()),
+                    toml::Value::Integer(n) => Ok(n.to_string()),
+                    _ => Err(err("args must be a list of strings")),
+                }).collect::<Result<Vec<String>, CError>>()?,
+                Some(_) => {return Err(err("args must be a list of strings"));},
+                None => Vec::new(),
+            };
+            let expect = match (value_field(table, "output", &at)?, table.get("error")) {
+                (Some(_), Some(_)) => {return Err(CError::from(format!("Error: {}: give either output or error, not both", at)));},
+                (Some(v), None) => Expect::Output(v),
+                (None, Some(e)) => Expect::Error(e.as_str().ok_or(err("error must be a string"))?.to_string()),
+                (None, None) => {return Err(CError::from(format!("Error: {}: expected output or error is missing", at)));},
+            };
+            cases.push(TestCase {
+                name,
+                input: value_field(table, "input", &at)?.unwrap_or_default(),
+                args,
+                binary: table.get("binary").and_then(|x| x.as_bool()).unwrap_or(false),
+                expect,
+            });
+        }
+        let list = match doc.get("roundtrip") {
+            Some(toml::Value::Array(s)) => &s[..],
+            Some(_) => {return Err(err("roundtrip must be an array of tables, written [[roundtrip]]"));},
+            None => &[],
+        };
+        let mut roundtrips = Vec::new();
+        for x in list.iter() {
+            let table = x.as_table().ok_or(err("roundtrip must be an array of tables, written [[roundtrip]]"))?;
+            let name = |key: &str| match table.get(key).map(|x| x.as_str()) {
+                Some(Some(s)) => Ok(s.to_string()),
+                _ => Err(err(&format!("a round trip's {} must be the name of a transform", key))),
+            };
+            let mut rt = RoundTrip::new(&name("encode")?, &name("decode")?);
+            let count = |key: &str| match table.get(key).map(|x| x.as_integer()) {
+                Some(Some(n)) if n >= 0 => Ok(Some(n as usize)),
+                Some(_) => Err(err(&format!("{} must be a number", key))),
+                None => Ok(None),
+            };
+            rt.min_len = count("min_len")?.unwrap_or(rt.min_len);
+            rt.max_len = count("max_len")?.unwrap_or(rt.max_len).max(rt.min_len);
+            rt.cases = count("cases")?.unwrap_or(rt.cases);
+            rt.seed = count("seed")?.map(|x| x as u64).unwrap_or(rt.seed);
+            if let Some(x) = table.get("input") {
+                let names: Vec<&str> = INPUT_KINDS.iter().map(|x| x.0).collect();
+                rt.input = x.as_str().and_then(InputKind::from_name)
+                    .ok_or(err(&format!("a round trip's input must be one of {}", names.join(", "))))?;
+            }
+            rt.args = match table.get("args") {
+                Some(toml::Value::Array(a)) => a.iter().map(|x| match x {
+                    toml::Value::String(s) => ArgGen::parse(s),
+                    toml::Value::Integer(n) => Ok(ArgGen::Fixed(n.to_string())),
+                    _ => Err(err("args must be a list of strings")),
+                }).collect::<Result<Vec<ArgGen>, CError>>()?,
+                Some(_) => {return Err(err("args must be a list of strings"));},
+                None => Vec::new(),
+            };
+            roundtrips.push(rt);
+        }
+        Ok(TestFile { path: path.to_path_buf(), method, timeout, cases, roundtrips })
+    }
+
+    // the method under test, compiling it if it is a script
+    pub fn load_method(&self, search: &SearchPath) -> Result<Vec<u8>, CError> {
+        match &self.method {
+            MethodRef::Dictionary(s) => search.load(s),
+            MethodRef::Script(p) => {
+                let bytes = fs::read(p).map_err(|e| CError::from(format!("Error: {}: {}", p.display(), e)))?;
+                if bytes.starts_with(b"CPTH") {
+                    return Ok(bytes);
+                }
+                convert_to_method(&String::from_utf8(bytes)?).map_err(|e| CError::from(format!("{}: {}", p.display(), e)))
+            },
+        }
+    }
+
+    // runs the cases whose names contain filter, or all of them
+    pub fn run(&self, search: &Rc<SearchPath>, filter: Option<&str>) -> FileResult {
+        let cases: Vec<&TestCase> = self.cases.iter().filter(|x| filter.is_none_or(|f| x.name.contains(f))).collect();
+        let mut results: Vec<CaseResult> = match self.load_method(search) {
+            _ if cases.is_empty() => Vec::new(),
+            Ok(m) => cases.iter().map(|x| self.run_case(&m, x, search)).collect(),
+            //every case fails if the method can't be loaded
+            Err(e) => cases.iter().map(|x| CaseResult {
+                name: x.name.clone(),
+                outcome: Outcome::Failed(format!("could not load the method: {}", e)),
+                time: Duration::ZERO,
+            }).collect(),
+        };
+        let cache = Rc::new(MethodCache::new(search.clone()));
+        let limits = Limits { timeout: Some(self.timeout), ..Limits::default() };
+        for rt in self.roundtrips.iter().filter(|x| filter.is_none_or(|f| x.name().contains(f))) {
+            let started = Instant::now();
+            let report = check_round_trip(rt, &cache, limits);
+            let outcome = match (&report.failure, &report.original) {
+                (Some(x), Some(first)) => {
+                    let mut msg = format!("failed on case {} of {} (seed {})\ninput: {:?}\nargs: {:?}\n{}",
+                        report.cases, rt.cases, report.seed, x.input, x.args, x.problem);
+                    if first != x {
+                        msg += &format!("\nshrunk from input {:?} with args {:?}", first.input, first.args);
+                    }
+                    Outcome::Failed(msg)
+                },
+                _ => Outcome::Passed,
+            };
+            results.push(CaseResult { name: rt.name(), outcome, time: started.elapsed() });
+        }
+        FileResult { path: self.path.clone(), cases: results }
+    }
+
+    fn run_case(&self, method: &[u8], case: &TestCase, search: &Rc<SearchPath>) -> CaseResult {
+        let started = Instant::now();
+        let args: Vec<&str> = case.args.iter().map(|x| &x[..]).collect();
+        let limits = Limits { timeout: Some(self.timeout), ..Limits::default() };
+        let result = Vm::new(method.to_vec(), case.input.as_bytes(), &args, 0).and_then(|vm| {
+            let vm = vm.with_search_path(search.clone()).with_limits(limits);
+            let mut vm = if case.binary { vm.with_binary_io() } else { vm };
+            vm.run(&mut NullTracer)
+        });
+        let outcome = match (&case.expect, result) {
+            (Expect::Output(want), Ok(got)) if *want == got => Outcome::Passed,
+            (Expect::Output(want), Ok(got)) => Outcome::Failed(format!("output differs:\n{}", output_diff(want, &got))),
+            (Expect::Output(_), Err(e)) => Outcome::Failed(format!("unexpected error: {}", e)),
+            (Expect::Error(want), Err(e)) if e.to_string().contains(want) => Outcome::Passed,
+            (Expect::Error(want), Err(e)) => Outcome::Failed(format!("expected an error containing {:?}, got: {}", want, e)),
+            (Expect::Error(want), Ok(got)) => Outcome::Failed(format!("expected an error containing {:?}, got output {:?}", want, got)),
+        };
+        CaseResult { name: case.name.clone(), outcome, time: started.elapsed() }
+    }
+}
+
+// the lines of the expected and actual output, marked - and + where they differ
+pub fn output_diff(want: &Value, got: &Value) -> String {
+    let (want, got) = (want.to_string_lossy(), got.to_string_lossy());
+    //control characters are escaped so that they show
+    let visible = |s: &str| s.chars().map(|c| match c {
+        c if c.is_control() && c != '\t' => c.escape_default().to_string(),
+        c => c.to_string(),
+    }).collect::<String>();
+    let a: Vec<String> = want.lines().map(visible).collect();
+    let b: Vec<String> = got.lines().map(visible).collect();
+    let mut out = String::new();
+    for x in diff::slice(&a, &b) {
+        let line = match x {
+            diff::Result::Left(s) => format!("-{}", s),
+            diff::Result::Right(s) => format!("+{}", s),
+            diff::Result::Both(s, _) => format!(" {}", s),
+        };
+        out += &line;
+        out.push('\n');
+    }
+    //differences that don't show in the lines, such as a missing final newline
+    if want.ends_with('\n') != got.ends_with('\n') {
+        out += if want.ends_with('\n') { "(the output has no final newline)\n" } else { "(the output has an extra final newline)\n" };
+    }
+    out
+}
+
+// the test files matched by glob patterns. A directory stands for every test file under it
+pub fn find_tests(patterns: &[String]) -> Result<Vec<PathBuf>, CError> {
+    let mut files: Vec<PathBuf> = Vec::new();
+    for p in patterns {
+        let pattern = if Path::new(p).is_dir() {
+            format!("{}/**/*.{}", p.trim_end_matches('/'), TEST_EXTENSION)
+        } else {
+            p.clone()
+        };
+        let paths = glob::glob(&pattern).map_err(|e| CError::from(format!("Error: bad pattern {}: {}", p, e.msg)))?;
+        for x in paths.filter_map(|x| x.ok()) {
+            if x.is_file() && !files.contains(&x) {
+                files.push(x);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum ReportFormat {
+    Text,
+    Tap,
+    Junit,
+}
+
+impl ReportFormat {
+    pub fn from_name(name: &str) -> Option<ReportFormat> {
+        match name {
+            "text" => Some(ReportFormat::Text),
+            "tap" => Some(ReportFormat::Tap),
+            "junit" => Some(ReportFormat::Junit),
+            _ => None,
+        }
+    }
+}
+
+pub fn report(results: &[FileResult], format: ReportFormat) -> String {
+    match format {
+        ReportFormat::Text => report_text(results),
+        ReportFormat::Tap => report_tap(results),
+        ReportFormat::Junit => report_junit(results),
+    }
+}
+
+fn report_text(results: &[FileResult]) -> String {
+    let mut out = String::new();
+    let (mut total, mut failed) = (0, 0);
+    for f in results {
+        for x in f.cases.iter() {
+            total += 1;
+            match &x.outcome {
+                Outcome::Passed => {out += &format!("ok      {}: {}\n", f.path.display(), x.name);},
+                Outcome::Failed(s) => {
+                    failed += 1;
+                    out += &format!("FAILED  {}: {}\n", f.path.display(), x.name);
+                    for line in s.lines() {
+                        out += &format!("    {}\n", line);
+                    }
+                },
+            }
+        }
+    }
+    out += &format!("\n{} passed, {} failed\n", total - failed, failed);
+    out
+}
+
+// Test Anything Protocol, version 13
+fn report_tap(results: &[FileResult]) -> String {
+    let total: usize = results.iter().map(|x| x.cases.len()).sum();
+    let mut out = format!("TAP version 13\n1..{}\n", total);
+    let mut n = 0;
+    for f in results {
+        for x in f.cases.iter() {
+            n += 1;
+            let name = format!("{}: {}", f.path.display(), x.name).replace('#', "\\#");
+            match &x.outcome {
+                Outcome::Passed => {out += &format!("ok {} - {}\n", n, name);},
+                Outcome::Failed(s) => {
+                    out += &format!("not ok {} - {}\n  ---\n  message: |\n", n, name);
+                    for line in s.lines() {
+                        out += &format!("    {}\n", line);
+                    }
+                    out += "  ...\n";
+                },
+            }
+        }
+    }
+    out
+}
+
+fn xml_escape(st: &str) -> String {
+    st.chars().map(|c| match c {
+        '&' => "&amp;".to_string(),
+        '<' => "&lt;".to_string(),
+        '>' => "&gt;".to_string(),
+        '"' => "&quot;".to_string(),
+        '\'' => "&apos;".to_string(),
+        //characters XML can't hold at all
+        c if (c as u32) < 0x20 && !matches!(c, '\t' | '\n' | '\r') => format!("\\u{{{:x}}}", c as u32),
+        c => c.to_string(),
+    }).collect()
+}
+
+// JUnit XML, with one test suite per file
+fn report_junit(results: &[FileResult]) -> String {
+    let total: usize = results.iter().map(|x| x.cases.len()).sum();
+    let failed: usize = results.iter().map(|x| x.failures()).sum();
+    let time: f64 = results.iter().flat_map(|x| x.cases.iter()).map(|x| x.time.as_secs_f64()).sum();
+    let mut out = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites name=\"cipherlang\" tests=\"{}\" failures=\"{}\" time=\"{:.3}\">\n",
+        total, failed, time);
+    for f in results {
+        let suite = xml_escape(&f.path.display().to_string());
+        let time: f64 = f.cases.iter().map(|x| x.time.as_secs_f64()).sum();
+        out += &format!("  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" time=\"{:.3}\">\n",
+            suite, f.cases.len(), f.failures(), time);
+        for x in f.cases.iter() {
+            let head = format!("    <testcase name=\"{}\" classname=\"{}\" time=\"{:.3}\"", xml_escape(&x.name), suite, x.time.as_secs_f64());
+            match &x.outcome {
+                Outcome::Passed => {out += &format!("{}/>\n", head);},
+                Outcome::Failed(s) => {
+                    let message = s.lines().next().unwrap_or_default();
+                    out += &format!("{}>\n      <failure message=\"{}\">{}</failure>\n    </testcase>\n",
+                        head, xml_escape(message), xml_escape(s));
+                },
+            }
+        }
+        out += "  </testsuite>\n";
+    }
+    out += "</testsuites>\n";
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CAESAR: &str = "%argmin=1,argmax=1\nvar x\nload x from _stdin\napply shift<1> to x\nwrite x to _stdout\n";
+
+    fn parse(text: &str) -> TestFile {
+        TestFile::parse(Path::new("tests/caesar.ciptest"), text).unwrap()
+    }
+
+    fn parse_err(text: &str) -> String {
+        TestFile::parse(Path::new("tests/caesar.ciptest"), text).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn parses_cases() {
+        let t = parse("timeout = 2.5\n\n[[case]]\nname = \"shifts\"\nargs = [\"3\", 4]\ninput = \"abc\"\noutput = \"def\\n\"\n\n[[case]]\ninput_hex = \"00ff\"\nerror = \"requires\"\nbinary = true\n");
+        assert_eq!(t.method, MethodRef::Script(PathBuf::from("tests/caesar.cip")));
+        assert_eq!(t.timeout, Duration::from_millis(2500));
+        assert_eq!(t.cases, vec![
+            TestCase {
+                name: "shifts".to_string(),
+                input: Value(b"abc".to_vec()),
+                args: vec!["3".to_string(), "4".to_string()],
+                binary: false,
+                expect: Expect::Output(Value(b"def\n".to_vec())),
+            },
+            TestCase {
+                name: "case 2".to_string(),
+                input: Value(vec![0, 0xff]),
+                args: Vec::new(),
+                binary: true,
+                expect: Expect::Error("requires".to_string()),
+            },
+        ]);
+    }
+
+    #[test]
+    fn parses_the_method_under_test() {
+        assert_eq!(parse("script = \"lib/c.cip\"").method, MethodRef::Script(PathBuf::from("tests/lib/c.cip")));
+        assert_eq!(parse("method = \"team/caesar@2\"").method, MethodRef::Dictionary("team/caesar@2".to_string()));
+        assert_eq!(parse("").timeout, DEFAULT_TIMEOUT);
+    }
+
+    #[test]
+    fn parses_round_trips() {
+        let t = parse("[[roundtrip]]\nencode = \"rc\"\ndecode = \"rc_dec\"\nargs = [\"int:2..10\", \"letters:1..3\", 5]\ninput = \"letters\"\ncases = 7\nmin_len = 2\nmax_len = 1\nseed = 42\n");
+        let rt = &t.roundtrips[0];
+        assert_eq!((&rt.encode[..], &rt.decode[..]), ("rc", "rc_dec"));
+        assert_eq!(rt.args, vec![ArgGen::Int(2, 10), ArgGen::Letters(1, 3), ArgGen::Fixed("5".to_string())]);
+        assert_eq!(rt.input, InputKind::Letters);
+        //max_len is raised to min_len
+        assert_eq!((rt.cases, rt.min_len, rt.max_len, rt.seed), (7, 2, 2, 42));
+        //an encoder that only accepts ASCII is checked on it by default
+        let t = parse("[[roundtrip]]\nencode = \"hex_dec\"\ndecode = \"hex\"\n");
+        assert_eq!(t.roundtrips[0].input, InputKind::Ascii);
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        assert!(parse_err("script = \"a.cip\"\nmethod = \"a\"").contains("either script or method"));
+        assert!(parse_err("[[case]]\ninput = \"a\"").contains("(case 1): expected output or error is missing"));
+        assert!(parse_err("[[case]]\noutput = \"a\"\nerror = \"b\"").contains("not both"));
+        assert!(parse_err("[[case]]\noutput_hex = \"0g\"").contains("output_hex"));
+        assert!(parse_err("timeout = -1").contains("timeout"));
+        assert!(parse_err("case = 3").contains("[[case]]"));
+        assert!(parse_err("[[roundtrip]]\nencode = \"rc\"").contains("decode"));
+        assert!(parse_err("[[roundtrip]]\nencode = \"rc\"\ndecode = \"rc_dec\"\ninput = \"runes\"").contains("text, ascii, letters, bytes"));
+        assert!(parse_err("[[case").starts_with("Error: tests/caesar.ciptest: "));
+    }
+
+    #[test]
+    fn runs_cases_and_round_trips() {
+        let dir = std::env::temp_dir().join(format!("cipherlang-test-run-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("caesar.cip"), CAESAR).unwrap();
+        let text = "[[case]]\nname = \"shifts\"\nargs = [\"1\"]\ninput = \"abc\"\noutput = \"bcd\\n\"\n\n[[case]]\nname = \"wrong\"\nargs = [\"1\"]\ninput = \"abc\"\noutput = \"abc\\n\"\n\n[[case]]\nname = \"needs an argument\"\nerror = \"incorrect argument number\"\n\n[[roundtrip]]\nencode = \"shift\"\ndecode = \"shift\"\nargs = [\"3\"]\ncases = 20\nseed = 1\n";
+        let t = TestFile::parse(&dir.join("caesar.ciptest"), text).unwrap();
+        let result = t.run(&Rc::new(SearchPath::default()), None);
+        fs::remove_dir_all(&dir).unwrap();
+        let outcomes: Vec<(&str, bool)> = result.cases.iter().map(|x| (&x.name[..], x.outcome == Outcome::Passed)).collect();
+        assert_eq!(outcomes, vec![("shifts", true), ("wrong", false), ("needs an argument", true), ("round trip shift -> shift", false)]);
+        assert_eq!(result.failures(), 2);
+        match &result.cases[1].outcome {
+            Outcome::Failed(s) => {assert_eq!(s, "output differs:\n-abc\n+bcd\n");},
+            Outcome::Passed => unreachable!(),
+        }
+        match &result.cases[3].outcome {
+            Outcome::Failed(s) => {
+                assert!(s.starts_with("failed on case "), "{}", s);
+                assert!(s.contains(" of 20 (seed 1)\ninput: \"a\"\nargs: [\"3\"]\nshift gave \"d\", which shift decoded to \"g\"\nshrunk from "), "{}", s);
+            },
+            Outcome::Passed => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn filter_selects_cases() {
+        let t = parse("[[case]]\nname = \"one\"\noutput = \"\"\n\n[[case]]\nname = \"two\"\noutput = \"\"\n");
+        //the method is missing, so every selected case fails to load it
+        let result = t.run(&Rc::new(SearchPath::default()), Some("tw"));
+        assert_eq!(result.cases.len(), 1);
+        assert_eq!(result.cases[0].name, "two");
+        assert!(matches!(&result.cases[0].outcome, Outcome::Failed(s) if s.starts_with("could not load the method")));
+    }
+
+    #[test]
+    fn diffs_show_hidden_differences() {
+        let diff = output_diff(&Value(b"a\nb\n".to_vec()), &Value(b"a\nb\x01".to_vec()));
+        assert_eq!(diff, " a\n-b\n+b\\u{1}\n(the output has no final newline)\n");
+    }
+
+    fn results() -> Vec<FileResult> {
+        vec![FileResult {
+            path: PathBuf::from("t/a.ciptest"),
+            cases: vec![
+                CaseResult { name: "first #1".to_string(), outcome: Outcome::Passed, time: Duration::ZERO },
+                CaseResult { name: "<second>".to_string(), outcome: Outcome::Failed("output differs:\n-a\n+b & c".to_string()), time: Duration::from_millis(250) },
+            ],
+        }]
+    }
+
+    #[test]
+    fn reports_tap() {
+        assert_eq!(report(&results(), ReportFormat::Tap), "TAP version 13\n1..2\n\
+            ok 1 - t/a.ciptest: first \\#1\n\
+            not ok 2 - t/a.ciptest: <second>\n  ---\n  message: |\n    output differs:\n    -a\n    +b & c\n  ...\n");
+    }
+
+    #[test]
+    fn reports_junit() {
+        assert_eq!(report(&results(), ReportFormat::Junit), "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+            <testsuites name=\"cipherlang\" tests=\"2\" failures=\"1\" time=\"0.250\">\n\
+            \x20 <testsuite name=\"t/a.ciptest\" tests=\"2\" failures=\"1\" time=\"0.250\">\n\
+            \x20   <testcase name=\"first #1\" classname=\"t/a.ciptest\" time=\"0.000\"/>\n\
+            \x20   <testcase name=\"&lt;second&gt;\" classname=\"t/a.ciptest\" time=\"0.250\">\n\
+            \x20     <failure message=\"output differs:\">output differs:\n-a\n+b &amp; c</failure>\n\
+            \x20   </testcase>\n\
+            \x20 </testsuite>\n\
+            </testsuites>\n");
+    }
+
+    #[test]
+    fn reports_text() {
+        assert_eq!(report(&results(), ReportFormat::Text), "ok      t/a.ciptest: first #1\n\
+            FAILED  t/a.ciptest: <second>\n    output differs:\n    -a\n    +b & c\n\n1 passed, 1 failed\n");
+    }
+}
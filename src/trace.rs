@@ -0,0 +1,179 @@
+use std::io::Write;
+use serde_json::json;
+use crate::Value;
+
+// Execution tracing for the interpreter.
+// The interpreter reports each instruction it executes to a Tracer, which decides
+// what (if anything) to print. Nothing is traced unless a tracer is supplied.
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum TraceLevel {
+    Off,
+    Summary, //one line per method run
+    Instructions, //one line per instruction
+    Json, //one JSON object per instruction
+}
+
+impl TraceLevel {
+    pub fn from_name(name: &str) -> Option<TraceLevel> {
+        match name {
+            "off" => Some(TraceLevel::Off),
+            "summary" => Some(TraceLevel::Summary),
+            "instructions" | "inst" => Some(TraceLevel::Instructions),
+            "json" => Some(TraceLevel::Json),
+            _ => None,
+        }
+    }
+}
+
+// an executed instruction
+#[derive(Debug,Clone)]
+pub struct TraceEvent {
+    pub depth: usize, //recursion depth of the method being run
+    pub offset: usize, //offset of the instruction in the method
+    pub opcode: u8,
+    pub operands: Vec<u16>,
+    pub writes: Vec<(String, Value)>, //location written, new value
+}
+
+impl TraceEvent {
+    pub fn op_name(&self) -> &'static str {
+        op_name(self.opcode)
+    }
+}
+
+pub fn op_name(opcode: u8) -> &'static str {
+    match opcode {
+        0 => "load",
+        1 => "apply",
+        2 => "operation",
+        _ => "unknown",
+    }
+}
+
+pub trait Tracer {
+    fn instruction(&mut self, event: &TraceEvent);
+    // called when a method finishes running
+    fn finish(&mut self, depth: usize, instructions: usize, output_len: usize);
+}
+
+// discards all events
+pub struct NullTracer;
+
+impl Tracer for NullTracer {
+    fn instruction(&mut self, _event: &TraceEvent) {}
+    fn finish(&mut self, _depth: usize, _instructions: usize, _output_len: usize) {}
+}
+
+// writes events to a stream (usually stderr) in the format chosen by the level
+pub struct StreamTracer<W: Write> {
+    level: TraceLevel,
+    out: W,
+}
+
+impl<W: Write> StreamTracer<W> {
+    pub fn new(level: TraceLevel, out: W) -> StreamTracer<W> {
+        StreamTracer { level, out }
+    }
+}
+
+impl StreamTracer<std::io::Stderr> {
+    pub fn stderr(level: TraceLevel) -> StreamTracer<std::io::Stderr> {
+        StreamTracer::new(level, std::io::stderr())
+    }
+}
+
+impl<W: Write> Tracer for StreamTracer<W> {
+    fn instruction(&mut self, event: &TraceEvent) {
+        //trace output is best-effort, so write errors are ignored
+        match self.level {
+            TraceLevel::Instructions => {
+                let operands: Vec<String> = event.operands.iter().map(|x| format!("0x{:03x}", x)).collect();
+                let _ = write!(self.out, "{}{:04x}: {} {}", "  ".repeat(event.depth), event.offset,
+                    event.op_name(), operands.join(" "));
+                for (k, v) in event.writes.iter() {
+                    let _ = write!(self.out, "  {} = {:?}", k, v);
+                }
+                let _ = writeln!(self.out);
+            },
+            TraceLevel::Json => {
+                let writes: Vec<serde_json::Value> = event.writes.iter().map(
+                    |(k, v)| match v.as_text() {
+                        Ok(s) => json!({ "target": k, "value": s }),
+                        //values that aren't text are written as hex
+                        Err(_) => json!({ "target": k, "hex": v.as_bytes().iter().map(|x| format!("{:02x}", x)).collect::<String>() }),
+                    }
+                ).collect();
+                let _ = writeln!(self.out, "{}", json!({
+                    "depth": event.depth,
+                    "offset": event.offset,
+                    "op": event.op_name(),
+                    "operands": event.operands,
+                    "writes": writes,
+                }));
+            },
+            _ => {},
+        }
+    }
+
+    fn finish(&mut self, depth: usize, instructions: usize, output_len: usize) {
+        match self.level {
+            TraceLevel::Summary | TraceLevel::Instructions => {
+                let _ = writeln!(self.out, "{}method finished: {} instructions, {} bytes of output",
+                    "  ".repeat(depth), instructions, output_len);
+            },
+            TraceLevel::Json => {
+                let _ = writeln!(self.out, "{}", json!({
+                    "depth": depth,
+                    "finished": true,
+                    "instructions": instructions,
+                    "output_len": output_len,
+                }));
+            },
+            TraceLevel::Off => {},
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event() -> TraceEvent {
+        TraceEvent { depth: 1, offset: 0x15, opcode: 0, operands: vec![0x300, 0x2], writes: vec![("v2".to_string(), Value::from("ab\"c"))] }
+    }
+
+    fn traced(level: TraceLevel) -> String {
+        let mut buf: Vec<u8> = Vec::new();
+        let mut tracer = StreamTracer::new(level, &mut buf);
+        tracer.instruction(&event());
+        tracer.finish(1, 4, 12);
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn levels_by_name() {
+        assert_eq!(TraceLevel::from_name("inst"), Some(TraceLevel::Instructions));
+        assert_eq!(TraceLevel::from_name("json"), Some(TraceLevel::Json));
+        assert_eq!(TraceLevel::from_name("verbose"), None);
+    }
+
+    #[test]
+    fn instruction_format() {
+        assert_eq!(traced(TraceLevel::Instructions),
+            "  0015: load 0x300 0x002  v2 = \"ab\\\"c\"\n  method finished: 4 instructions, 12 bytes of output\n");
+        assert_eq!(traced(TraceLevel::Summary), "  method finished: 4 instructions, 12 bytes of output\n");
+        assert_eq!(traced(TraceLevel::Off), "");
+    }
+
+    #[test]
+    fn json_format() {
+        let out = traced(TraceLevel::Json);
+        let lines: Vec<serde_json::Value> = out.lines().map(|x| serde_json::from_str(x).unwrap()).collect();
+        assert_eq!(lines[0], json!({
+            "depth": 1, "offset": 0x15, "op": "load", "operands": [0x300, 2],
+            "writes": [{ "target": "v2", "value": "ab\"c" }],
+        }));
+        assert_eq!(lines[1], json!({ "depth": 1, "finished": true, "instructions": 4, "output_len": 12 }));
+    }
+}
@@ -1,171 +1,512 @@
-use cipherlang::*;
-use dirs;
-
-use crate::interpret;
+use crate::*;
+use crate::alphabet::Alphabet;
 
 //this module contains all the built-in transforms and operations
 
+// what a transform accepts as input
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Encoding {
+    Bytes, //anything
+    Utf8,
+    Ascii,
+}
+
+impl Encoding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Bytes => "bytes",
+            Encoding::Utf8 => "utf-8",
+            Encoding::Ascii => "ascii",
+        }
+    }
+    pub fn accepts(&self, input: &[u8]) -> bool {
+        match self {
+            Encoding::Bytes => true,
+            Encoding::Utf8 => std::str::from_utf8(input).is_ok(),
+            Encoding::Ascii => input.is_ascii(),
+        }
+    }
+}
+
+// name, arguments, accepted input and description of each built-in transform.
+// Transforms on bytes work on the characters of the UTF-8 parts of the input, and leave
+// any other bytes alone
+pub const BUILTIN_TRANSFORMS: [(&str, &str, Encoding, &str); 27] = [
+    ("upper", "", Encoding::Bytes, "Transforms any lowercase character into the corresponding uppercase character"),
+    ("lower", "", Encoding::Bytes, "Transforms any uppercase character into the corresponding lowercase character"),
+    ("trim_numeric", "", Encoding::Bytes, "Removes any numeric characters from the input"),
+    ("trim_alpha", "", Encoding::Bytes, "Removes any letters from the input"),
+    ("trim_special", "", Encoding::Bytes, "Removes any special characters (punctuation and symbols) from the input"),
+    ("trim_whitespace", "", Encoding::Bytes, "Removes any whitespace characters from the input"),
+    ("prune", "", Encoding::Bytes, "Removes any non-alphabetic characters from the input"),
+    ("prune_numeric", "", Encoding::Bytes, "Removes any non-numeric characters from the input"),
+    ("prune_ascii", "", Encoding::Bytes, "Removes any non-ascii characters from the input"),
+    ("shift", "int DISTANCE, ALPHABET", Encoding::Bytes, "Performs a caesarian shift on the input. Shifts letters of ALPHABET (latin by default) by DISTANCE"),
+    ("rc", "uint NUM", Encoding::Utf8, "Performs a rail cipher on the input, with NUM rails"),
+    ("rc_dec", "uint NUM", Encoding::Utf8, "Reverses a rail cipher on input, with NUM rails"),
+    ("xor", "KEY", Encoding::Bytes, "XORs the input with KEY, repeating the key as needed"),
+    ("hex", "", Encoding::Bytes, "Writes each byte of the input as two hex digits"),
+    ("hex_dec", "", Encoding::Ascii, "Reads pairs of hex digits into bytes"),
+    ("vigenere", "KEY, ALPHABET, ADVANCE", Encoding::Bytes, "Performs a Vigenère cipher, shifting each letter by the next letter of KEY"),
+    ("vigenere_dec", "KEY, ALPHABET, ADVANCE", Encoding::Bytes, "Reverses a Vigenère cipher"),
+    ("beaufort", "KEY, ALPHABET, ADVANCE", Encoding::Bytes, "Performs a Beaufort cipher, replacing each letter by the next key letter minus it"),
+    ("beaufort_dec", "KEY, ALPHABET, ADVANCE", Encoding::Bytes, "Reverses a Beaufort cipher (the same as beaufort)"),
+    ("variant_beaufort", "KEY, ALPHABET, ADVANCE", Encoding::Bytes, "Performs a variant Beaufort cipher, shifting each letter back by the next letter of KEY"),
+    ("variant_beaufort_dec", "KEY, ALPHABET, ADVANCE", Encoding::Bytes, "Reverses a variant Beaufort cipher"),
+    ("gronsfeld", "DIGITS, ALPHABET, ADVANCE", Encoding::Bytes, "Performs a Gronsfeld cipher, shifting each letter by the next of the key's DIGITS"),
+    ("gronsfeld_dec", "DIGITS, ALPHABET, ADVANCE", Encoding::Bytes, "Reverses a Gronsfeld cipher"),
+    ("autokey", "KEY, ALPHABET, ADVANCE", Encoding::Bytes, "Performs an autokey cipher: a Vigenère cipher whose key continues with the plaintext"),
+    ("autokey_dec", "KEY, ALPHABET, ADVANCE", Encoding::Bytes, "Reverses an autokey cipher"),
+    ("porta", "KEY, ALPHABET, ADVANCE", Encoding::Bytes, "Performs a Porta cipher, swapping the halves of the alphabet as the next key letter says"),
+    ("porta_dec", "KEY, ALPHABET, ADVANCE", Encoding::Bytes, "Reverses a Porta cipher (the same as porta)"),
+];
+
+pub fn is_builtin_transform(name: &str) -> bool {
+    BUILTIN_TRANSFORMS.iter().any(|x| x.0 == name)
+}
+
+pub fn transform_encoding(name: &str) -> Option<Encoding> {
+    BUILTIN_TRANSFORMS.iter().find(|x| x.0 == name).map(|x| x.2)
+}
+
 // Helper functions
 
-//returns true if character is an ascii special character
+//returns true if character is a special character: punctuation or a symbol
 pub fn is_special(c: char) -> bool {
-    (c >= '!' && c <= '/') || (c >= ':' && c <= '@') || (c >= '[' && c <= '`') ||
-      (c >= '{' && c <= '~')
+    !c.is_alphanumeric() && !c.is_whitespace() && !c.is_control()
+}
+
+// applies f to each UTF-8 run of the input. Bytes that aren't UTF-8 are kept if keep_invalid is set
+fn map_text(input: &[u8], keep_invalid: bool, mut f: impl FnMut(&str) -> String) -> Vec<u8> {
+    let mut out: Vec<u8> = Vec::new();
+    for chunk in input.utf8_chunks() {
+        out.extend_from_slice(f(chunk.valid()).as_bytes());
+        if keep_invalid {
+            out.extend_from_slice(chunk.invalid());
+        }
+    }
+    out
+}
+
+// keeps the characters for which keep returns true
+fn filter_chars(input: &[u8], keep_invalid: bool, keep: impl Fn(char) -> bool) -> Vec<u8> {
+    map_text(input, keep_invalid, |s| s.chars().filter(|c| keep(*c)).collect())
 }
 
 // Utilities:
 
-pub fn transform_upper(input: &String) -> Result<String, CError> {
-    Ok(input.to_ascii_uppercase())
+pub fn transform_upper(input: &[u8]) -> Result<Vec<u8>, CError> {
+    Ok(map_text(input, true, str::to_uppercase))
 }
 
-pub fn transform_lower(input: &String) -> Result<String, CError> {
-    Ok(input.to_ascii_lowercase())
+pub fn transform_lower(input: &[u8]) -> Result<Vec<u8>, CError> {
+    Ok(map_text(input, true, str::to_lowercase))
 }
 
-pub fn transform_trim_special( input: &String) -> Result<String, CError> {
-    let mut t = input.clone();
-    t.retain(|x| !is_special(x));
-    Ok(t)
+pub fn transform_trim_special(input: &[u8]) -> Result<Vec<u8>, CError> {
+    Ok(filter_chars(input, true, |c| !is_special(c)))
 }
 
-pub fn transform_trim_numeric(input: &String) -> Result<String, CError> {
-    let mut t = input.clone();
-    t.retain(|x| !x.is_ascii_digit());
-    Ok(t)
+pub fn transform_trim_numeric(input: &[u8]) -> Result<Vec<u8>, CError> {
+    Ok(filter_chars(input, true, |c| !c.is_numeric()))
 }
 
-pub fn transform_trim_alpha(input: &String) -> Result<String, CError> {
-    let mut t = input.clone();
-    t.retain(|x| !x.is_ascii_alphabetic());
-    Ok(t)
+pub fn transform_trim_alpha(input: &[u8]) -> Result<Vec<u8>, CError> {
+    Ok(filter_chars(input, true, |c| !c.is_alphabetic()))
 }
 
-pub fn transform_trim_whitespace(input: &String) -> Result<String, CError> {
-    let mut t = input.clone();
-    t.retain(|x| !x.is_ascii_whitespace());
-    Ok(t)
+pub fn transform_trim_whitespace(input: &[u8]) -> Result<Vec<u8>, CError> {
+    Ok(filter_chars(input, true, |c| !c.is_whitespace()))
 }
 
-pub fn transform_prune(input: &String) -> Result<String, CError> {
-    let mut t = input.clone();
-    t.retain(|x| x.is_ascii_alphabetic());
-    Ok(t)
+pub fn transform_prune(input: &[u8]) -> Result<Vec<u8>, CError> {
+    Ok(filter_chars(input, false, char::is_alphabetic))
 }
 
-pub fn transform_prune_numeric(input: &String) -> Result<String, CError> {
-    let mut t = input.clone();
-    t.retain(|x| x.is_ascii_digit());
-    Ok(t)
+pub fn transform_prune_numeric(input: &[u8]) -> Result<Vec<u8>, CError> {
+    Ok(filter_chars(input, false, char::is_numeric))
 }
 
-pub fn transform_prune_ascii(input: &String) -> Result<String, CError> {
-    let mut t = input.clone();
+pub fn transform_prune_ascii(input: &[u8]) -> Result<Vec<u8>, CError> {
+    let mut t = input.to_vec();
     t.retain(|x| x.is_ascii());
     Ok(t)
 }
 
+pub fn transform_hex(input: &[u8]) -> Result<Vec<u8>, CError> {
+    Ok(input.iter().map(|x| format!("{:02x}", x)).collect::<String>().into_bytes())
+}
+
+pub fn transform_hex_decode(input: &[u8]) -> Result<Vec<u8>, CError> {
+    let digits: Vec<u8> = input.iter().copied().filter(|x| !x.is_ascii_whitespace()).collect();
+    if !digits.len().is_multiple_of(2) {
+        return Err(CError::from_slice("Error: hex input has an odd number of digits"));
+    }
+    let mut out: Vec<u8> = Vec::new();
+    for x in digits.chunks(2) {
+        //the input is ascii, so this is valid UTF-8
+        match u8::from_str_radix(std::str::from_utf8(x).unwrap(), 16) {
+            Ok(b) => out.push(b),
+            Err(_) => {return Err(CError::from_slice("Error: invalid hex digit"));},
+        }
+    }
+    Ok(out)
+}
+
 
 // Ciphers
 
-pub fn transform_shift(input: &String, arg: i16) -> Result<String, CError> {
-    if !input.is_ascii() {
-        return Err(CError::from_slice("Error: non-ascii string"));
-    }
-    let mut output: Vec<u8> = Vec::new();
-    for x in input.bytes() {
-        if x > 64 && x <= 90 {
-            output.push( (65 + ((x - 65) as i8 + arg as i8) % 26) as u8);
-        } else if x > 96 && x <= 122 {
-            output.push( ( 97 + ((x - 97) as i8 + arg as i8) % 26) as u8);
-        } else {
-            output.push(x);
+pub fn transform_shift(input: &[u8], arg: i16, alphabet: &Alphabet) -> Result<Vec<u8>, CError> {
+    let shift = (arg as i64).rem_euclid(alphabet.len() as i64) as usize;
+    Ok(map_text(input, true, |s| s.chars().map(|c| match alphabet.index(c) {
+        Some((i, upper)) => alphabet.letter(i + shift, upper),
+        None => c,
+    }).collect()))
+}
+
+// The polyalphabetic ciphers shift each letter by a different amount, given by the next
+// letter of a key. Letters keep their case, and characters outside the alphabet are left
+// alone; whether the key moves on at them is set by KeyAdvance.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Polyalphabetic {
+    Vigenere,
+    Beaufort,
+    VariantBeaufort,
+    Gronsfeld,
+    Autokey,
+    Porta,
+}
+
+// the transform name of each cipher and direction
+const POLYALPHABETIC: [(&str, Polyalphabetic, bool); 12] = [
+    ("vigenere", Polyalphabetic::Vigenere, false),
+    ("vigenere_dec", Polyalphabetic::Vigenere, true),
+    ("beaufort", Polyalphabetic::Beaufort, false),
+    ("beaufort_dec", Polyalphabetic::Beaufort, true),
+    ("variant_beaufort", Polyalphabetic::VariantBeaufort, false),
+    ("variant_beaufort_dec", Polyalphabetic::VariantBeaufort, true),
+    ("gronsfeld", Polyalphabetic::Gronsfeld, false),
+    ("gronsfeld_dec", Polyalphabetic::Gronsfeld, true),
+    ("autokey", Polyalphabetic::Autokey, false),
+    ("autokey_dec", Polyalphabetic::Autokey, true),
+    ("porta", Polyalphabetic::Porta, false),
+    ("porta_dec", Polyalphabetic::Porta, true),
+];
+
+// the cipher a transform performs, and whether it decodes
+pub fn polyalphabetic_cipher(name: &str) -> Option<(Polyalphabetic, bool)> {
+    POLYALPHABETIC.iter().find(|x| x.0 == name).map(|x| (x.1, x.2))
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum KeyAdvance {
+    Letters, //the key moves on only at letters of the alphabet (the default)
+    All, //the key moves on at every character
+}
+
+impl KeyAdvance {
+    pub fn from_name(name: &str) -> Option<KeyAdvance> {
+        match name {
+            "letters" => Some(KeyAdvance::Letters),
+            "all" => Some(KeyAdvance::All),
+            _ => None,
         }
     }
+}
 
-    Ok(String::from_utf8(output)?)
+// the shift each key character gives: its position in the alphabet, or for Gronsfeld its value as a digit
+fn cipher_key(cipher: Polyalphabetic, key: &str, alphabet: &Alphabet) -> Result<Vec<usize>, CError> {
+    if key.is_empty() {
+        return Err(CError::from_slice("Error: the cipher's key is empty"));
+    }
+    key.chars().map(|c| match cipher {
+        Polyalphabetic::Gronsfeld => match c.to_digit(10) {
+            Some(d) => Ok(d as usize),
+            None => Err(CError::from(format!("Error: Gronsfeld keys are digits, not '{}'", c))),
+        },
+        _ => match alphabet.index(c) {
+            Some((i, _)) => Ok(i),
+            None => Err(CError::from(format!("Error: key letter '{}' is not in the alphabet", c))),
+        },
+    }).collect()
 }
 
-pub fn transform_rc_encode(input: &String, arg: u16) -> Result<String, CError> {
-    if arg == 1 || arg as usize > input.len(){
-        return Ok(input.to_string());
+pub fn transform_polyalphabetic(input: &[u8], cipher: Polyalphabetic, key: &str, decode: bool, alphabet: &Alphabet, advance: KeyAdvance) -> Result<Vec<u8>, CError> {
+    let n = alphabet.len();
+    if cipher == Polyalphabetic::Porta && !n.is_multiple_of(2) {
+        return Err(CError::from_slice("Error: Porta needs an alphabet with an even number of letters"));
     }
-    let mut rails: Vec<String> = vec![String::new(); arg.into()];
-    let mut up: bool = false; //is pointer moving up or down
-    let mut pointer: usize = 0; //which rail is being incremented
+    let key = cipher_key(cipher, key, alphabet)?;
+    //an autokey cipher's key continues with the plaintext, so it grows as the text is read
+    let mut stream = key.clone();
+    let mut pos = 0;
+    Ok(map_text(input, true, |s| s.chars().map(|c| {
+        let letter = alphabet.index(c);
+        if letter.is_none() && advance == KeyAdvance::Letters {
+            return c;
+        }
+        let k = match cipher {
+            Polyalphabetic::Autokey => stream[pos],
+            _ => key[pos % key.len()],
+        } % n;
+        pos += 1;
+        let (i, upper) = match letter {
+            Some(s) => s,
+            None => {
+                //a character outside the alphabet still takes a place in the key
+                if cipher == Polyalphabetic::Autokey {
+                    stream.push(0);
+                }
+                return c;
+            },
+        };
+        let out = match (cipher, decode) {
+            (Polyalphabetic::Vigenere | Polyalphabetic::Gronsfeld | Polyalphabetic::Autokey, false) => (i + k) % n,
+            (Polyalphabetic::Vigenere | Polyalphabetic::Gronsfeld | Polyalphabetic::Autokey, true) => (i + n - k) % n,
+            (Polyalphabetic::Beaufort, _) => (k + n - i) % n,
+            (Polyalphabetic::VariantBeaufort, false) => (i + n - k) % n,
+            (Polyalphabetic::VariantBeaufort, true) => (i + k) % n,
+            (Polyalphabetic::Porta, _) => {
+                //the key letter's pair picks how far the halves are shifted against each other
+                let (h, p) = (n / 2, (k / 2) % (n / 2));
+                if i < h { h + (i + p) % h } else { (i - h + h - p) % h }
+            },
+        };
+        if cipher == Polyalphabetic::Autokey {
+            stream.push(if decode { out } else { i });
+        }
+        alphabet.letter(out, upper)
+    }).collect()))
+}
 
-    for x in input.chars() {
-      rails[pointer].push(x);
-      if pointer == 0 {
-          up = false
-      }
-      if pointer == (arg-1).into() {
-          up = true;
-      }
-      if up {pointer -= 1} else {pointer += 1}
+pub fn transform_xor(input: &[u8], key: &[u8]) -> Result<Vec<u8>, CError> {
+    if key.is_empty() {
+        return Err(CError::from_slice("Error: xor key is empty"));
     }
-    Ok(rails.concat())
+    Ok(input.iter().zip(key.iter().cycle()).map(|(a, b)| a ^ b).collect())
 }
 
-pub fn transform_rc_decode(input: &String, arg: u16) -> Result<String, CError> {
-    let mut rails: Vec<String> = vec![String::new(); arg.into()];
-    let mut lens: Vec<usize> = vec![0; arg as usize];
-    let mut pointer: usize = 0;
-    let mut up: bool = false;
-    
-    for _i in 0..input.len() {
-        lens[pointer] += 1;
+// the rail that each character of a rail cipher of len characters is placed on
+fn rail_pattern(len: usize, rails: usize) -> Vec<usize> {
+    let mut out: Vec<usize> = Vec::with_capacity(len);
+    let mut up: bool = false; //is pointer moving up or down
+    let mut pointer: usize = 0; //which rail is being incremented
+    for _i in 0..len {
+        out.push(pointer);
         if pointer == 0 {
             up = false
         }
-        if pointer == (arg-1).into() {
+        if pointer == rails - 1 {
             up = true;
         }
         if up {pointer -= 1} else {pointer += 1}
     }
-    //place string in rails
-    let mut offset = 0;
-    for i in 0..rails.len() {
-        rails[i] = input[offset..offset+lens[i]].to_string();
-        offset += lens[i];
+    out
+}
+
+pub fn transform_rc_encode(input: &str, arg: u16) -> Result<String, CError> {
+    let chars: Vec<char> = input.chars().collect();
+    if arg <= 1 || arg as usize > chars.len() {
+        return Ok(input.to_string());
+    }
+    let mut rails: Vec<String> = vec![String::new(); arg.into()];
+    for (c, r) in chars.iter().zip(rail_pattern(chars.len(), arg.into())) {
+        rails[r].push(*c);
     }
+    Ok(rails.concat())
+}
 
-    pointer = 0;
-    up = false;
-    let mut out = String::new();
-    for _i in 0..input.len() {
-        out.push(rails[pointer].chars().nth(0).unwrap());
-        rails[pointer] = rails[pointer][1..].to_string();
-        if pointer == 0 {
-            up = false
-        }
-        if pointer == (arg-1).into() {
-            up = true;
+pub fn transform_rc_decode(input: &str, arg: u16) -> Result<String, CError> {
+    let chars: Vec<char> = input.chars().collect();
+    if arg <= 1 || arg as usize > chars.len() {
+        return Ok(input.to_string());
+    }
+    let pattern = rail_pattern(chars.len(), arg.into());
+    //find where each rail starts in the input
+    let mut starts: Vec<usize> = vec![0; arg as usize];
+    for r in pattern.iter() {
+        if *r + 1 < starts.len() {
+            starts[*r + 1] += 1;
         }
-        if up {pointer -= 1} else {pointer += 1}
     }
-    Ok(out)
+    for i in 1..starts.len() {
+        starts[i] += starts[i - 1];
+    }
+    Ok(pattern.iter().map(|r| {
+        let c = chars[starts[*r]];
+        starts[*r] += 1;
+        c
+    }).collect())
 }
 
-pub fn external_transform(input: &String, transform: &String, args: &Vec<&str>, depth: usize) -> Result<String, CError> {
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    //search in dictionary for method with matching name. If so, load it in.
-    //then, run interpreter on it.
-    let mut hdir = dirs::home_dir().expect("Could not find home dir");
-    hdir.push(".ciplang/methods");
+    fn shift(text: &str, by: i16, alphabet: &str) -> String {
+        String::from_utf8(transform_shift(text.as_bytes(), by, &Alphabet::from_name(alphabet).unwrap()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn shift_wraps_within_the_alphabet() {
+        assert_eq!(shift("Hello, World", 3, "latin"), "Khoor, Zruog");
+        assert_eq!(shift("Khoor, Zruog", -3, "latin"), "Hello, World");
+        assert_eq!(shift("xyz", 29, "latin"), "abc");
+        assert_eq!(shift("Zürich", 1, "german"), "Äßsjdi");
+        assert_eq!(shift("Привет", 1, "cyrillic"), "Рсйгёу");
+        //letters from other alphabets are left alone
+        assert_eq!(shift("abc Ωμέγα", 1, "latin"), "bcd Ωμέγα");
+    }
 
-    //now, place the compiled method there
-    hdir.push(transform);
-    hdir.set_extension("cpth");
-    let path = match hdir.to_str() {
-        Some(s) => s,
-        None => {return Err(CError::from("Could not find transform"));}
-    };
-    let method = read_bin_file(&path.to_string());
+    #[test]
+    fn case_changes_are_unicode_aware() {
+        assert_eq!(transform_upper("straße ñ".as_bytes()).unwrap(), "STRASSE Ñ".as_bytes());
+        assert_eq!(transform_lower(b"\xffABC").unwrap(), b"\xffabc");
+        assert_eq!(transform_trim_alpha("a1é2ж".as_bytes()).unwrap(), b"12");
+        assert_eq!(transform_prune(&[&b"a1"[..], "é".as_bytes(), b"2\xff"].concat()).unwrap(), "aé".as_bytes());
+        assert_eq!(transform_trim_special("¿qué? «sí»".as_bytes()).unwrap(), "qué sí".as_bytes());
+    }
+
+    #[test]
+    fn rail_cipher_counts_characters() {
+        assert_eq!(transform_rc_encode("WEAREDISCOVERED", 3).unwrap(), "WECRERDSOEEAIVD");
+        assert_eq!(transform_rc_decode("WECRERDSOEEAIVD", 3).unwrap(), "WEAREDISCOVERED");
+        let text = "Grüße aus Köln, 東京";
+        for rails in 1..8 {
+            let encoded = transform_rc_encode(text, rails).unwrap();
+            assert_eq!(encoded.chars().count(), text.chars().count());
+            assert_eq!(transform_rc_decode(&encoded, rails).unwrap(), text);
+        }
+    }
+
+    #[test]
+    fn rail_cipher_decode_does_not_panic_on_multibyte_input() {
+        //more rails than characters, but fewer than bytes
+        assert_eq!(transform_rc_decode("äöü", 4).unwrap(), "äöü");
+        assert_eq!(transform_rc_decode("é", 2).unwrap(), "é");
+        assert_eq!(transform_rc_decode("", 3).unwrap(), "");
+        assert_eq!(transform_rc_decode("ab", 0).unwrap(), "ab");
+    }
 
-    if depth > MAX_RECURSION_DEPTH {
-        return Err(CError::from_slice("Maximum Recursion Depth exceeded"));
+    fn cipher(name: &str, text: &str, key: &str) -> String {
+        cipher_with(name, text, key, &Alphabet::latin(), KeyAdvance::Letters)
     }
 
-    interpret(&method, input, args, depth+1)
+    fn cipher_with(name: &str, text: &str, key: &str, alphabet: &Alphabet, advance: KeyAdvance) -> String {
+        let (c, decode) = polyalphabetic_cipher(name).unwrap();
+        String::from_utf8(transform_polyalphabetic(text.as_bytes(), c, key, decode, alphabet, advance).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn vigenere() {
+        assert_eq!(cipher("vigenere", "ATTACKATDAWN", "LEMON"), "LXFOPVEFRNHR");
+        assert_eq!(cipher("vigenere_dec", "LXFOPVEFRNHR", "lemon"), "ATTACKATDAWN");
+    }
+
+    #[test]
+    fn beaufort() {
+        let text = "DEFENDTHEEASTWALLOFTHECASTLE";
+        let encoded = "CKMPVCPVWPIWUJOGIUAPVWRIWUUK";
+        assert_eq!(cipher("beaufort", text, "FORTIFICATION"), encoded);
+        assert_eq!(cipher("beaufort_dec", encoded, "FORTIFICATION"), text);
+    }
+
+    #[test]
+    fn variant_beaufort() {
+        //the variant Beaufort cipher is a Vigenère decryption
+        assert_eq!(cipher("variant_beaufort", "ATTACKATDAWN", "LEMON"), cipher("vigenere_dec", "ATTACKATDAWN", "LEMON"));
+        assert_eq!(cipher("variant_beaufort", "ATTACKATDAWN", "LEMON"), "PPHMPZWHPNLJ");
+        assert_eq!(cipher("variant_beaufort_dec", "PPHMPZWHPNLJ", "LEMON"), "ATTACKATDAWN");
+    }
+
+    #[test]
+    fn gronsfeld() {
+        assert_eq!(cipher("gronsfeld", "ATTACKATDAWN", "31415"), cipher("vigenere", "ATTACKATDAWN", "DBEBF"));
+        assert_eq!(cipher("gronsfeld", "ATTACKATDAWN", "31415"), "DUXBHNBXEFZO");
+        assert_eq!(cipher("gronsfeld_dec", "DUXBHNBXEFZO", "31415"), "ATTACKATDAWN");
+    }
+
+    #[test]
+    fn autokey() {
+        assert_eq!(cipher("autokey", "ATTACKATDAWN", "QUEENLY"), "QNXEPVYTWTWP");
+        assert_eq!(cipher("autokey_dec", "QNXEPVYTWTWP", "QUEENLY"), "ATTACKATDAWN");
+    }
+
+    #[test]
+    fn porta() {
+        //with key letters A or B, the halves of the alphabet are swapped
+        assert_eq!(cipher("porta", "ABCMNOZ", "A"), "NOPZABM");
+        assert_eq!(cipher("porta", "ABCMNOZ", "B"), "NOPZABM");
+        //C and D shift the halves by one against each other
+        assert_eq!(cipher("porta", "ABCMNOZ", "C"), "OPQNMAL");
+        assert_eq!(cipher("porta", "Attack at dawn", "KEY"), "Sehsrw se pshb");
+        assert_eq!(cipher("porta_dec", "Sehsrw se pshb", "KEY"), "Attack at dawn");
+    }
+
+    #[test]
+    fn reciprocal_ciphers_undo_themselves() {
+        let text = "The Quick Brown Fox, 1 jumps!";
+        for name in ["beaufort", "porta"] {
+            assert_eq!(cipher(name, &cipher(name, text, "DICKENS"), "DICKENS"), text);
+        }
+    }
+
+    #[test]
+    fn case_and_other_characters_are_kept() {
+        assert_eq!(cipher("vigenere", "Attack at dawn!", "LEMON"), "Lxfopv ef rnhr!");
+        assert_eq!(cipher("vigenere", "ä 1 Ω", "B"), "ä 1 Ω");
+    }
+
+    #[test]
+    fn key_advances_on_every_character_with_all() {
+        let all = cipher_with("vigenere", "Attack at dawn", "LEMON", &Alphabet::latin(), KeyAdvance::All);
+        assert_eq!(all, "Lxfopv mh oeib");
+        let back = cipher_with("vigenere_dec", &all, "LEMON", &Alphabet::latin(), KeyAdvance::All);
+        assert_eq!(back, "Attack at dawn");
+        //with autokey, characters outside the alphabet still take a place in the key
+        let auto = cipher_with("autokey", "ab, cd", "K", &Alphabet::latin(), KeyAdvance::All);
+        assert_eq!(auto, "kb, cf");
+        assert_eq!(cipher_with("autokey_dec", &auto, "K", &Alphabet::latin(), KeyAdvance::All), "ab, cd");
+    }
+
+    #[test]
+    fn custom_alphabets() {
+        let greek = Alphabet::from_name("greek").unwrap();
+        let encoded = cipher_with("vigenere", "Καλημερα", "βγ", &greek, KeyAdvance::Letters);
+        assert_eq!(encoded, "Λγμινησγ");
+        assert_eq!(cipher_with("vigenere_dec", &encoded, "ΒΓ", &greek, KeyAdvance::Letters), "Καλημερα");
+        let abc = Alphabet::from_name("custom:abcd").unwrap();
+        assert_eq!(cipher_with("porta", "abcd", "a", &abc, KeyAdvance::Letters), "cdab");
+    }
+
+    #[test]
+    fn transform_arguments() {
+        use std::rc::Rc;
+        use crate::dictionary::SearchPath;
+        use crate::interpret::{run_transform, Limits, MethodCache};
+        let cache = Rc::new(MethodCache::new(Rc::new(SearchPath::default())));
+        let run = |name: &str, args: &[&str]| run_transform(&Value(b"Attack at dawn".to_vec()), name, args, &cache, Limits::default(), true)
+            .map(|x| x.to_string_lossy());
+        assert_eq!(run("vigenere", &["LEMON"]).unwrap(), "Lxfopv ef rnhr");
+        //an empty alphabet is the default one
+        assert_eq!(run("vigenere", &["LEMON", "", "all"]).unwrap(), "Lxfopv mh oeib");
+        assert_eq!(run("vigenere", &["LEMON", "latin", "letters"]).unwrap(), "Lxfopv ef rnhr");
+        assert!(run("vigenere", &[]).unwrap_err().to_string().contains("requires a key"));
+        assert!(run("porta", &["KEY", "latin", "sometimes"]).unwrap_err().to_string().contains("not 'sometimes'"));
+        assert!(run("beaufort", &["KEY", "klingon"]).is_err());
+    }
+
+    #[test]
+    fn bad_keys_and_alphabets() {
+        let run = |c: Polyalphabetic, key: &str, alphabet: &str| {
+            transform_polyalphabetic(b"abc", c, key, false, &Alphabet::from_name(alphabet).unwrap(), KeyAdvance::Letters)
+                .unwrap_err().to_string()
+        };
+        assert!(run(Polyalphabetic::Vigenere, "", "latin").contains("key is empty"));
+        assert!(run(Polyalphabetic::Vigenere, "ab1", "latin").contains("key letter '1'"));
+        assert!(run(Polyalphabetic::Vigenere, "ab", "greek").contains("key letter 'a'"));
+        assert!(run(Polyalphabetic::Gronsfeld, "12a", "latin").contains("not 'a'"));
+        assert!(run(Polyalphabetic::Porta, "a", "custom:abc").contains("even number of letters"));
+        assert_eq!(KeyAdvance::from_name("every"), None);
+    }
 }
@@ -0,0 +1,342 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+// Runs the cipherlang binary with a scratch directory as its home and working directory,
+// so the dictionary starts empty for every test.
+struct Scratch {
+    dir: PathBuf,
+}
+
+impl Scratch {
+    fn new(name: &str) -> Scratch {
+        let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("cli").join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Scratch { dir }
+    }
+
+    fn file(&self, name: &str, contents: &str) -> String {
+        fs::write(self.dir.join(name), contents).unwrap();
+        name.to_string()
+    }
+
+    fn run(&self, args: &[&str], stdin: &[u8]) -> Output {
+        self.run_in(&self.dir, args, stdin, &[])
+    }
+
+    // runs in another directory, with extra environment variables
+    fn run_in(&self, dir: &Path, args: &[&str], stdin: &[u8], env: &[(&str, PathBuf)]) -> Output {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_cipherlang"))
+            .args(args)
+            .current_dir(dir)
+            .env("HOME", &self.dir)
+            .env_remove("CIPLANG_PATH")
+            .env_remove("XDG_CONFIG_HOME")
+            .env_remove("XDG_CACHE_HOME")
+            .envs(env.iter().map(|(k, v)| (k, v)))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(stdin).unwrap();
+        child.wait_with_output().unwrap()
+    }
+}
+
+fn status(out: &Output) -> i32 {
+    out.status.code().unwrap()
+}
+
+fn stderr(out: &Output) -> String {
+    String::from_utf8_lossy(&out.stderr).into_owned()
+}
+
+const CAESAR: &str = "%argmin=1,argmax=1,usage=\"caesar SHIFT\"\nvar x\nload x from _stdin\napply shift<3> to x\nwrite x to _stdout\n";
+
+#[test]
+fn successful_runs_exit_with_zero() {
+    let s = Scratch::new("success");
+    s.file("caesar.cip", CAESAR);
+    let out = s.run(&["run", "-f", "caesar.cip", "-a", "1"], b"abc");
+    assert_eq!(status(&out), 0, "{}", stderr(&out));
+    assert_eq!(out.stdout, b"def\n\n");
+    //arguments after -- are passed as they are, commas included
+    let out = s.run(&["run", "-f", "caesar.cip", "--", "a,b"], b"xyz");
+    assert_eq!(status(&out), 0, "{}", stderr(&out));
+}
+
+#[test]
+fn runtime_errors_exit_with_one() {
+    let s = Scratch::new("runtime");
+    s.file("bad.cip", "%argmin=0,argmax=0\nvar x\nload x from _stdin\nload x from _stdin\n");
+    let out = s.run(&["run", "-f", "bad.cip"], b"one line");
+    assert_eq!(status(&out), 1);
+    assert!(stderr(&out).contains("exceeded input text"), "{}", stderr(&out));
+}
+
+#[test]
+fn usage_errors_exit_with_two() {
+    let s = Scratch::new("usage");
+    s.file("caesar.cip", CAESAR);
+    assert_eq!(status(&s.run(&["run", "-f", "caesar.cip", "--frobnicate"], b"")), 2);
+    assert_eq!(status(&s.run(&["run", "-f", "caesar.cip", "--trace=loud"], b"")), 2);
+    assert_eq!(status(&s.run(&["explode"], b"")), 2);
+    //the method itself rejects the argument count
+    let out = s.run(&["run", "-f", "caesar.cip"], b"abc");
+    assert_eq!(status(&out), 2);
+    assert!(stderr(&out).contains("Usage: caesar SHIFT"), "{}", stderr(&out));
+}
+
+#[test]
+fn compile_errors_exit_with_three() {
+    let s = Scratch::new("compile");
+    s.file("broken.cip", "%argmin=0,argmax=0\nvar x\nload y from _stdin\n");
+    let out = s.run(&["run", "-f", "broken.cip"], b"");
+    assert_eq!(status(&out), 3);
+    assert!(stderr(&out).starts_with("broken.cip: Line 3"), "{}", stderr(&out));
+    assert_eq!(status(&s.run(&["compile", "broken.cip"], b"")), 3);
+}
+
+#[test]
+fn missing_files_exit_with_four() {
+    let s = Scratch::new("io");
+    s.file("caesar.cip", CAESAR);
+    assert_eq!(status(&s.run(&["run", "-f", "nothing.cip"], b"")), 4);
+    assert_eq!(status(&s.run(&["run", "nothing"], b"")), 4);
+    assert_eq!(status(&s.run(&["run", "-f", "caesar.cip", "-a", "1", "-i", "nothing.txt"], b"")), 4);
+    assert_eq!(status(&s.run(&["remove", "nothing"], b"")), 4);
+}
+
+#[test]
+fn exceeded_limits_exit_with_five() {
+    let s = Scratch::new("limit");
+    s.file("caesar.cip", CAESAR);
+    for limit in ["--max-instructions=1", "--max-output=2", "--max-string=1"] {
+        let out = s.run(&["run", "-f", "caesar.cip", "-a", "1", limit], b"abc");
+        assert_eq!(status(&out), 5, "{}: {}", limit, stderr(&out));
+    }
+    let out = s.run(&["run", "-f", "caesar.cip", "-a", "1", "--stream", "--max-output=2"], b"abc\n");
+    assert_eq!(status(&out), 5, "{}", stderr(&out));
+}
+
+#[test]
+fn compiled_methods_run_like_scripts() {
+    let s = Scratch::new("compiled");
+    s.file("caesar.cip", CAESAR);
+    assert_eq!(status(&s.run(&["compile", "caesar.cip", "-o", "caesar.cpth"], b"")), 0);
+    let script = s.run(&["run", "-f", "caesar.cip", "-a", "1"], b"hello");
+    let method = s.run(&["run", "-f", "caesar.cpth", "-a", "1"], b"hello");
+    assert_eq!(status(&method), 0, "{}", stderr(&method));
+    assert_eq!(script.stdout, method.stdout);
+}
+
+#[test]
+fn dictionary_commands() {
+    let s = Scratch::new("dict");
+    s.file("caesar.cip", CAESAR);
+    assert_eq!(status(&s.run(&["dict", "install", "caesar.cip", "--name", "rot"], b"")), 0);
+    assert_eq!(status(&s.run(&["dict", "rename", "rot", "caesar"], b"")), 0);
+    let list = s.run(&["list"], b"");
+    assert_eq!(String::from_utf8_lossy(&list.stdout), "caesar\n");
+    let info = s.run(&["dict", "info", "caesar"], b"");
+    let info = String::from_utf8_lossy(&info.stdout);
+    assert!(info.contains("\nsource: ") && info.contains("caesar.cip\n"), "{}", info);
+    assert!(info.contains("\nsha256: "), "{}", info);
+    //installed methods run by name
+    let out = s.run(&["run", "caesar", "-a", "1"], b"abc");
+    assert_eq!(out.stdout, b"def\n\n");
+    assert_eq!(status(&s.run(&["dict", "remove", "caesar"], b"")), 0);
+    assert_eq!(status(&s.run(&["run", "caesar", "-a", "1"], b"abc")), 4);
+}
+
+#[test]
+fn search_path_order_and_shadowing() {
+    let s = Scratch::new("search");
+    let dirs = [
+        ("flag", "flagdict"),
+        ("env", "envdict"),
+        ("project", "proj/.ciplang/methods"),
+        ("config", "cfg/ciplang/shared"),
+        ("home", ".ciplang/methods"),
+    ];
+    for (source, dir) in dirs {
+        fs::create_dir_all(s.dir.join(dir)).unwrap();
+        let script = s.file(&format!("{}.cip", source),
+            &format!("%argmin=0,argmax=0\nvar x\nconst k \"{}\"\nload x from k\nwrite x to _stdout\n", source));
+        let out = s.run(&["compile", &script, "-o", &format!("{}/who.cpth", dir)], b"");
+        assert_eq!(status(&out), 0, "{}", stderr(&out));
+    }
+    s.file("cfg/ciplang/config.toml", "path = [\"shared\"]\n");
+    let proj = s.dir.join("proj");
+    let env = [("CIPLANG_PATH", s.dir.join("envdict")), ("XDG_CONFIG_HOME", s.dir.join("cfg"))];
+
+    let path = s.run_in(&proj, &["--dict", "../flagdict", "dict", "path"], b"", &env);
+    let sources: Vec<String> = String::from_utf8_lossy(&path.stdout).lines()
+        .map(|x| x.rsplit('[').next().unwrap().trim_end_matches(']').to_string()).collect();
+    assert_eq!(sources, vec!["--dict", "CIPLANG_PATH", "project", "config", "home"]);
+
+    //each dictionary is used once the ones before it are gone
+    let mut flags: Vec<&str> = vec!["--dict", "../flagdict"];
+    for (i, (source, dir)) in dirs.iter().enumerate() {
+        let out = s.run_in(&proj, &[&flags[..], &["run", "who"]].concat(), b"", &env);
+        assert_eq!(String::from_utf8_lossy(&out.stdout), format!("{}\n\n", source));
+        let warnings = stderr(&out).matches("warning: method 'who'").count();
+        assert_eq!(warnings, (i + 1 < dirs.len()) as usize, "{}", stderr(&out));
+        fs::remove_file(s.dir.join(dir).join("who.cpth")).unwrap();
+        flags.clear();
+    }
+}
+
+#[test]
+fn bundles_move_methods_with_their_dependencies() {
+    let s = Scratch::new("bundle");
+    let helper = s.file("helper.cip", "%argmin=0,argmax=0\nvar x\nload x from _stdin\napply upper<> to x\nwrite x to _stdout\n");
+    let main = s.file("loud.cip", "%argmin=0,argmax=0\nvar x\nload x from _stdin\napply helper<> to x\nwrite x to _stdout\n");
+    for script in [&helper, &main] {
+        assert_eq!(status(&s.run(&["dict", "install", script], b"")), 0);
+    }
+    let alone = s.run(&["bundle", "create", "-o", "alone.json", "loud"], b"");
+    assert_eq!(status(&alone), 0, "{}", stderr(&alone));
+    let out = s.run(&["bundle", "create", "-o", "all.json", "--deps", "--sources", "loud"], b"");
+    assert_eq!(status(&out), 0, "{}", stderr(&out));
+    assert!(stderr(&out).contains("Packed 2 methods"));
+
+    //install into an empty dictionary
+    fs::remove_dir_all(s.dir.join(".ciplang")).unwrap();
+    let out = s.run(&["bundle", "install", "alone.json"], b"");
+    assert_eq!(status(&out), 4);
+    assert!(stderr(&out).contains("'loud' uses 'helper', which is not in the bundle or the dictionary"));
+    assert!(!s.dir.join(".ciplang/methods/loud.cpth").exists());
+    let out = s.run(&["bundle", "install", "all.json", "--sources", "src"], b"");
+    assert_eq!(status(&out), 0, "{}", stderr(&out));
+    assert_eq!(fs::read_to_string(s.dir.join("src/loud.cip")).unwrap(), fs::read_to_string(s.dir.join(&main)).unwrap());
+    assert_eq!(s.run(&["run", "loud"], b"abc\n").stdout, b"ABC\n\n\n");
+
+    //a changed method is caught before anything is installed
+    let bundle = fs::read_to_string(s.dir.join("all.json")).unwrap();
+    fs::write(s.dir.join("bad.json"), bundle.replace("\"sha256\": \"", "\"sha256\": \"0")).unwrap();
+    let out = s.run(&["bundle", "install", "bad.json"], b"");
+    assert_ne!(status(&out), 0);
+    assert!(stderr(&out).contains("does not match its hash"), "{}", stderr(&out));
+}
+
+#[test]
+fn check_reports_cycles_and_missing_methods() {
+    let s = Scratch::new("check");
+    let script = |uses: &str| format!("%argmin=0,argmax=0\nvar x\nload x from _stdin\napply {}<> to x\nwrite x to _stdout\n", uses);
+    for (name, uses) in [("ping", "pong"), ("pong", "ping"), ("outer", "ping"), ("lost", "nowhere")] {
+        let file = s.file(&format!("{}.cip", name), &script(uses));
+        assert_eq!(status(&s.run(&["dict", "install", &file], b"")), 0);
+    }
+    let out = s.run(&["deps", "outer"], b"");
+    assert_eq!(status(&out), 4);
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "outer\n  ping\n    pong\n      ping  (cycle)\n");
+
+    let out = s.run(&["check"], b"");
+    assert_eq!(status(&out), 4);
+    let report = String::from_utf8_lossy(&out.stdout);
+    assert!(report.contains("lost: uses 'nowhere', which is not in the dictionary\n"), "{}", report);
+    assert!(report.contains("outer: cycle: outer -> ping -> pong -> ping\n"), "{}", report);
+    assert!(report.contains("ping: cycle: ping -> pong -> ping\n"), "{}", report);
+
+    let file = s.file("fine.cip", &script("upper"));
+    assert_eq!(status(&s.run(&["dict", "install", &file], b"")), 0);
+    let out = s.run(&["check", "fine"], b"");
+    assert_eq!(status(&out), 0);
+    assert!(stderr(&out).contains("1 method checked, no problems found"));
+}
+
+#[test]
+fn compiled_scripts_are_cached_until_they_change() {
+    let s = Scratch::new("cache");
+    let script = s.file("caesar.cip", CAESAR);
+    assert_eq!(s.run(&["run", "-f", &script, "-a", "1"], b"abc\n").stdout, b"def\n\n");
+    let entries = || -> Vec<PathBuf> {
+        let dir = s.dir.join(".cache/ciplang/scripts");
+        fs::read_dir(dir).map(|x| x.map(|e| e.unwrap().path()).collect()).unwrap_or_default()
+    };
+    let cached = entries();
+    assert_eq!(cached.len(), 1);
+    assert!(!s.dir.join(".caesar.cip.cpth").exists());
+
+    //swap in another method, to see when the cache is used
+    let upper = s.file("upper.cip", "%argmin=0,argmax=1\nvar x\nload x from _stdin\napply upper<> to x\nwrite x to _stdout\n");
+    assert_eq!(status(&s.run(&["compile", &upper, "-o", "upper.cpth"], b"")), 0);
+    fs::copy(s.dir.join("upper.cpth"), &cached[0]).unwrap();
+    assert_eq!(s.run(&["run", "-f", &script, "-a", "1"], b"abc\n").stdout, b"ABC\n\n");
+    assert_eq!(s.run(&["run", "--no-cache", "-f", &script, "-a", "1"], b"abc\n").stdout, b"def\n\n");
+    assert_eq!(entries(), cached);
+
+    //editing the script gives it a new entry
+    s.file("caesar.cip", &CAESAR.replace("var x", "var x # edited"));
+    assert_eq!(s.run(&["run", "-f", &script, "-a", "1"], b"abc\n").stdout, b"def\n\n");
+    assert_eq!(entries().len(), 2);
+
+    let out = s.run(&["cache", "clean"], b"");
+    assert!(stderr(&out).contains("Removed 2 compiled scripts"));
+    assert!(entries().is_empty());
+    let out = s.run(&["cache", "path"], b"");
+    assert_eq!(String::from_utf8_lossy(&out.stdout).trim_end(), s.dir.join(".cache/ciplang/scripts").display().to_string());
+}
+
+#[test]
+fn test_command_reports_failures() {
+    let s = Scratch::new("ciptest");
+    s.file("caesar.cip", CAESAR);
+    s.file("caesar.ciptest", "[[case]]\nname = \"shifts\"\nargs = [\"1\"]\ninput = \"abc\"\noutput = \"def\\n\"\n");
+    let out = s.run(&["test", "--format", "tap"], b"");
+    assert_eq!(status(&out), 0, "{}", String::from_utf8_lossy(&out.stdout));
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "TAP version 13\n1..1\nok 1 - caesar.ciptest: shifts\n");
+
+    s.file("broken.ciptest", "script = \"caesar.cip\"\n\n[[case]]\nname = \"no shift\"\nargs = [\"1\"]\ninput = \"abc\"\noutput = \"abc\\n\"\n");
+    let out = s.run(&["test", "--format", "junit", "-o", "report.xml"], b"");
+    assert_eq!(status(&out), 1);
+    let report = fs::read_to_string(s.dir.join("report.xml")).unwrap();
+    assert!(report.contains("tests=\"2\" failures=\"1\""), "{}", report);
+    assert_eq!(status(&s.run(&["test", "--filter", "shifts"], b"")), 0);
+    assert_eq!(status(&s.run(&["test", "nothing*.ciptest"], b"")), 4);
+}
+
+#[test]
+fn lone_builtin_transforms_run_as_pipelines() {
+    let s = Scratch::new("pipeline");
+    let out = s.run(&["run", "upper"], b"abc\nxyz\n");
+    assert_eq!(status(&out), 0, "{}", stderr(&out));
+    assert_eq!(out.stdout, b"ABC\nXYZ\n");
+    assert_eq!(s.run(&["run", "upper | shift<1>"], b"abc\n").stdout, b"BCD\n");
+    //the output limit covers the whole run, not each line
+    let out = s.run(&["run", "--max-output=6", "upper"], b"abc\nxyz\n");
+    assert_eq!(status(&out), 5);
+    assert_eq!(out.stdout, b"ABC\n");
+}
+
+#[test]
+fn invalid_method_files_and_output_files() {
+    let s = Scratch::new("invalid");
+    s.file("bad.cpth", "CPTH but not a method");
+    let out = s.run(&["run", "-f", "bad.cpth"], b"");
+    assert_eq!(status(&out), 3, "{}", stderr(&out));
+
+    let script = s.file("caesar.cip", CAESAR);
+    let stdout = s.run(&["run", "-f", &script, "-a", "1"], b"abc\n").stdout;
+    assert_eq!(status(&s.run(&["run", "-f", &script, "-a", "1", "-o", "out.txt"], b"abc\n")), 0);
+    assert_eq!(fs::read(s.dir.join("out.txt")).unwrap(), stdout);
+}
+
+#[test]
+fn streamed_and_buffered_output_match() {
+    let s = Scratch::new("stream");
+    let script = s.file("twice.cip", "%argmin=0,argmax=0\nvar x\nload x from _stdin\nwrite x to _stdout\napply upper<> to x\nwrite x to _stdout\n");
+    for mode in [&[][..], &["--binary"][..]] {
+        let buffered = s.run(&[&["run", "-f", &script][..], mode].concat(), b"abc\nxyz\n");
+        let streamed = s.run(&[&["run", "-f", &script, "--stream"][..], mode].concat(), b"abc\nxyz\n");
+        assert_eq!(status(&streamed), 0, "{}", stderr(&streamed));
+        assert_eq!(streamed.stdout, buffered.stdout, "{:?}", mode);
+    }
+    let out = s.run(&["run", "-f", &script, "--stream", "-o", "out.txt"], b"abc\nxyz\n");
+    assert_eq!(status(&out), 0);
+    assert_eq!(fs::read(s.dir.join("out.txt")).unwrap(), b"abc\nABC\n\n");
+}